@@ -4,8 +4,54 @@ pub const CONTRACT_NAME: &str = "eris-staking-hub";
 pub const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const CONTRACT_DENOM: &str = "uluna";
 pub const DAY: u64 = 24 * 60 * 60;
+pub const WEEK: u64 = 7 * DAY;
+/// Weight of a validator that has not been assigned one explicitly, in basis points
+pub const DEFAULT_VALIDATOR_WEIGHT: u64 = 10_000;
+/// Maximum number of redelegations in progress between a pair of validators, as in the staking
+/// module's default `max_entries`
+pub const MAX_REDELEGATION_ENTRIES: usize = 7;
+/// Reply ID of the stake token instantiation
+pub const REGISTER_STAKE_TOKEN_REPLY_ID: u64 = 1;
+/// Reply IDs of failed swap routes start here, offset by the index of the route in the swap config
+pub const SWAP_ROUTE_REPLY_ID: u64 = 100;
 
 pub fn get_reward_fee_cap() -> Decimal {
     // 10% max reward fee
     Decimal::from_ratio(10_u128, 100_u128)
 }
+
+pub fn get_reserve_share_cap() -> Decimal {
+    // 50% max share of deposits kept in the liquid reserve
+    Decimal::from_ratio(50_u128, 100_u128)
+}
+
+pub fn get_instant_unbond_fee_cap() -> Decimal {
+    // 10% max instant unbond fee
+    Decimal::from_ratio(10_u128, 100_u128)
+}
+
+pub fn get_keeper_reward_share_cap() -> Decimal {
+    // 5% max share of harvested rewards paid to keepers
+    Decimal::from_ratio(5_u128, 100_u128)
+}
+
+pub fn get_deposit_fee_cap() -> Decimal {
+    // 5% max fee on bonded Luna
+    Decimal::from_ratio(5_u128, 100_u128)
+}
+
+pub fn get_withdraw_fee_cap() -> Decimal {
+    // 5% max fee on withdrawn Luna
+    Decimal::from_ratio(5_u128, 100_u128)
+}
+
+pub fn get_rebalance_tolerance_cap() -> Decimal {
+    // 10% max deviation from the target delegation left unbalanced
+    Decimal::from_ratio(10_u128, 100_u128)
+}
+
+pub fn get_slashing_dust_tolerance() -> Decimal {
+    // Losses of up to 0.001% of a delegation, but at least 1 uluna, are taken as rounding of the
+    // delegation shares rather than slashing
+    Decimal::from_ratio(1_u128, 100_000_u128)
+}
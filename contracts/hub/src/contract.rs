@@ -1,4 +1,4 @@
-use classic_bindings::TerraQuery;
+use classic_bindings::{TerraMsg, TerraQuery};
 use cosmwasm_std::{
     entry_point, from_binary, to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response,
     StdError, StdResult,
@@ -6,9 +6,13 @@ use cosmwasm_std::{
 use cw2::{get_contract_version, set_contract_version};
 use cw20::Cw20ReceiveMsg;
 
+use eris::asset::{native_asset, token_asset};
 use eris::hub::{CallbackMsg, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, ReceiveMsg};
 
-use crate::constants::{CONTRACT_NAME, CONTRACT_VERSION};
+use crate::constants::{
+    CONTRACT_DENOM, CONTRACT_NAME, CONTRACT_VERSION, REGISTER_STAKE_TOKEN_REPLY_ID,
+    SWAP_ROUTE_REPLY_ID,
+};
 use crate::helpers::{parse_received_fund, unwrap_reply};
 use crate::state::State;
 use crate::{execute, queries};
@@ -19,7 +23,7 @@ pub fn instantiate(
     env: Env,
     _info: MessageInfo,
     msg: InstantiateMsg,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     execute::instantiate(deps, env, msg)
 }
 
@@ -29,29 +33,73 @@ pub fn execute(
     env: Env,
     info: MessageInfo,
     msg: ExecuteMsg,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     let api = deps.api;
     match msg {
         ExecuteMsg::Receive(cw20_msg) => receive(deps, env, info, cw20_msg),
         ExecuteMsg::Bond {
             receiver,
-        } => execute::bond(
+            referral,
+            min_ustake_out,
+        } => {
+            let receiver =
+                receiver.map(|s| api.addr_validate(&s)).transpose()?.unwrap_or(info.sender);
+            let referral = referral.map(|s| api.addr_validate(&s)).transpose()?;
+            match info.funds.as_slice() {
+                [coin] if coin.denom != CONTRACT_DENOM => execute::bond_with_swap(
+                    deps,
+                    env,
+                    receiver,
+                    native_asset(coin.denom.clone(), coin.amount),
+                    referral,
+                    min_ustake_out,
+                ),
+                _ => execute::bond(
+                    deps,
+                    env,
+                    receiver,
+                    parse_received_fund(&info.funds, CONTRACT_DENOM)?,
+                    false,
+                    referral,
+                    min_ustake_out,
+                ),
+            }
+        },
+        ExecuteMsg::Donate {} => execute::bond(
             deps,
             env,
-            receiver.map(|s| api.addr_validate(&s)).transpose()?.unwrap_or(info.sender),
+            info.sender,
             parse_received_fund(&info.funds, "uluna")?,
-            false,
+            true,
+            None,
+            None,
         ),
-        ExecuteMsg::Donate {} => {
-            execute::bond(deps, env, info.sender, parse_received_fund(&info.funds, "uluna")?, true)
-        },
         ExecuteMsg::WithdrawUnbonded {
             receiver,
+            batch_ids,
+            limit,
         } => execute::withdraw_unbonded(
             deps,
             env,
             info.sender.clone(),
             receiver.map(|s| api.addr_validate(&s)).transpose()?.unwrap_or(info.sender),
+            batch_ids,
+            limit,
+        ),
+        ExecuteMsg::CancelUnbond {
+            amount,
+        } => execute::cancel_unbond(deps, env, info.sender, amount),
+        ExecuteMsg::TransferUnbondRequest {
+            id,
+            shares,
+            recipient,
+        } => execute::transfer_unbond_request(
+            deps,
+            env,
+            info.sender,
+            id,
+            shares,
+            api.addr_validate(&recipient)?,
         ),
         ExecuteMsg::AddValidator {
             validator,
@@ -59,26 +107,27 @@ pub fn execute(
         ExecuteMsg::RemoveValidator {
             validator,
         } => execute::remove_validator(deps, env, info.sender, validator),
+        ExecuteMsg::ReplaceValidator {
+            old,
+            new,
+        } => execute::replace_validator(deps, env, info.sender, old, new),
+        ExecuteMsg::SetValidatorWeights {
+            weights,
+        } => execute::set_validator_weights(deps, info.sender, weights),
         ExecuteMsg::TransferOwnership {
             new_owner,
         } => execute::transfer_ownership(deps, info.sender, new_owner),
         ExecuteMsg::AcceptOwnership {} => execute::accept_ownership(deps, info.sender),
-        ExecuteMsg::Harvest {} => execute::harvest(deps, env),
-        ExecuteMsg::Rebalance {} => execute::rebalance(deps, env),
-        ExecuteMsg::Reconcile {} => execute::reconcile(deps, env),
-        ExecuteMsg::SubmitBatch {} => execute::submit_batch(deps, env),
+        ExecuteMsg::Harvest {} => execute::harvest(deps, env, info.sender),
+        ExecuteMsg::Rebalance {
+            max_moves,
+        } => execute::rebalance(deps, env, max_moves),
+        ExecuteMsg::EvaluateValidators {} => execute::evaluate_validators(deps, env),
+        ExecuteMsg::EvictValidators {} => execute::evict_validators(deps, env),
+        ExecuteMsg::Reconcile {} => execute::reconcile(deps, env, info.sender),
+        ExecuteMsg::SubmitBatch {} => execute::submit_batch(deps, env, info.sender),
         ExecuteMsg::Callback(callback_msg) => callback(deps, env, info, callback_msg),
-        ExecuteMsg::UpdateConfig {
-            protocol_fee_contract,
-            protocol_reward_fee,
-            swap_config,
-        } => execute::update_config(
-            deps,
-            info.sender,
-            protocol_fee_contract,
-            protocol_reward_fee,
-            swap_config,
-        ),
+        ExecuteMsg::UpdateConfig(msg) => execute::update_config(deps, env, info.sender, *msg),
     }
 }
 
@@ -87,29 +136,49 @@ fn receive(
     env: Env,
     info: MessageInfo,
     cw20_msg: Cw20ReceiveMsg,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     let api = deps.api;
     match from_binary(&cw20_msg.msg)? {
         ReceiveMsg::QueueUnbond {
             receiver,
+            min_uluna_out,
         } => {
             let state = State::default();
-
-            let stake_token = state.stake_token.load(deps.storage)?;
-            if info.sender != stake_token {
-                return Err(StdError::generic_err(format!(
-                    "expecting Stake token, received {}",
-                    info.sender
-                )));
-            }
+            state.assert_stake_token(deps.storage, &info.sender)?;
 
             execute::queue_unbond(
                 deps,
                 env,
                 api.addr_validate(&receiver.unwrap_or(cw20_msg.sender))?,
                 cw20_msg.amount,
+                min_uluna_out,
             )
         },
+        ReceiveMsg::InstantUnbond {
+            receiver,
+        } => {
+            let state = State::default();
+            state.assert_stake_token(deps.storage, &info.sender)?;
+
+            execute::instant_unbond(
+                deps,
+                env,
+                api.addr_validate(&receiver.unwrap_or(cw20_msg.sender))?,
+                cw20_msg.amount,
+            )
+        },
+        ReceiveMsg::Bond {
+            receiver,
+            referral,
+            min_ustake_out,
+        } => execute::bond_with_swap(
+            deps,
+            env,
+            api.addr_validate(&receiver.unwrap_or(cw20_msg.sender))?,
+            token_asset(info.sender, cw20_msg.amount),
+            referral.map(|s| api.addr_validate(&s)).transpose()?,
+            min_ustake_out,
+        ),
     }
 }
 
@@ -118,25 +187,55 @@ fn callback(
     env: Env,
     info: MessageInfo,
     callback_msg: CallbackMsg,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     if env.contract.address != info.sender {
         return Err(StdError::generic_err("callbacks can only be invoked by the contract itself"));
     }
 
     match callback_msg {
         CallbackMsg::Swap {} => execute::swap(deps, env),
-        CallbackMsg::Reinvest {} => execute::reinvest(deps, env),
+        CallbackMsg::SwapRoute {
+            denom,
+        } => execute::swap_route(deps, env, denom),
+        CallbackMsg::SwapHop {
+            hop,
+        } => execute::swap_hop(deps, env, hop),
+        CallbackMsg::AssertMinReturn {
+            snapshot,
+            min_received,
+        } => execute::assert_min_return(deps, env, snapshot, min_received),
+        CallbackMsg::Reinvest {
+            keeper,
+        } => execute::reinvest(deps, env, keeper),
         CallbackMsg::CheckReceivedCoin {
             snapshot,
         } => execute::callback_received_coin(deps, env, snapshot),
+        CallbackMsg::BondSwapped {
+            receiver,
+            snapshot,
+            min_received,
+            referral,
+            min_ustake_out,
+        } => execute::bond_swapped(
+            deps,
+            env,
+            receiver,
+            snapshot,
+            min_received,
+            referral,
+            min_ustake_out,
+        ),
     }
 }
 
 #[entry_point]
-pub fn reply(deps: DepsMut<TerraQuery>, _env: Env, reply: Reply) -> StdResult<Response> {
+pub fn reply(deps: DepsMut<TerraQuery>, _env: Env, reply: Reply) -> StdResult<Response<TerraMsg>> {
     match reply.id {
-        1 => execute::register_stake_token(deps, unwrap_reply(reply)?),
-        id => Err(StdError::generic_err(format!("invalid reply id: {}; must be 1", id))),
+        REGISTER_STAKE_TOKEN_REPLY_ID => execute::register_stake_token(deps, unwrap_reply(reply)?),
+        id if id >= SWAP_ROUTE_REPLY_ID => {
+            execute::skip_swap_route(deps, id - SWAP_ROUTE_REPLY_ID, reply.result)
+        },
+        id => Err(StdError::generic_err(format!("invalid reply id: {}", id))),
     }
 }
 
@@ -177,14 +276,41 @@ pub fn query(deps: Deps<TerraQuery>, env: Env, msg: QueryMsg) -> StdResult<Binar
             start_after,
             limit,
         } => to_binary(&queries::query_exchange_rates(deps, env, start_after, limit)?),
+        QueryMsg::ExchangeRateAt {
+            time,
+            interpolate,
+        } => to_binary(&queries::query_exchange_rate_at(deps, time, interpolate.unwrap_or(false))?),
+        QueryMsg::Referrals {
+            start_after,
+            limit,
+        } => to_binary(&queries::referrals(deps, start_after, limit)?),
+        QueryMsg::KeeperStatus {} => to_binary(&queries::keeper_status(deps, env)?),
+        QueryMsg::Yield {
+            window_seconds,
+        } => to_binary(&queries::query_yield(deps, env, window_seconds)?),
+        QueryMsg::SimulateBond {
+            uluna,
+        } => to_binary(&queries::simulate_bond(deps, env, uluna)?),
+        QueryMsg::SimulateUnbond {
+            ustake,
+        } => to_binary(&queries::simulate_unbond(deps, env, ustake)?),
+        QueryMsg::ValidatorScores {} => to_binary(&queries::validator_scores(deps)?),
     }
 }
 
 #[entry_point]
-pub fn migrate(deps: DepsMut<TerraQuery>, _env: Env, _msg: MigrateMsg) -> StdResult<Response> {
+pub fn migrate(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    msg: MigrateMsg,
+) -> StdResult<Response<TerraMsg>> {
     let contract_version = get_contract_version(deps.storage)?;
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
+    if let Some(history_config) = msg.history_config {
+        execute::set_history_config(deps.storage, env.block.time.seconds(), history_config)?;
+    }
+
     Ok(Response::new()
         .add_attribute("previous_contract_name", &contract_version.contract)
         .add_attribute("previous_contract_version", &contract_version.version)
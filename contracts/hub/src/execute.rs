@@ -1,31 +1,40 @@
-use classic_bindings::TerraQuery;
+use classic_bindings::{TerraMsg, TerraQuerier, TerraQuery};
 use cosmwasm_std::{
     to_binary, Addr, Coin, CosmosMsg, Decimal, DepsMut, DistributionMsg, Env, Event, Order,
-    Response, StdError, StdResult, SubMsg, SubMsgResponse, Uint128, WasmMsg,
+    Response, StdError, StdResult, Storage, SubMsg, SubMsgResponse, SubMsgResult, Uint128, WasmMsg,
 };
 use cw2::set_contract_version;
 use cw20::{Cw20ExecuteMsg, MinterResponse};
 use cw20_base::msg::InstantiateMsg as Cw20InstantiateMsg;
-use eris::asset::{Asset, AssetInfo};
+use eris::asset::{native_asset, Asset, AssetInfo};
 use eris::{CustomResponse, DecimalCheckedOps};
 
 use eris::hub::{
-    Batch, CallbackMsg, ExecuteMsg, FeeConfig, InstantiateMsg, PendingBatch, SwapConfig,
-    UnbondRequest,
+    Batch, CallbackMsg, ExchangeRateReason, ExecuteMsg, FeeConfig, HistoryConfig, InstantiateMsg,
+    PendingBatch, SwapConfig, SwapHop, SwapKind, UnbondRequest, UpdateConfigMsg, ValidatorScore,
 };
 
-use crate::constants::{get_reward_fee_cap, CONTRACT_DENOM, CONTRACT_NAME, CONTRACT_VERSION};
+use crate::constants::{
+    get_deposit_fee_cap, get_instant_unbond_fee_cap, get_keeper_reward_share_cap,
+    get_rebalance_tolerance_cap, get_reserve_share_cap, get_reward_fee_cap,
+    get_slashing_dust_tolerance, get_withdraw_fee_cap, CONTRACT_DENOM, CONTRACT_NAME,
+    CONTRACT_VERSION, MAX_REDELEGATION_ENTRIES, REGISTER_STAKE_TOKEN_REPLY_ID, SWAP_ROUTE_REPLY_ID,
+};
 use crate::helpers::{
-    check_swap_config, dedupe, query_cw20_total_supply, query_delegation, query_delegations,
+    check_fee_recipients, check_swap_config, dedupe, exceeds_max_spread,
+    exclude_commission_offenders, find_commission_offenders, query_belief_price,
+    query_cw20_total_supply, query_delegation, query_delegations, query_pair_simulation,
 };
 use crate::math::{
-    compute_mint_amount, compute_redelegations_for_rebalancing, compute_redelegations_for_removal,
-    compute_unbond_amount, compute_undelegations, mark_reconciled_batches, reconcile_batches,
+    compute_fee_split, compute_mint_amount, compute_redelegations_for_rebalancing,
+    compute_redelegations_for_removal, compute_reserve_amount, compute_unbond_amount,
+    compute_undelegations, find_validator_to_delegate, mark_reconciled_batches, reconcile_batches,
+    socialize_slashing,
 };
 use crate::state::State;
-use crate::types::{Coins, Delegation};
+use crate::types::{Coins, Delegation, Redelegation};
 
-type ContractResult = StdResult<Response>;
+type ContractResult = StdResult<Response<TerraMsg>>;
 
 //--------------------------------------------------------------------------------------------------
 // Instantiation
@@ -35,7 +44,7 @@ pub fn instantiate(
     deps: DepsMut<TerraQuery>,
     env: Env,
     msg: InstantiateMsg,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
     let state = State::default();
@@ -58,6 +67,10 @@ pub fn instantiate(
         &FeeConfig {
             protocol_fee_contract: deps.api.addr_validate(&msg.protocol_fee_contract)?,
             protocol_reward_fee: msg.protocol_reward_fee,
+            keeper_reward: None,
+            fee_recipients: vec![],
+            deposit_fee: Decimal::zero(),
+            withdraw_fee: Decimal::zero(),
         },
     )?;
 
@@ -91,14 +104,14 @@ pub fn instantiate(
             funds: vec![],
             label: "Eris Liquid Staking Token".to_string(),
         }),
-        1,
+        REGISTER_STAKE_TOKEN_REPLY_ID,
     )))
 }
 
 pub fn register_stake_token(
     deps: DepsMut<TerraQuery>,
     response: SubMsgResponse,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
 
     let event = response
@@ -133,49 +146,87 @@ pub fn register_stake_token(
 /// the case of 15 validators.
 ///
 /// To save gas for users, now we simply delegate all deposited Luna to the validator with the
-/// smallest amount of delegation. If delegations become severely unbalance as a result of this
-/// (e.g. when a single user makes a very big deposit), anyone can invoke `ExecuteMsg::Rebalance`
-/// to balance the delegations.
+/// smallest amount of delegation relative to its weight. If delegations become severely unbalance
+/// as a result of this (e.g. when a single user makes a very big deposit), anyone can invoke
+/// `ExecuteMsg::Rebalance` to balance the delegations.
 pub fn bond(
-    deps: DepsMut<TerraQuery>,
+    mut deps: DepsMut<TerraQuery>,
     env: Env,
     receiver: Addr,
     uluna_to_bond: Uint128,
     donate: bool,
-) -> StdResult<Response> {
+    referral: Option<Addr>,
+    min_ustake_out: Option<Uint128>,
+) -> StdResult<Response<TerraMsg>> {
+    // A loss since the last harvest must be accounted for before the delegations change
+    let slash_event = detect_slashing(&mut deps, &env)?;
+
     let state = State::default();
     let stake_token = state.stake_token.load(deps.storage)?;
     let validators = state.validators.load(deps.storage)?;
+    let weights = state.get_validator_weights(deps.storage, &validators)?;
 
     // Query the current delegations made to validators, and find the validator with the smallest
-    // delegated amount through a linear search
-    // The code for linear search is a bit uglier than using `sort_by` but cheaper: O(n) vs O(n * log(n))
+    // delegated amount relative to its weight, leaving out validators exceeding the max commission
     let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
-    let mut validator = &delegations[0].validator;
-    let mut amount = delegations[0].amount;
-    for d in &delegations[1..] {
-        if d.amount < amount {
-            validator = &d.validator;
-            amount = d.amount;
-        }
+    let offenders = find_commission_offenders(deps.storage, &deps.querier, &validators)?;
+    let (eligible_delegations, eligible_weights) =
+        exclude_commission_offenders(&delegations, &weights, &offenders);
+    let validator = find_validator_to_delegate(&eligible_delegations, &eligible_weights);
+
+    // Part of the deposit is kept undelegated to fund the liquid reserve
+    let reserve_config = state.get_reserve_config(deps.storage)?;
+    let uluna_reserve = state.get_liquid_reserve(deps.storage)?;
+    let uluna_to_reserve = compute_reserve_amount(uluna_to_bond, uluna_reserve, &reserve_config)?;
+    let uluna_to_delegate = uluna_to_bond.checked_sub(uluna_to_reserve)?;
+    if !uluna_to_reserve.is_zero() {
+        state.liquid_reserve.save(deps.storage, &(uluna_reserve + uluna_to_reserve))?;
     }
-    let new_delegation = Delegation {
-        validator: validator.clone(),
-        amount: uluna_to_bond.u128(),
-    };
 
-    // Query the current supply of Stake and compute the amount to mint
+    let new_delegation = Delegation::new(validator, uluna_to_delegate.u128());
+    state.record_delegation(deps.storage, &new_delegation, env.block.time.seconds())?;
+
+    // Query the current supply of Stake and compute the amount to mint. The deposit fee is minted
+    // to the fee recipients instead of the receiver
     let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
-    let ustake_to_mint = if donate {
-        Uint128::zero()
+    let fee_config = state.fee_config.load(deps.storage)?;
+    let (ustake_to_mint, ustake_deposit_fee) = if donate {
+        (Uint128::zero(), Uint128::zero())
     } else {
-        compute_mint_amount(ustake_supply, uluna_to_bond, &delegations)
+        let ustake_minted =
+            compute_mint_amount(ustake_supply, uluna_to_bond, &delegations, uluna_reserve);
+        let ustake_deposit_fee = fee_config.deposit_fee.checked_mul_uint(ustake_minted)?;
+        (ustake_minted.checked_sub(ustake_deposit_fee)?, ustake_deposit_fee)
     };
 
+    if let Some(min_ustake_out) = min_ustake_out {
+        if ustake_to_mint < min_ustake_out {
+            return Err(StdError::generic_err(format!(
+                "minted {} ustake, less than the minimum of {}",
+                ustake_to_mint, min_ustake_out
+            )));
+        }
+    }
+
+    // Donations increase the amount of uluna per ustake
+    if donate {
+        let uluna_staked: u128 = delegations.iter().map(|d| d.amount).sum();
+        let exchange_rate = calc_current_exchange_rate(
+            uluna_staked + uluna_reserve.u128() + uluna_to_bond.u128(),
+            ustake_supply,
+        )?;
+        state.record_exchange_rate(
+            deps.storage,
+            env.block.time.seconds(),
+            exchange_rate,
+            ExchangeRateReason::Donate,
+        )?;
+    }
+
     let delegate_msg = new_delegation.to_cosmos_msg();
 
-    let mint_msg: CosmosMsg = CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: stake_token.into(),
+    let mint_msg: CosmosMsg<TerraMsg> = CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: stake_token.to_string(),
         msg: to_binary(&Cw20ExecuteMsg::Mint {
             recipient: receiver.to_string(),
             amount: ustake_to_mint,
@@ -183,75 +234,595 @@ pub fn bond(
         funds: vec![],
     });
 
-    let event = Event::new("erishub/bonded")
+    let mut event = Event::new("erishub/bonded")
         .add_attribute("time", env.block.time.seconds().to_string())
         .add_attribute("height", env.block.height.to_string())
         .add_attribute("receiver", receiver)
         .add_attribute("uluna_bonded", uluna_to_bond)
+        .add_attribute("uluna_reserved", uluna_to_reserve)
         .add_attribute("ustake_minted", ustake_to_mint);
 
-    let mut response = Response::new().add_message(delegate_msg);
+    let mut response = Response::new().add_events(slash_event).add_message(delegate_msg);
 
     if !donate {
         response = response.add_message(mint_msg);
     }
 
+    if let Some(referral) = referral {
+        state.referrals.update(deps.storage, &referral, |x| -> StdResult<_> {
+            Ok(x.unwrap_or_default() + uluna_to_bond)
+        })?;
+        event = event.add_attribute("referral", referral);
+    }
+
+    if !ustake_deposit_fee.is_zero() {
+        event = event.add_attribute("ustake_deposit_fee", ustake_deposit_fee);
+        for (recipient, amount) in compute_fee_split(&fee_config, ustake_deposit_fee)? {
+            if amount.is_zero() {
+                continue;
+            }
+            response = response.add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: stake_token.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Mint {
+                    recipient: recipient.to_string(),
+                    amount,
+                })?,
+                funds: vec![],
+            }));
+        }
+    }
+
     response = response.add_event(event).add_attribute("action", "erishub/bond");
 
-    Ok(response.add_message(check_received_coin_msg(&deps, &env, Some(uluna_to_bond))?))
+    // the reserved part of the deposit stays in the contract's balance, so only the delegated part
+    // is deducted from the snapshot
+    Ok(response.add_message(check_received_coin_msg(&deps, &env, Some(uluna_to_delegate))?))
+}
+
+/// Bond a native coin other than Luna or a CW20 token, by first swapping it to Luna through its
+/// configured swap route. Only routes without hops are supported, as hops swap the contract's whole
+/// balance of a denom, which may include harvested rewards
+pub fn bond_with_swap(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    receiver: Addr,
+    offer: Asset,
+    referral: Option<Addr>,
+    min_ustake_out: Option<Uint128>,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+    let denom = offer.info.to_string();
+    let item =
+        state
+            .swap_config
+            .load(deps.storage)?
+            .into_iter()
+            .find(|item| item.denom == denom)
+            .ok_or_else(|| StdError::generic_err(format!("no swap route for denom {}", denom)))?;
+
+    if !item.hops.is_empty() {
+        return Err(StdError::generic_err(format!(
+            "cannot bond {}: only swap routes without hops are supported",
+            denom
+        )));
+    }
+    if offer.amount.is_zero() {
+        return Err(StdError::generic_err("amount to bond must be greater than zero"));
+    }
+
+    let snapshot = deps.querier.query_balance(&env.contract.address, CONTRACT_DENOM)?;
+    let (swap_msg, min_received) = match &offer.info {
+        AssetInfo::NativeToken {
+            denom,
+        } => {
+            let coin = Coin::new(offer.amount.u128(), denom);
+            let min_received = route_min_received(&deps, &item, &coin)?.ok_or_else(|| {
+                StdError::generic_err(format!("cannot bond {}: unknown price", denom))
+            })?;
+            let resolved = resolve_swap(&deps, &item, &coin, CONTRACT_DENOM, false)?;
+            (resolved_swap_msg(&deps, &item, coin, &resolved, None)?, min_received)
+        },
+        // price sources only know native denoms, so CW20 tokens are protected by `min_return` and
+        // the pair's max spread
+        AssetInfo::Token {
+            ..
+        } => {
+            let min_received = match item.min_return {
+                Some(min_return) => min_return.checked_mul_uint(offer.amount)?,
+                None => Uint128::zero(),
+            };
+            let swap_msg = offer.clone().into_swap_msg(
+                &deps.querier,
+                item.contract.to_string(),
+                None,
+                item.max_spread,
+                None,
+            )?;
+            (swap_msg, min_received)
+        },
+    };
+
+    let bond_msg = CallbackMsg::BondSwapped {
+        receiver,
+        snapshot,
+        min_received,
+        referral,
+        min_ustake_out,
+    }
+    .into_cosmos_msg(&env.contract.address)?;
+
+    let event = Event::new("erishub/bond_swap")
+        .add_attribute("denom", denom)
+        .add_attribute("amount", offer.amount)
+        .add_attribute("min_received", min_received);
+
+    Ok(Response::new()
+        .add_message(swap_msg)
+        .add_message(bond_msg)
+        .add_event(event)
+        .add_attribute("action", "erishub/bond_with_swap"))
+}
+
+/// Following the swap of `bond_with_swap`, bond the Luna received since the snapshot was taken
+pub fn bond_swapped(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    receiver: Addr,
+    snapshot: Coin,
+    min_received: Uint128,
+    referral: Option<Addr>,
+    min_ustake_out: Option<Uint128>,
+) -> StdResult<Response<TerraMsg>> {
+    let current_balance =
+        deps.querier.query_balance(&env.contract.address, &snapshot.denom)?.amount;
+    let received = current_balance.saturating_sub(snapshot.amount);
+
+    if received.is_zero() || received < min_received {
+        return Err(StdError::generic_err(format!(
+            "swap returned {}{}, less than the minimum of {}",
+            received, snapshot.denom, min_received
+        )));
+    }
+
+    bond(deps, env, receiver, received, false, referral, min_ustake_out)
 }
 
-pub fn harvest(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
-    let withdraw_msgs = deps
-        .querier
-        .query_all_delegations(&env.contract.address)?
+pub fn harvest(
+    mut deps: DepsMut<TerraQuery>,
+    env: Env,
+    keeper: Addr,
+) -> StdResult<Response<TerraMsg>> {
+    let slash_event = detect_slashing(&mut deps, &env)?;
+
+    // The keeper is only rewarded if keeper rewards are enabled
+    let state = State::default();
+    let fee_config = state.fee_config.load(deps.storage)?;
+    let keeper = fee_config.keeper_reward.map(|_| keeper);
+
+    let delegations = deps.querier.query_all_delegations(&env.contract.address)?;
+    for d in &delegations {
+        state.rewards_since.save(deps.storage, &d.validator, &env.block.time.seconds())?;
+    }
+
+    let withdraw_msgs = delegations
         .into_iter()
         .map(|d| {
             CosmosMsg::Distribution(DistributionMsg::WithdrawDelegatorReward {
                 validator: d.validator,
             })
         })
-        .collect::<Vec<CosmosMsg>>();
+        .collect::<Vec<CosmosMsg<TerraMsg>>>();
 
-    let callback_msgs = vec![CallbackMsg::Swap {}, CallbackMsg::Reinvest {}]
-        .iter()
-        .map(|callback| callback.into_cosmos_msg(&env.contract.address))
-        .collect::<StdResult<Vec<_>>>()?;
+    let callback_msgs = vec![
+        CallbackMsg::Swap {},
+        CallbackMsg::Reinvest {
+            keeper,
+        },
+    ]
+    .iter()
+    .map(|callback| callback.into_cosmos_msg(&env.contract.address))
+    .collect::<StdResult<Vec<_>>>()?;
 
-    Ok(Response::new()
+    let mut response = Response::new()
         .add_messages(withdraw_msgs)
         .add_message(check_received_coin_msg(&deps, &env, None)?)
-        .add_messages(callback_msgs)
-        .add_attribute("action", "erishub/harvest"))
+        .add_messages(callback_msgs);
+
+    if let Some(event) = slash_event {
+        response = response.add_event(event);
+    }
+
+    Ok(response.add_attribute("action", "erishub/harvest"))
+}
+
+/// Compare the current delegations against the last known ones. If a validator got slashed, the
+/// loss is shared between the bonded pool and the batches that are still unbonding. The amount
+/// deducted from the batches is recovered in `reconcile`, if the unbonding entries turn out not
+/// to have been slashed.
+fn detect_slashing(deps: &mut DepsMut<TerraQuery>, env: &Env) -> StdResult<Option<Event>> {
+    let state = State::default();
+    let validators = state.validators.load(deps.storage)?;
+    let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
+
+    let mut uluna_bonded = Uint128::zero();
+    let mut uluna_slashed = Uint128::zero();
+    let mut losses: Vec<String> = vec![];
+    for d in &delegations {
+        let last = state.last_delegations.may_load(deps.storage, &d.validator)?.unwrap_or_default();
+        let current = Uint128::new(d.amount);
+
+        // Delegated amounts are rounded down from the shares, so on validators that were slashed
+        // before, every delegation may come out slightly lower than recorded
+        let dust = get_slashing_dust_tolerance().checked_mul_uint(last)?.max(Uint128::new(1));
+        if current + dust < last {
+            uluna_slashed += last - current;
+            losses.push(format!("{}:{}", d.validator, last - current));
+        }
+        uluna_bonded += last;
+
+        if current.is_zero() {
+            state.last_delegations.remove(deps.storage, &d.validator);
+        } else {
+            state.last_delegations.save(deps.storage, &d.validator, &current)?;
+        }
+    }
+
+    if uluna_slashed.is_zero() {
+        return Ok(None);
+    }
+
+    // Only batches that are still unbonding are exposed to the slashing
+    let mut batches = state
+        .previous_batches
+        .idx
+        .reconciled
+        .prefix(false.into())
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (_, v) = item?;
+            Ok(v)
+        })
+        .filter(|b| b.as_ref().map_or(true, |b| b.est_unbond_end_time > env.block.time.seconds()))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let deductions = socialize_slashing(&mut batches, uluna_bonded, uluna_slashed);
+    let uluna_deducted: Uint128 = deductions.iter().sum();
+
+    for (batch, uluna_for_batch) in batches.iter().zip(deductions) {
+        state.previous_batches.save(deps.storage, batch.id, batch)?;
+        if !uluna_for_batch.is_zero() {
+            let deducted = state.slash_deductions.may_load(deps.storage, batch.id)?;
+            state.slash_deductions.save(
+                deps.storage,
+                batch.id,
+                &(deducted.unwrap_or_default() + uluna_for_batch),
+            )?;
+        }
+    }
+
+    if !uluna_deducted.is_zero() {
+        let slash_recovery = state.slash_recovery.may_load(deps.storage)?.unwrap_or_default();
+        state.slash_recovery.save(deps.storage, &(slash_recovery + uluna_deducted))?;
+    }
+
+    let ids = batches.iter().map(|b| b.id.to_string()).collect::<Vec<_>>().join(",");
+
+    Ok(Some(
+        Event::new("erishub/slashed")
+            .add_attribute("time", env.block.time.seconds().to_string())
+            .add_attribute("height", env.block.height.to_string())
+            .add_attribute("losses", losses.join(","))
+            .add_attribute("uluna_slashed", uluna_slashed)
+            .add_attribute("ids", ids)
+            .add_attribute("uluna_deducted", uluna_deducted),
+    ))
 }
 
-pub fn swap(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
+pub fn swap(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
     let swap_config = state.swap_config.load(deps.storage)?;
 
-    let mut swap_msgs: Vec<CosmosMsg> = vec![];
+    let mut swap_msgs: Vec<CosmosMsg<TerraMsg>> = vec![];
+    let mut route_msgs: Vec<SubMsg<TerraMsg>> = vec![];
+    let mut skip_events: Vec<Event> = vec![];
+
+    for (index, item) in swap_config.into_iter().enumerate() {
+        let balance = deps.querier.query_balance(env.contract.address.clone(), &item.denom)?;
+        if balance.amount.is_zero() {
+            continue;
+        }
+
+        // Routes with several hops or a minimum return are handled in a callback, so that the
+        // balances are queried only once the previous swaps have been executed. The callbacks run
+        // after all direct swaps, as a hop spends the whole balance of its denom. A route failing
+        // at any hop or below its minimum return is reverted on its own, so it does not block the
+        // harvest but is skipped until the next one
+        if !item.hops.is_empty() || item.min_return.is_some() {
+            route_msgs.push(SubMsg::reply_on_error(
+                CallbackMsg::SwapRoute {
+                    denom: balance.denom,
+                }
+                .into_cosmos_msg(&env.contract.address)?,
+                SWAP_ROUTE_REPLY_ID + index as u64,
+            ));
+            continue;
+        }
 
-    for item in swap_config.into_iter() {
-        let balance = deps.querier.query_balance(env.contract.address.clone(), item.denom)?;
-        if !balance.amount.is_zero() {
-            swap_msgs.push(
-                Asset {
-                    info: eris::asset::AssetInfo::NativeToken {
-                        denom: balance.denom.clone(),
+        let belief_price = match &item.price_source {
+            Some(price_source) => {
+                let offer = native_asset(balance.denom.clone(), balance.amount)
+                    .deduct_tax(&deps.querier)?;
+                match query_belief_price(&deps.querier, &item.contract, &offer, price_source)? {
+                    Some(belief_price) => Some(belief_price),
+                    None => {
+                        skip_events.push(swap_skipped_event(&offer.denom, "unknown price"));
+                        continue;
                     },
-                    amount: balance.amount,
                 }
-                .into_swap_msg(
-                    &deps.querier,
-                    item.contract.to_string(),
-                    None,
-                    None,
-                )?,
-            )
+            },
+            None => None,
+        };
+
+        let resolved =
+            resolve_swap(&deps, &item, &balance, CONTRACT_DENOM, belief_price.is_some())?;
+
+        if let Some(belief_price) = belief_price {
+            let return_amount = resolved.return_amount.unwrap_or_default();
+            let max_spread = item.max_spread.unwrap_or_default();
+            if exceeds_max_spread(resolved.offer_amount, return_amount, belief_price, max_spread) {
+                skip_events.push(
+                    swap_skipped_event(&balance.denom, "max spread exceeded")
+                        .add_attribute("belief_price", belief_price.to_string())
+                        .add_attribute("return_amount", return_amount),
+                );
+                continue;
+            }
+        }
+
+        swap_msgs.push(resolved_swap_msg(&deps, &item, balance, &resolved, belief_price)?);
+    }
+
+    Ok(Response::new()
+        .add_messages(swap_msgs)
+        .add_submessages(route_msgs)
+        .add_events(skip_events)
+        .add_attribute("action", "erishub/swap"))
+}
+
+pub fn skip_swap_route(
+    deps: DepsMut<TerraQuery>,
+    index: u64,
+    result: SubMsgResult,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+    let item = state
+        .swap_config
+        .load(deps.storage)?
+        .into_iter()
+        .nth(index as usize)
+        .ok_or_else(|| StdError::generic_err(format!("no swap route at index {}", index)))?;
+
+    let error = match result {
+        SubMsgResult::Err(error) => error,
+        SubMsgResult::Ok(_) => return Err(StdError::generic_err("swap route did not fail")),
+    };
+
+    Ok(Response::new()
+        .add_event(swap_skipped_event(&item.denom, "route failed").add_attribute("error", error))
+        .add_attribute("action", "erishub/skip_swap_route"))
+}
+
+fn swap_skipped_event(denom: &str, reason: &str) -> Event {
+    Event::new("erishub/swap_skipped").add_attribute("denom", denom).add_attribute("reason", reason)
+}
+
+pub fn swap_route(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    denom: String,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+    let item =
+        state
+            .swap_config
+            .load(deps.storage)?
+            .into_iter()
+            .find(|item| item.denom == denom)
+            .ok_or_else(|| StdError::generic_err(format!("no swap route for denom {}", denom)))?;
+
+    let balance = deps.querier.query_balance(&env.contract.address, &denom)?;
+    if balance.amount.is_zero() {
+        return Ok(Response::new().add_attribute("action", "erishub/swap_route"));
+    }
+
+    let snapshot = deps.querier.query_balance(&env.contract.address, CONTRACT_DENOM)?;
+    let min_received = match route_min_received(&deps, &item, &balance)? {
+        Some(min_received) => min_received,
+        None => {
+            return Ok(Response::new()
+                .add_event(swap_skipped_event(&balance.denom, "unknown price"))
+                .add_attribute("action", "erishub/swap_route"));
+        },
+    };
+
+    let ask_denom = item.hops.first().map_or(CONTRACT_DENOM, |hop| hop.denom.as_str());
+    let resolved = resolve_swap(&deps, &item, &balance, ask_denom, false)?;
+    let mut msgs = vec![resolved_swap_msg(&deps, &item, balance, &resolved, None)?];
+    for hop in item.hops {
+        msgs.push(
+            CallbackMsg::SwapHop {
+                hop,
+            }
+            .into_cosmos_msg(&env.contract.address)?,
+        );
+    }
+    msgs.push(
+        CallbackMsg::AssertMinReturn {
+            snapshot,
+            min_received,
+        }
+        .into_cosmos_msg(&env.contract.address)?,
+    );
+
+    Ok(Response::new().add_messages(msgs).add_attribute("action", "erishub/swap_route"))
+}
+
+pub fn swap_hop(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    hop: SwapHop,
+) -> StdResult<Response<TerraMsg>> {
+    let balance = deps.querier.query_balance(&env.contract.address, &hop.denom)?;
+
+    let mut response = Response::new();
+    if !balance.amount.is_zero() {
+        response = response.add_message(swap_coin_msg(
+            &deps,
+            balance,
+            &hop.contract,
+            None,
+            hop.max_spread,
+        )?);
+    }
+
+    Ok(response.add_attribute("action", "erishub/swap_hop"))
+}
+
+pub fn assert_min_return(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    snapshot: Coin,
+    min_received: Uint128,
+) -> StdResult<Response<TerraMsg>> {
+    let current_balance =
+        deps.querier.query_balance(&env.contract.address, &snapshot.denom)?.amount;
+    let received = current_balance.saturating_sub(snapshot.amount);
+
+    if received < min_received {
+        return Err(StdError::generic_err(format!(
+            "swap route returned {}{}, less than the minimum of {}",
+            received, snapshot.denom, min_received
+        )));
+    }
+
+    Ok(Response::new().add_attribute("action", "erishub/assert_min_return"))
+}
+
+/// Minimum amount of uluna the whole route must return for `balance`, from the configured
+/// `min_return` and, with a price source, the reference price minus the max spread. Returns `None`
+/// if the price source does not know the price
+fn route_min_received(
+    deps: &DepsMut<TerraQuery>,
+    item: &SwapConfig,
+    balance: &Coin,
+) -> StdResult<Option<Uint128>> {
+    let mut min_received = match item.min_return {
+        Some(min_return) => min_return.checked_mul_uint(balance.amount)?,
+        None => Uint128::zero(),
+    };
+
+    if let Some(price_source) = &item.price_source {
+        let offer =
+            native_asset(balance.denom.clone(), balance.amount).deduct_tax(&deps.querier)?;
+        match query_belief_price(&deps.querier, &item.contract, &offer, price_source)? {
+            Some(belief_price) => {
+                let expected_return =
+                    offer.amount.multiply_ratio(Decimal::one().atomics(), belief_price.atomics());
+                let max_spread = item.max_spread.unwrap_or_default().min(Decimal::one());
+                let min_return_at_price =
+                    (Decimal::one() - max_spread).checked_mul_uint(expected_return)?;
+                min_received = min_received.max(min_return_at_price);
+            },
+            None => return Ok(None),
         }
     }
 
-    Ok(Response::new().add_messages(swap_msgs).add_attribute("action", "erishub/swap"))
+    Ok(Some(min_received))
+}
+
+/// How the first hop of a swap route is executed, resolved from the configured `SwapKind`
+struct ResolvedSwap {
+    /// Either `SwapKind::Pair` or `SwapKind::Market`
+    kind: SwapKind,
+    /// Amount actually offered, after tax for pair contracts
+    offer_amount: Uint128,
+    /// Amount of the ask denom returned, if simulated
+    return_amount: Option<Uint128>,
+    ask_denom: String,
+}
+
+fn resolve_swap(
+    deps: &DepsMut<TerraQuery>,
+    item: &SwapConfig,
+    balance: &Coin,
+    ask_denom: &str,
+    simulate: bool,
+) -> StdResult<ResolvedSwap> {
+    let pair_offer =
+        native_asset(balance.denom.clone(), balance.amount).deduct_tax(&deps.querier)?;
+    let simulate_pair = || -> StdResult<Uint128> {
+        Ok(query_pair_simulation(&deps.querier, &item.contract, &pair_offer)?.return_amount)
+    };
+    let simulate_market = || -> StdResult<Uint128> {
+        Ok(TerraQuerier::new(&deps.querier).query_swap(balance.clone(), ask_denom)?.receive.amount)
+    };
+
+    let (kind, offer_amount, return_amount) = match item.kind {
+        SwapKind::Pair => {
+            (SwapKind::Pair, pair_offer.amount, simulate.then(simulate_pair).transpose()?)
+        },
+        SwapKind::Market => {
+            (SwapKind::Market, balance.amount, simulate.then(simulate_market).transpose()?)
+        },
+        SwapKind::BestRate => {
+            let pair_return = simulate_pair()?;
+            let market_return = simulate_market()?;
+            if market_return > pair_return {
+                (SwapKind::Market, balance.amount, Some(market_return))
+            } else {
+                (SwapKind::Pair, pair_offer.amount, Some(pair_return))
+            }
+        },
+    };
+
+    Ok(ResolvedSwap {
+        kind,
+        offer_amount,
+        return_amount,
+        ask_denom: ask_denom.to_string(),
+    })
+}
+
+fn resolved_swap_msg(
+    deps: &DepsMut<TerraQuery>,
+    item: &SwapConfig,
+    balance: Coin,
+    resolved: &ResolvedSwap,
+    belief_price: Option<Decimal>,
+) -> StdResult<CosmosMsg<TerraMsg>> {
+    match resolved.kind {
+        // the market module has no belief price, it is protected by the simulation only
+        SwapKind::Market => {
+            native_asset(balance.denom, balance.amount).into_market_swap_msg(&resolved.ask_denom)
+        },
+        _ => swap_coin_msg(deps, balance, &item.contract, belief_price, item.max_spread),
+    }
+}
+
+fn swap_coin_msg(
+    deps: &DepsMut<TerraQuery>,
+    coin: Coin,
+    contract: &Addr,
+    belief_price: Option<Decimal>,
+    max_spread: Option<Decimal>,
+) -> StdResult<CosmosMsg<TerraMsg>> {
+    native_asset(coin.denom, coin.amount).into_swap_msg(
+        &deps.querier,
+        contract.to_string(),
+        belief_price,
+        max_spread,
+        None,
+    )
 }
 
 /// NOTE:
@@ -259,8 +830,12 @@ pub fn swap(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
 /// because we have already withdrawn all claimable staking rewards previously in the same atomic
 /// execution.
 /// 2. Same as with `bond`, in the latest implementation we only delegate staking rewards with the
-/// validator that has the smallest delegation amount.
-pub fn reinvest(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
+/// validator that has the smallest delegation amount relative to its weight.
+pub fn reinvest(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    keeper: Option<Addr>,
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
     let validators = state.validators.load(deps.storage)?;
     let mut unlocked_coins = state.unlocked_coins.load(deps.storage)?;
@@ -273,43 +848,89 @@ pub fn reinvest(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
         .ok_or_else(|| StdError::generic_err("no uluna available to be bonded"))?
         .amount;
 
+    let weights = state.get_validator_weights(deps.storage, &validators)?;
     let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
-    let mut validator = &delegations[0].validator;
-    let mut amount = delegations[0].amount;
-    for d in &delegations[1..] {
-        if d.amount < amount {
-            validator = &d.validator;
-            amount = d.amount;
-        }
-    }
 
+    // Rewards are not delegated to validators exceeding the max commission, unless all of them do
+    let offenders = find_commission_offenders(deps.storage, &deps.querier, &validators)?;
+    let (eligible_delegations, eligible_weights) =
+        exclude_commission_offenders(&delegations, &weights, &offenders);
+    let validator = find_validator_to_delegate(&eligible_delegations, &eligible_weights);
+
+    // The keeper calling `Harvest` receives a capped share of the rewards
+    let uluna_keeper_reward = match (&keeper, &fee_config.keeper_reward) {
+        (Some(_), Some(keeper_reward)) => keeper_reward
+            .reward_share
+            .checked_mul_uint(uluna_available)?
+            .min(keeper_reward.max_reward),
+        _ => Uint128::zero(),
+    };
+
+    let uluna_to_bond = uluna_available.checked_sub(uluna_keeper_reward)?;
+
+    let uluna_reserve = state.get_liquid_reserve(deps.storage)?;
     let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
-    let protocol_fee_amount = fee_config.protocol_reward_fee.checked_mul_uint(uluna_available)?;
+    let protocol_fee_amount = fee_config.protocol_reward_fee.checked_mul_uint(uluna_to_bond)?;
     let protocol_fee_mint_amount =
-        compute_mint_amount(ustake_supply, protocol_fee_amount, &delegations);
-
-    let uluna_to_bond = uluna_available;
+        compute_mint_amount(ustake_supply, protocol_fee_amount, &delegations, uluna_reserve);
+
+    // Part of the rewards is kept undelegated to fund the liquid reserve. If the max reserve was
+    // lowered, the reserve above it is delegated along with the rewards
+    let reserve_config = state.get_reserve_config(deps.storage)?;
+    let uluna_to_reserve = compute_reserve_amount(uluna_to_bond, uluna_reserve, &reserve_config)?;
+    let uluna_reserve_surplus = uluna_reserve.saturating_sub(reserve_config.max_reserve);
+    let uluna_to_delegate = uluna_to_bond.checked_sub(uluna_to_reserve)? + uluna_reserve_surplus;
+    if !uluna_to_reserve.is_zero() || !uluna_reserve_surplus.is_zero() {
+        state
+            .liquid_reserve
+            .save(deps.storage, &(uluna_reserve + uluna_to_reserve - uluna_reserve_surplus))?;
+    }
 
-    let new_delegation = Delegation::new(validator, uluna_to_bond.u128());
+    let new_delegation = Delegation::new(validator, uluna_to_delegate.u128());
+    state.record_delegation(deps.storage, &new_delegation, env.block.time.seconds())?;
 
     unlocked_coins.retain(|coin| coin.denom != "uluna");
     state.unlocked_coins.save(deps.storage, &unlocked_coins)?;
 
-    let event = Event::new("erishub/harvested")
+    // The minted protocol fee is split between the fee recipients
+    let fee_split = compute_fee_split(&fee_config, protocol_fee_mint_amount)?;
+
+    let mut event = Event::new("erishub/harvested")
         .add_attribute("time", env.block.time.seconds().to_string())
         .add_attribute("height", env.block.height.to_string())
         .add_attribute("uluna_bonded", uluna_to_bond)
+        .add_attribute("uluna_reserved", uluna_to_reserve)
+        .add_attribute("uluna_reserve_surplus", uluna_reserve_surplus)
         .add_attribute("uluna_protocol_fee", protocol_fee_amount)
         .add_attribute("uluna_protocol_fee_mint", protocol_fee_mint_amount);
 
+    if !protocol_fee_mint_amount.is_zero() {
+        let fee_split = fee_split
+            .iter()
+            .map(|(recipient, amount)| format!("{}:{}", recipient, amount))
+            .collect::<Vec<_>>()
+            .join(",");
+        event = event.add_attribute("ustake_protocol_fee_split", fee_split);
+    }
+
+    if !offenders.is_empty() {
+        event = event.add_attribute("commission_exceeded", offenders.join(","));
+    }
+
     let mut msgs = vec![new_delegation.to_cosmos_msg()];
+    let mut events = vec![event];
 
-    if !protocol_fee_mint_amount.is_zero() {
-        let mint_msg: CosmosMsg = CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: stake_token.into(),
+    if let Some(keeper) = keeper.filter(|_| !uluna_keeper_reward.is_zero()) {
+        msgs.push(keeper_payment_msg(&deps, &keeper, uluna_keeper_reward)?);
+        events.push(keeper_paid_event(&keeper, uluna_keeper_reward));
+    }
+
+    for (recipient, amount) in fee_split.into_iter().filter(|(_, amount)| !amount.is_zero()) {
+        let mint_msg: CosmosMsg<TerraMsg> = CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: stake_token.to_string(),
             msg: to_binary(&Cw20ExecuteMsg::Mint {
-                recipient: fee_config.protocol_fee_contract.to_string(),
-                amount: protocol_fee_mint_amount,
+                recipient: recipient.to_string(),
+                amount,
             })?,
             funds: vec![],
         });
@@ -319,16 +940,22 @@ pub fn reinvest(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
 
     // update exchange_rate history
     let utoken_staked: u128 = delegations.iter().map(|d| d.amount).sum();
-    let total_utoken = utoken_staked + uluna_to_bond.u128();
+    let total_utoken = utoken_staked + uluna_reserve.u128() + uluna_to_bond.u128();
     let exchange_rate = calc_current_exchange_rate(
         total_utoken,
         ustake_supply.checked_add(protocol_fee_mint_amount)?,
     )?;
-    state.exchange_history.save(deps.storage, env.block.time.seconds(), &exchange_rate)?;
+    state.record_exchange_rate(
+        deps.storage,
+        env.block.time.seconds(),
+        exchange_rate,
+        ExchangeRateReason::Harvest,
+    )?;
+    state.compact_exchange_history(deps.storage, env.block.time.seconds())?;
 
     Ok(Response::new()
         .add_messages(msgs)
-        .add_event(event)
+        .add_events(events)
         .add_attribute("action", "erishub/reinvest")
         .add_attribute("exchange_rate", exchange_rate.to_string()))
 }
@@ -348,7 +975,7 @@ fn check_received_coin_msg(
     env: &Env,
     // offset to account for funds being sent that should be ignored
     negative_offset: Option<Uint128>,
-) -> StdResult<CosmosMsg> {
+) -> StdResult<CosmosMsg<TerraMsg>> {
     let mut amount =
         deps.querier.query_balance(env.contract.address.to_string(), CONTRACT_DENOM)?.amount;
 
@@ -403,9 +1030,32 @@ pub fn queue_unbond(
     env: Env,
     receiver: Addr,
     ustake_to_burn: Uint128,
-) -> StdResult<Response> {
+    min_uluna_out: Option<Uint128>,
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
 
+    // The uluna returned are only known once the batch is submitted, so the bound is checked
+    // against an estimate at the current exchange rate
+    if let Some(min_uluna_out) = min_uluna_out {
+        let stake_token = state.stake_token.load(deps.storage)?;
+        let validators = state.validators.load(deps.storage)?;
+        let fee_config = state.fee_config.load(deps.storage)?;
+        let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
+        let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
+        let uluna_reserve = state.get_liquid_reserve(deps.storage)?;
+
+        let uluna_estimated =
+            compute_unbond_amount(ustake_supply, ustake_to_burn, &delegations, uluna_reserve);
+        let uluna_estimated = uluna_estimated
+            .checked_sub(fee_config.withdraw_fee.checked_mul_uint(uluna_estimated)?)?;
+        if uluna_estimated < min_uluna_out {
+            return Err(StdError::generic_err(format!(
+                "estimated {} uluna, less than the minimum of {}",
+                uluna_estimated, min_uluna_out
+            )));
+        }
+    }
+
     let mut pending_batch = state.pending_batch.load(deps.storage)?;
     pending_batch.ustake_to_burn += ustake_to_burn;
     state.pending_batch.save(deps.storage, &pending_batch)?;
@@ -424,7 +1074,7 @@ pub fn queue_unbond(
         },
     )?;
 
-    let mut msgs: Vec<CosmosMsg> = vec![];
+    let mut msgs: Vec<CosmosMsg<TerraMsg>> = vec![];
     let mut start_time = pending_batch.est_unbond_start_time.to_string();
     if env.block.time.seconds() >= pending_batch.est_unbond_start_time {
         start_time = "immediate".to_string();
@@ -449,85 +1099,269 @@ pub fn queue_unbond(
         .add_attribute("action", "erishub/queue_unbond"))
 }
 
-pub fn submit_batch(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
+pub fn cancel_unbond(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    user: Addr,
+    amount: Option<Uint128>,
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
     let stake_token = state.stake_token.load(deps.storage)?;
-    let validators = state.validators.load(deps.storage)?;
-    let unbond_period = state.unbond_period.load(deps.storage)?;
-    let pending_batch = state.pending_batch.load(deps.storage)?;
 
-    let current_time = env.block.time.seconds();
-    if current_time < pending_batch.est_unbond_start_time {
+    // NOTE: Only requests in the pending batch can be cancelled. Once a batch is submitted, the Stake
+    // tokens have been burned and the Luna undelegated.
+    let mut pending_batch = state.pending_batch.load(deps.storage)?;
+    let mut request = state
+        .unbond_requests
+        .may_load(deps.storage, (pending_batch.id, &user))?
+        .ok_or_else(|| StdError::generic_err("no unbond request found in the pending batch"))?;
+
+    let ustake_to_return = amount.unwrap_or(request.shares);
+    if ustake_to_return.is_zero() {
+        return Err(StdError::generic_err("amount to cancel must be greater than zero"));
+    }
+    if ustake_to_return > request.shares {
         return Err(StdError::generic_err(format!(
-            "batch can only be submitted for unbonding after {}",
-            pending_batch.est_unbond_start_time
+            "cannot cancel {} ustake, only {} queued",
+            ustake_to_return, request.shares
         )));
     }
 
-    let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
-    let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
-
-    let uluna_to_unbond =
-        compute_unbond_amount(ustake_supply, pending_batch.ustake_to_burn, &delegations);
-    let new_undelegations = compute_undelegations(uluna_to_unbond, &delegations);
-
-    // NOTE: Regarding the `uluna_unclaimed` value
-    //
-    // If validators misbehave and get slashed during the unbonding period, the contract can receive
-    // LESS Luna than `uluna_to_unbond` when unbonding finishes!
-    //
-    // In this case, users who invokes `withdraw_unbonded` will have their txs failed as the contract
-    // does not have enough Luna balance.
-    //
-    // I don't have a solution for this... other than to manually fund contract with the slashed amount.
-    state.previous_batches.save(
-        deps.storage,
-        pending_batch.id,
-        &Batch {
-            id: pending_batch.id,
-            reconciled: false,
-            total_shares: pending_batch.ustake_to_burn,
-            uluna_unclaimed: uluna_to_unbond,
-            est_unbond_end_time: current_time + unbond_period,
-        },
-    )?;
-
-    let epoch_period = state.epoch_period.load(deps.storage)?;
-    state.pending_batch.save(
-        deps.storage,
-        &PendingBatch {
-            id: pending_batch.id + 1,
-            ustake_to_burn: Uint128::zero(),
-            est_unbond_start_time: current_time + epoch_period,
-        },
-    )?;
+    request.shares -= ustake_to_return;
+    if request.shares.is_zero() {
+        state.unbond_requests.remove(deps.storage, (pending_batch.id, &user))?;
+    } else {
+        state.unbond_requests.save(deps.storage, (pending_batch.id, &user), &request)?;
+    }
 
-    let undelegate_msgs = new_undelegations.iter().map(|d| d.to_cosmos_msg()).collect::<Vec<_>>();
+    pending_batch.ustake_to_burn -= ustake_to_return;
+    state.pending_batch.save(deps.storage, &pending_batch)?;
 
-    let burn_msg = CosmosMsg::Wasm(WasmMsg::Execute {
+    let transfer_msg = CosmosMsg::Wasm(WasmMsg::Execute {
         contract_addr: stake_token.into(),
-        msg: to_binary(&Cw20ExecuteMsg::Burn {
-            amount: pending_batch.ustake_to_burn,
+        msg: to_binary(&Cw20ExecuteMsg::Transfer {
+            recipient: user.to_string(),
+            amount: ustake_to_return,
         })?,
         funds: vec![],
     });
 
-    let event = Event::new("erishub/unbond_submitted")
+    let event = Event::new("erishub/unbond_cancelled")
         .add_attribute("time", env.block.time.seconds().to_string())
         .add_attribute("height", env.block.height.to_string())
         .add_attribute("id", pending_batch.id.to_string())
-        .add_attribute("uluna_unbonded", uluna_to_unbond)
-        .add_attribute("ustake_burned", pending_batch.ustake_to_burn);
+        .add_attribute("user", user)
+        .add_attribute("ustake_returned", ustake_to_return);
+
+    Ok(Response::new()
+        .add_message(transfer_msg)
+        .add_event(event)
+        .add_attribute("action", "erishub/cancel_unbond"))
+}
+
+/// Unbond instantly by paying out Luna from the liquid reserve. The instant unbond fee is kept in
+/// the reserve, so it accrues to the remaining stakers.
+pub fn instant_unbond(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    receiver: Addr,
+    ustake_to_burn: Uint128,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+    let stake_token = state.stake_token.load(deps.storage)?;
+    let validators = state.validators.load(deps.storage)?;
+    let reserve_config = state.get_reserve_config(deps.storage)?;
+    let uluna_reserve = state.get_liquid_reserve(deps.storage)?;
+
+    let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
+    let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
+
+    let uluna_unbonded =
+        compute_unbond_amount(ustake_supply, ustake_to_burn, &delegations, uluna_reserve);
+    let uluna_fee = reserve_config.instant_unbond_fee.checked_mul_uint(uluna_unbonded)?;
+    let uluna_to_refund = uluna_unbonded.checked_sub(uluna_fee)?;
+
+    if uluna_to_refund > uluna_reserve {
+        return Err(StdError::generic_err(format!(
+            "insufficient liquid reserve: {} available, {} required",
+            uluna_reserve, uluna_to_refund
+        )));
+    }
+
+    state.liquid_reserve.save(deps.storage, &(uluna_reserve - uluna_to_refund))?;
+
+    let burn_msg = CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: stake_token.into(),
+        msg: to_binary(&Cw20ExecuteMsg::Burn {
+            amount: ustake_to_burn,
+        })?,
+        funds: vec![],
+    });
+
+    let refund_asset = Asset {
+        info: AssetInfo::NativeToken {
+            denom: CONTRACT_DENOM.to_string(),
+        },
+        amount: uluna_to_refund,
+    };
+
+    let tax = refund_asset.compute_tax(&deps.querier)?;
+    let refund_msg = refund_asset.into_msg(&deps.querier, receiver.clone())?;
+
+    let event = Event::new("erishub/instant_unbonded")
+        .add_attribute("time", env.block.time.seconds().to_string())
+        .add_attribute("height", env.block.height.to_string())
+        .add_attribute("receiver", receiver)
+        .add_attribute("ustake_burned", ustake_to_burn)
+        .add_attribute("uluna_refunded", uluna_to_refund)
+        .add_attribute("uluna_fee", uluna_fee)
+        .add_attribute("tax", tax);
 
     Ok(Response::new()
-        .add_messages(undelegate_msgs)
         .add_message(burn_msg)
-        .add_message(check_received_coin_msg(&deps, &env, None)?)
+        .add_message(refund_msg)
+        .add_event(event)
+        .add_attribute("action", "erishub/instant_unbond"))
+}
+
+pub fn submit_batch(
+    mut deps: DepsMut<TerraQuery>,
+    env: Env,
+    keeper: Addr,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+    let stake_token = state.stake_token.load(deps.storage)?;
+    let validators = state.validators.load(deps.storage)?;
+    let unbond_period = state.unbond_period.load(deps.storage)?;
+    let pending_batch = state.pending_batch.load(deps.storage)?;
+
+    let current_time = env.block.time.seconds();
+    if current_time < pending_batch.est_unbond_start_time {
+        return Err(StdError::generic_err(format!(
+            "batch can only be submitted for unbonding after {}",
+            pending_batch.est_unbond_start_time
+        )));
+    }
+
+    // A loss since the last harvest is already reflected in the unbond amount, so it must not be
+    // socialized onto the new batch later
+    let slash_event = detect_slashing(&mut deps, &env)?;
+
+    let weights = state.get_validator_weights(deps.storage, &validators)?;
+    let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
+    let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
+    let uluna_reserve = state.get_liquid_reserve(deps.storage)?;
+
+    let uluna_to_unbond = compute_unbond_amount(
+        ustake_supply,
+        pending_batch.ustake_to_burn,
+        &delegations,
+        uluna_reserve,
+    );
+
+    // The batch's share of the liquid reserve is set aside for it, only the rest is undelegated
+    let uluna_staked: u128 = delegations.iter().map(|d| d.amount).sum();
+    let uluna_from_reserve =
+        uluna_reserve.multiply_ratio(pending_batch.ustake_to_burn, ustake_supply);
+    let uluna_to_undelegate =
+        uluna_to_unbond.checked_sub(uluna_from_reserve)?.min(Uint128::new(uluna_staked));
+    if !uluna_from_reserve.is_zero() {
+        state.liquid_reserve.save(deps.storage, &(uluna_reserve - uluna_from_reserve))?;
+        state.batch_reserves.save(deps.storage, pending_batch.id, &uluna_from_reserve)?;
+    }
+
+    let new_undelegations = compute_undelegations(uluna_to_undelegate, &delegations, &weights);
+
+    state.record_undelegations(deps.storage, &new_undelegations, current_time)?;
+
+    // Rounding in the unbond amount slightly changes the amount of uluna per ustake
+    let exchange_rate = calc_current_exchange_rate(
+        uluna_staked + uluna_reserve.u128() - uluna_to_unbond.u128(),
+        ustake_supply.checked_sub(pending_batch.ustake_to_burn)?,
+    )?;
+    state.record_exchange_rate(
+        deps.storage,
+        current_time,
+        exchange_rate,
+        ExchangeRateReason::SubmitBatch,
+    )?;
+
+    // NOTE: Regarding the `uluna_unclaimed` value
+    //
+    // If validators misbehave and get slashed during the unbonding period, the contract can receive
+    // LESS Luna than `uluna_to_unbond` when unbonding finishes. Slashing detected during `harvest`
+    // is shared between the bonded pool and the batches still unbonding, and `reconcile` deducts
+    // any remaining shortfall from the batches, so that `withdraw_unbonded` does not fail.
+    state.previous_batches.save(
+        deps.storage,
+        pending_batch.id,
+        &Batch {
+            id: pending_batch.id,
+            reconciled: false,
+            total_shares: pending_batch.ustake_to_burn,
+            uluna_unclaimed: uluna_to_unbond,
+            est_unbond_end_time: current_time + unbond_period,
+        },
+    )?;
+
+    let epoch_period = state.epoch_period.load(deps.storage)?;
+    state.pending_batch.save(
+        deps.storage,
+        &PendingBatch {
+            id: pending_batch.id + 1,
+            ustake_to_burn: Uint128::zero(),
+            est_unbond_start_time: current_time + epoch_period,
+        },
+    )?;
+
+    let undelegate_msgs = new_undelegations.iter().map(|d| d.to_cosmos_msg()).collect::<Vec<_>>();
+
+    let burn_msg = CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: stake_token.into(),
+        msg: to_binary(&Cw20ExecuteMsg::Burn {
+            amount: pending_batch.ustake_to_burn,
+        })?,
+        funds: vec![],
+    });
+
+    let event = Event::new("erishub/unbond_submitted")
+        .add_attribute("time", env.block.time.seconds().to_string())
+        .add_attribute("height", env.block.height.to_string())
+        .add_attribute("id", pending_batch.id.to_string())
+        .add_attribute("uluna_unbonded", uluna_to_unbond)
+        .add_attribute("uluna_from_reserve", uluna_from_reserve)
+        .add_attribute("ustake_burned", pending_batch.ustake_to_burn);
+
+    // Keepers are only paid if there was something to unbond. A batch submitted by the contract
+    // itself, as an unbond request is queued after the epoch, has no keeper to pay
+    let keeper_tip = if pending_batch.ustake_to_burn.is_zero() || keeper == env.contract.address {
+        None
+    } else {
+        pay_keeper_tip(&mut deps, &keeper)?
+    };
+
+    let mut response =
+        Response::new().add_events(slash_event).add_messages(undelegate_msgs).add_message(burn_msg);
+
+    // the tip is sent out of the contract's balance before the check, so it is deducted from the
+    // snapshot
+    let mut uluna_tip = None;
+    if let Some((amount, tip_msg, tip_event)) = keeper_tip {
+        uluna_tip = Some(amount);
+        response = response.add_message(tip_msg).add_event(tip_event);
+    }
+
+    Ok(response
+        .add_message(check_received_coin_msg(&deps, &env, uluna_tip)?)
         .add_event(event)
         .add_attribute("action", "erishub/unbond"))
 }
 
-pub fn reconcile(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
+pub fn reconcile(
+    mut deps: DepsMut<TerraQuery>,
+    env: Env,
+    keeper: Addr,
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
     let current_time = env.block.time.seconds();
 
@@ -544,10 +1378,8 @@ pub fn reconcile(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
         })
         .collect::<StdResult<Vec<_>>>()?;
 
-    let mut batches = all_batches
-        .into_iter()
-        .filter(|b| current_time > b.est_unbond_end_time)
-        .collect::<Vec<_>>();
+    let (mut batches, unfinished_batches): (Vec<_>, Vec<_>) =
+        all_batches.into_iter().partition(|b| current_time > b.est_unbond_end_time);
 
     let uluna_expected_received: Uint128 = batches.iter().map(|b| b.uluna_unclaimed).sum();
 
@@ -555,39 +1387,198 @@ pub fn reconcile(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
         return Ok(Response::new());
     }
 
-    let unlocked_coins = state.unlocked_coins.load(deps.storage)?;
-    let uluna_expected_unlocked = Coins(unlocked_coins).find("uluna").amount;
+    let mut unlocked_coins = Coins(state.unlocked_coins.load(deps.storage)?);
+    let uluna_expected_unlocked = unlocked_coins.find("uluna").amount;
+    let uluna_expected_reserve = state.get_liquid_reserve(deps.storage)?;
+
+    // Luna set aside from the reserve for batches still unbonding is already held by the contract
+    let uluna_expected_set_aside = unfinished_batches
+        .iter()
+        .map(|b| Ok(state.batch_reserves.may_load(deps.storage, b.id)?.unwrap_or_default()))
+        .collect::<StdResult<Vec<_>>>()?
+        .into_iter()
+        .sum::<Uint128>();
 
-    let uluna_expected = uluna_expected_received + uluna_expected_unlocked;
+    let uluna_expected = uluna_expected_received
+        + uluna_expected_unlocked
+        + uluna_expected_reserve
+        + uluna_expected_set_aside;
     let uluna_actual = deps.querier.query_balance(&env.contract.address, "uluna")?.amount;
 
-    if uluna_actual >= uluna_expected {
+    // Amount deducted from these batches for slashing detected while they were unbonding
+    let uluna_deducted_earlier = batches
+        .iter()
+        .map(|b| Ok(state.slash_deductions.may_load(deps.storage, b.id)?.unwrap_or_default()))
+        .collect::<StdResult<Vec<_>>>()?
+        .into_iter()
+        .sum::<Uint128>();
+
+    let event = if uluna_actual >= uluna_expected {
+        // Batches may have been reduced for slashing which turned out not to affect their
+        // unbonding entries. That surplus belongs to the bonded pool, and is reinvested with the
+        // next harvest. Luna of batches reconciled earlier, but not yet withdrawn, is not surplus.
+        let uluna_recovered = recover_slashed_amount(
+            &deps,
+            &state,
+            uluna_actual.saturating_sub(uluna_expected),
+            uluna_deducted_earlier,
+        )?;
+        if !uluna_recovered.is_zero() {
+            unlocked_coins.add(&Coin::new(uluna_recovered.u128(), CONTRACT_DENOM))?;
+            state.unlocked_coins.save(deps.storage, &unlocked_coins.0)?;
+        }
+
         mark_reconciled_batches(&mut batches);
         for batch in &batches {
             state.previous_batches.save(deps.storage, batch.id, batch)?;
         }
+
         let ids = batches.iter().map(|b| b.id.to_string()).collect::<Vec<_>>().join(",");
-        let event = Event::new("erishub/reconciled")
+        let mut event = Event::new("erishub/reconciled")
             .add_attribute("ids", ids)
             .add_attribute("uluna_deducted", "0");
-        return Ok(Response::new().add_event(event).add_attribute("action", "erishub/reconcile"));
-    }
+        if !uluna_recovered.is_zero() {
+            event = event.add_attribute("uluna_recovered", uluna_recovered);
+        }
+        event
+    } else {
+        // The expected amounts already exclude the earlier deductions, so the unbonding entries'
+        // loss is offset against them, and only the remaining shortfall is deducted
+        let uluna_to_deduct = uluna_expected - uluna_actual;
+
+        reconcile_batches(&mut batches, uluna_to_deduct);
+
+        for batch in &batches {
+            state.previous_batches.save(deps.storage, batch.id, batch)?;
+        }
 
-    let uluna_to_deduct = uluna_expected - uluna_actual;
+        let ids = batches.iter().map(|b| b.id.to_string()).collect::<Vec<_>>().join(",");
 
-    reconcile_batches(&mut batches, uluna_to_deduct);
+        Event::new("erishub/reconciled")
+            .add_attribute("ids", ids)
+            .add_attribute("uluna_deducted", uluna_to_deduct.to_string())
+    };
+
+    // Whatever was not recovered of the earlier deductions was lost to slashing
+    if !uluna_deducted_earlier.is_zero() {
+        let slash_recovery = state.slash_recovery.may_load(deps.storage)?.unwrap_or_default();
+        state
+            .slash_recovery
+            .save(deps.storage, &slash_recovery.saturating_sub(uluna_deducted_earlier))?;
+    }
 
     for batch in &batches {
-        state.previous_batches.save(deps.storage, batch.id, batch)?;
+        state.batch_reserves.remove(deps.storage, batch.id);
+        state.slash_deductions.remove(deps.storage, batch.id);
     }
 
-    let ids = batches.iter().map(|b| b.id.to_string()).collect::<Vec<_>>().join(",");
+    // Slashing shows up in the delegations, even if it was not detected during harvest yet
+    let stake_token = state.stake_token.load(deps.storage)?;
+    let validators = state.validators.load(deps.storage)?;
+    let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
+    let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
+    let uluna_staked: u128 = delegations.iter().map(|d| d.amount).sum();
+    let exchange_rate =
+        calc_current_exchange_rate(uluna_staked + uluna_expected_reserve.u128(), ustake_supply)?;
+    state.record_exchange_rate(
+        deps.storage,
+        current_time,
+        exchange_rate,
+        ExchangeRateReason::Reconcile,
+    )?;
+
+    let mut response = Response::new().add_event(event);
+
+    if let Some((_, tip_msg, tip_event)) = pay_keeper_tip(&mut deps, &keeper)? {
+        response = response.add_message(tip_msg).add_event(tip_event);
+    }
+
+    Ok(response.add_attribute("action", "erishub/reconcile"))
+}
+
+fn recover_slashed_amount(
+    deps: &DepsMut<TerraQuery>,
+    state: &State,
+    uluna_surplus: Uint128,
+    uluna_deducted: Uint128,
+) -> StdResult<Uint128> {
+    if uluna_deducted.is_zero() {
+        return Ok(Uint128::zero());
+    }
+
+    let uluna_reconciled_unclaimed = state
+        .previous_batches
+        .idx
+        .reconciled
+        .prefix(true.into())
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (_, v) = item?;
+            Ok(v.uluna_unclaimed)
+        })
+        .collect::<StdResult<Vec<_>>>()?
+        .into_iter()
+        .sum::<Uint128>();
+
+    Ok(uluna_surplus.saturating_sub(uluna_reconciled_unclaimed).min(uluna_deducted))
+}
+
+/// Pay the flat keeper tip out of the unlocked uluna, if one is configured. The tip is capped by
+/// the amount of unlocked uluna available.
+fn pay_keeper_tip(
+    deps: &mut DepsMut<TerraQuery>,
+    keeper: &Addr,
+) -> StdResult<Option<(Uint128, CosmosMsg<TerraMsg>, Event)>> {
+    let state = State::default();
+    let fee_config = state.fee_config.load(deps.storage)?;
+
+    let tip = match fee_config.keeper_reward {
+        Some(keeper_reward) => keeper_reward.tip,
+        None => return Ok(None),
+    };
+
+    let mut unlocked_coins = state.unlocked_coins.load(deps.storage)?;
+    let uluna_tip = match unlocked_coins.iter_mut().find(|coin| coin.denom == CONTRACT_DENOM) {
+        Some(coin) => {
+            let uluna_tip = tip.min(coin.amount);
+            coin.amount -= uluna_tip;
+            uluna_tip
+        },
+        None => Uint128::zero(),
+    };
+
+    if uluna_tip.is_zero() {
+        return Ok(None);
+    }
+
+    unlocked_coins.retain(|coin| !coin.amount.is_zero());
+    state.unlocked_coins.save(deps.storage, &unlocked_coins)?;
 
-    let event = Event::new("erishub/reconciled")
-        .add_attribute("ids", ids)
-        .add_attribute("uluna_deducted", uluna_to_deduct.to_string());
+    Ok(Some((
+        uluna_tip,
+        keeper_payment_msg(deps, keeper, uluna_tip)?,
+        keeper_paid_event(keeper, uluna_tip),
+    )))
+}
+
+fn keeper_payment_msg(
+    deps: &DepsMut<TerraQuery>,
+    keeper: &Addr,
+    amount: Uint128,
+) -> StdResult<CosmosMsg<TerraMsg>> {
+    Asset {
+        info: AssetInfo::NativeToken {
+            denom: CONTRACT_DENOM.to_string(),
+        },
+        amount,
+    }
+    .into_msg(&deps.querier, keeper.clone())
+}
 
-    Ok(Response::new().add_event(event).add_attribute("action", "erishub/reconcile"))
+fn keeper_paid_event(keeper: &Addr, amount: Uint128) -> Event {
+    Event::new("erishub/keeper_paid")
+        .add_attribute("keeper", keeper)
+        .add_attribute("uluna_paid", amount)
 }
 
 pub fn withdraw_unbonded(
@@ -595,104 +1586,245 @@ pub fn withdraw_unbonded(
     env: Env,
     user: Addr,
     receiver: Addr,
-) -> StdResult<Response> {
+    batch_ids: Option<Vec<u64>>,
+    limit: Option<u32>,
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
     let current_time = env.block.time.seconds();
 
-    // NOTE: If the user has too many unclaimed requests, this may not fit in the WASM memory...
-    // However, this is practically never going to happen. Who would create hundreds of unbonding
-    // requests and never claim them?
-    let requests = state
-        .unbond_requests
-        .idx
-        .user
-        .prefix(user.to_string())
-        .range(deps.storage, None, None, Order::Ascending)
-        .map(|item| {
-            let (_, v) = item?;
-            Ok(v)
-        })
-        .collect::<StdResult<Vec<_>>>()?;
-
     // NOTE: Luna in the following batches are withdrawn it the batch:
     // - is a _previous_ batch, not a _pending_ batch
     // - is reconciled
     // - has finished unbonding
     // If not sure whether the batches have been reconciled, the user should first invoke `ExecuteMsg::Reconcile`
     // before withdrawing.
+    let is_withdrawable = |request: &UnbondRequest| -> StdResult<Option<(UnbondRequest, Batch)>> {
+        Ok(state
+            .previous_batches
+            .may_load(deps.storage, request.id)?
+            .filter(|batch| batch.reconciled && batch.est_unbond_end_time < current_time)
+            .map(|batch| (request.clone(), batch)))
+    };
+
+    // If `batch_ids` is provided, only the user's requests in these batches are considered.
+    // Otherwise, all of the user's requests are iterated lazily, so that with a `limit` the contract
+    // never has to load more requests into memory than it is going to withdraw.
+    let limit = limit.map(|limit| limit as usize).unwrap_or(usize::MAX);
+    let withdrawable = match batch_ids {
+        Some(mut batch_ids) => {
+            batch_ids.sort_unstable();
+            batch_ids.dedup();
+            batch_ids
+                .into_iter()
+                .map(|id| {
+                    state.unbond_requests.may_load(deps.storage, (id, &user))?.ok_or_else(|| {
+                        StdError::generic_err(format!("no unbond request found in batch {}", id))
+                    })
+                })
+                .filter_map(|request| request.and_then(|r| is_withdrawable(&r)).transpose())
+                .take(limit)
+                .collect::<StdResult<Vec<_>>>()?
+        },
+        None => state
+            .unbond_requests
+            .idx
+            .user
+            .prefix(user.to_string())
+            .range(deps.storage, None, None, Order::Ascending)
+            .filter_map(|item| item.and_then(|(_, r)| is_withdrawable(&r)).transpose())
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?,
+    };
+
     let mut total_uluna_to_refund = Uint128::zero();
     let mut ids: Vec<String> = vec![];
-    for request in &requests {
-        if let Ok(mut batch) = state.previous_batches.load(deps.storage, request.id) {
-            if batch.reconciled && batch.est_unbond_end_time < current_time {
-                let uluna_to_refund =
-                    batch.uluna_unclaimed.multiply_ratio(request.shares, batch.total_shares);
-
-                ids.push(request.id.to_string());
-
-                total_uluna_to_refund += uluna_to_refund;
-                batch.total_shares -= request.shares;
-                batch.uluna_unclaimed -= uluna_to_refund;
-
-                if batch.total_shares.is_zero() {
-                    state.previous_batches.remove(deps.storage, request.id)?;
-                } else {
-                    state.previous_batches.save(deps.storage, batch.id, &batch)?;
-                }
+    for (request, mut batch) in withdrawable {
+        let uluna_to_refund =
+            batch.uluna_unclaimed.multiply_ratio(request.shares, batch.total_shares);
 
-                state.unbond_requests.remove(deps.storage, (request.id, &user))?;
-            }
+        ids.push(request.id.to_string());
+
+        total_uluna_to_refund += uluna_to_refund;
+        batch.total_shares -= request.shares;
+        batch.uluna_unclaimed -= uluna_to_refund;
+
+        if batch.total_shares.is_zero() {
+            state.previous_batches.remove(deps.storage, request.id)?;
+        } else {
+            state.previous_batches.save(deps.storage, batch.id, &batch)?;
         }
+
+        state.unbond_requests.remove(deps.storage, (request.id, &user))?;
     }
 
     if total_uluna_to_refund.is_zero() {
         return Err(StdError::generic_err("withdrawable amount is zero"));
     }
 
+    let fee_config = state.fee_config.load(deps.storage)?;
+    let uluna_withdraw_fee = fee_config.withdraw_fee.checked_mul_uint(total_uluna_to_refund)?;
+    let fee_msgs = compute_fee_split(&fee_config, uluna_withdraw_fee)?
+        .into_iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(recipient, amount)| {
+            native_asset(CONTRACT_DENOM.to_string(), amount).into_msg(&deps.querier, recipient)
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
     let refund_asset = Asset {
         info: AssetInfo::NativeToken {
             denom: "uluna".to_string(),
         },
-        amount: total_uluna_to_refund,
+        amount: total_uluna_to_refund.checked_sub(uluna_withdraw_fee)?,
     };
 
     let tax = refund_asset.compute_tax(&deps.querier)?;
-    let refund_msg = refund_asset.into_msg(&deps.querier, receiver.clone())?;
-
-    let event = Event::new("erishub/unbonded_withdrawn")
+    let mut event = Event::new("erishub/unbonded_withdrawn")
         .add_attribute("time", env.block.time.seconds().to_string())
         .add_attribute("height", env.block.height.to_string())
         .add_attribute("ids", ids.join(","))
         .add_attribute("user", user)
-        .add_attribute("receiver", receiver)
-        .add_attribute("uluna_refunded", total_uluna_to_refund)
+        .add_attribute("receiver", receiver.clone())
+        .add_attribute("uluna_refunded", refund_asset.amount)
         .add_attribute("tax", tax);
 
+    if !uluna_withdraw_fee.is_zero() {
+        event = event.add_attribute("uluna_withdraw_fee", uluna_withdraw_fee);
+    }
+
+    let refund_msg = refund_asset.into_msg(&deps.querier, receiver)?;
+
     Ok(Response::new()
         .add_message(refund_msg)
+        .add_messages(fee_msgs)
         .add_event(event)
         .add_attribute("action", "erishub/withdraw_unbonded"))
 }
 
+pub fn transfer_unbond_request(
+    deps: DepsMut<TerraQuery>,
+    env: Env,
+    user: Addr,
+    id: u64,
+    shares: Option<Uint128>,
+    recipient: Addr,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+
+    if recipient == user {
+        return Err(StdError::generic_err("cannot transfer unbond request to oneself"));
+    }
+
+    let mut request = state
+        .unbond_requests
+        .may_load(deps.storage, (id, &user))?
+        .ok_or_else(|| StdError::generic_err(format!("no unbond request found in batch {}", id)))?;
+
+    let shares = shares.unwrap_or(request.shares);
+    if shares.is_zero() {
+        return Err(StdError::generic_err("shares to transfer must be greater than zero"));
+    }
+    if shares > request.shares {
+        return Err(StdError::generic_err(format!(
+            "cannot transfer {} shares, only {} available",
+            shares, request.shares
+        )));
+    }
+
+    request.shares -= shares;
+    if request.shares.is_zero() {
+        state.unbond_requests.remove(deps.storage, (id, &user))?;
+    } else {
+        state.unbond_requests.save(deps.storage, (id, &user), &request)?;
+    }
+
+    state.unbond_requests.update(deps.storage, (id, &recipient), |x| -> StdResult<_> {
+        let mut request = x.unwrap_or_else(|| UnbondRequest {
+            id,
+            user: recipient.clone(),
+            shares: Uint128::zero(),
+        });
+        request.shares += shares;
+        Ok(request)
+    })?;
+
+    let event = Event::new("erishub/unbond_request_transferred")
+        .add_attribute("time", env.block.time.seconds().to_string())
+        .add_attribute("height", env.block.height.to_string())
+        .add_attribute("id", id.to_string())
+        .add_attribute("user", user)
+        .add_attribute("recipient", recipient)
+        .add_attribute("shares", shares);
+
+    Ok(Response::new().add_event(event).add_attribute("action", "erishub/transfer_unbond_request"))
+}
+
 //--------------------------------------------------------------------------------------------------
 // Ownership and management logics
 //--------------------------------------------------------------------------------------------------
 
-pub fn rebalance(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
+pub fn rebalance(
+    mut deps: DepsMut<TerraQuery>,
+    env: Env,
+    max_moves: Option<u32>,
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
+
+    // A loss since the last harvest must be accounted for before the delegations change
+    let slash_event = detect_slashing(&mut deps, &env)?;
     let validators = state.validators.load(deps.storage)?;
+    let mut weights = state.get_validator_weights(deps.storage, &validators)?;
+
+    // Stake is moved away from validators exceeding the max commission, unless all of them do
+    let offenders = find_commission_offenders(deps.storage, &deps.querier, &validators)?;
+    if offenders.len() < validators.len() {
+        for (validator, weight) in validators.iter().zip(weights.iter_mut()) {
+            if offenders.contains(validator) {
+                *weight = 0;
+            }
+        }
+    }
 
     let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
 
-    let new_redelegations = compute_redelegations_for_rebalancing(&delegations);
+    let now = env.block.time.seconds();
+    let pending_redelegations = state.load_pending_redelegations(deps.storage, now)?;
+    let is_blocked = |src: &str, dst: &str| {
+        find_redelegation_block(&pending_redelegations, src, Some(dst)).is_some()
+    };
+
+    let rebalance_config = state.get_rebalance_config(deps.storage)?;
+    let (mut new_redelegations, uluna_skipped) = compute_redelegations_for_rebalancing(
+        &delegations,
+        &weights,
+        rebalance_config.tolerance,
+        is_blocked,
+    );
+
+    // Small redelegations are not worth the gas. With `max_moves`, the largest ones are made first
+    // and the rest is left to later calls
+    new_redelegations.retain(|rd| rd.amount >= rebalance_config.min_redelegation.u128());
+    if let Some(max_moves) = max_moves {
+        new_redelegations.sort_by_key(|rd| std::cmp::Reverse(rd.amount));
+        new_redelegations.truncate(max_moves as usize);
+    }
+
+    state.record_redelegations(deps.storage, &new_redelegations, now)?;
 
     let redelegate_msgs = new_redelegations.iter().map(|rd| rd.to_cosmos_msg()).collect::<Vec<_>>();
 
     let amount: u128 = new_redelegations.iter().map(|rd| rd.amount).sum();
 
-    let event = Event::new("erishub/rebalanced").add_attribute("uluna_moved", amount.to_string());
+    let mut event =
+        Event::new("erishub/rebalanced").add_attribute("uluna_moved", amount.to_string());
+    if uluna_skipped > 0 {
+        event = event.add_attribute("uluna_skipped", uluna_skipped.to_string());
+    }
+    if !offenders.is_empty() {
+        event = event.add_attribute("commission_exceeded", offenders.join(","));
+    }
 
-    let check_msg: Option<CosmosMsg> = if !redelegate_msgs.is_empty() {
+    let check_msg: Option<CosmosMsg<TerraMsg>> = if !redelegate_msgs.is_empty() {
         // only check coins if a redelegation is happening
         Some(check_received_coin_msg(&deps, &env, None)?)
     } else {
@@ -700,20 +1832,254 @@ pub fn rebalance(deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response> {
     };
 
     Ok(Response::new()
+        .add_events(slash_event)
         .add_messages(redelegate_msgs)
         .add_optional_message(check_msg)
         .add_event(event)
         .add_attribute("action", "erishub/rebalance"))
 }
 
+/// NOTE: The rewards of a delegation are withdrawn at every harvest and whenever the delegation
+/// changes, so the rewards per uluna delegated are divided by the time they have accumulated for
+/// to be comparable between validators. Both missed blocks and commission lower them. As the
+/// accumulated rewards are rounded down to whole uluna, the score is only meaningful once rewards
+/// have accumulated for a while; validators whose delegation just changed are not told apart from
+/// the best one.
+pub fn evaluate_validators(
+    mut deps: DepsMut<TerraQuery>,
+    env: Env,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+    let validators = state.validators.load(deps.storage)?;
+    let max_commission = state.get_max_commission(deps.storage)?;
+    let active_set = deps.querier.query_all_validators()?;
+
+    let mut evaluations: Vec<(String, Decimal, bool, Option<Decimal>)> = vec![];
+    let mut evictions: Vec<(String, &str)> = vec![];
+    for validator in validators {
+        let active_validator = active_set.iter().find(|v| v.address == validator);
+        let commission = active_validator.map(|v| v.commission).unwrap_or_default();
+
+        // uluna earned per uluna delegated and second, `None` if no time has passed to tell
+        let elapsed = state
+            .rewards_since
+            .may_load(deps.storage, &validator)?
+            .map_or(0, |since| env.block.time.seconds().saturating_sub(since));
+        let reward_rate = match deps.querier.query_delegation(&env.contract.address, &validator)? {
+            Some(d) if !d.amount.amount.is_zero() => {
+                let uluna_rewards = d
+                    .accumulated_rewards
+                    .iter()
+                    .find(|coin| coin.denom == CONTRACT_DENOM)
+                    .map(|coin| coin.amount)
+                    .unwrap_or_default();
+                (elapsed > 0).then(|| {
+                    Decimal::from_ratio(uluna_rewards, d.amount.amount.u128() * elapsed as u128)
+                })
+            },
+            _ => Some(Decimal::zero()),
+        };
+
+        if active_validator.is_none() {
+            evictions.push((validator.clone(), "inactive"));
+        } else if commission > max_commission {
+            evictions.push((validator.clone(), "commission"));
+        }
+
+        evaluations.push((validator, commission, active_validator.is_some(), reward_rate));
+    }
+
+    let best_reward_rate = evaluations
+        .iter()
+        .filter(|(validator, ..)| !evictions.iter().any(|(evicted, _)| evicted == validator))
+        .filter_map(|(.., reward_rate)| *reward_rate)
+        .max()
+        .unwrap_or_default();
+
+    let mut event = Event::new("erishub/validators_evaluated");
+    for (validator, commission, active, reward_rate) in evaluations {
+        if evictions.iter().any(|(evicted, _)| *evicted == validator) {
+            continue;
+        }
+
+        // Right after a harvest no rewards have accumulated yet, so all validators score the same
+        let score = match reward_rate {
+            Some(reward_rate) if !best_reward_rate.is_zero() => {
+                Decimal::from_ratio(reward_rate.atomics(), best_reward_rate.atomics())
+            },
+            _ => Decimal::one(),
+        };
+        state.validator_scores.save(
+            deps.storage,
+            &validator,
+            &ValidatorScore {
+                score,
+                commission,
+                active,
+                time: env.block.time.seconds(),
+            },
+        )?;
+        event = event.add_attribute(validator, score.to_string());
+    }
+
+    let mut response = Response::new();
+    if !evictions.is_empty() {
+        let (redelegations, events) = delist_validators(&mut deps, &env, &evictions)?;
+        if !redelegations.is_empty() {
+            response = response
+                .add_messages(redelegations.iter().map(|rd| rd.to_cosmos_msg()))
+                .add_message(check_received_coin_msg(&deps, &env, None)?);
+        }
+        response = response.add_events(events);
+    }
+
+    Ok(response.add_event(event).add_attribute("action", "erishub/evaluate_validators"))
+}
+
+pub fn evict_validators(mut deps: DepsMut<TerraQuery>, env: Env) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+    let validators = state.validators.load(deps.storage)?;
+
+    let offenders = find_commission_offenders(deps.storage, &deps.querier, &validators)?;
+    if offenders.is_empty() {
+        return Err(StdError::generic_err("no validator exceeds the max commission"));
+    }
+
+    let slash_event = detect_slashing(&mut deps, &env)?;
+
+    let evictions = offenders.into_iter().map(|v| (v, "commission")).collect::<Vec<_>>();
+    let (redelegations, events) = delist_validators(&mut deps, &env, &evictions)?;
+
+    let mut response = Response::new().add_events(slash_event);
+    if !redelegations.is_empty() {
+        response = response
+            .add_messages(redelegations.iter().map(|rd| rd.to_cosmos_msg()))
+            .add_message(check_received_coin_msg(&deps, &env, None)?);
+    }
+
+    Ok(response.add_events(events).add_attribute("action", "erishub/evict_validators"))
+}
+
+/// Stake received by a redelegation can not be redelegated again until it completes, and only a
+/// limited number of redelegations can be in progress between two validators. Returns why stake
+/// can not be moved from `src` to `dst` yet, if it can not. Without a `dst`, any validator the
+/// limit is reached with blocks the source.
+fn find_redelegation_block(
+    pending_redelegations: &[(String, String, usize)],
+    src: &str,
+    dst: Option<&str>,
+) -> Option<String> {
+    pending_redelegations.iter().find_map(|(pending_src, pending_dst, entries)| {
+        if pending_dst == src {
+            Some(format!("a redelegation to {} is still in progress", src))
+        } else if pending_src == src
+            && dst.is_none_or(|dst| pending_dst == dst)
+            && *entries >= MAX_REDELEGATION_ENTRIES
+        {
+            Some(format!("too many redelegations from {} to {} in progress", src, pending_dst))
+        } else {
+            None
+        }
+    })
+}
+
+/// Assert that the stake of a validator can be redelegated, to `dst` or to any validator
+fn assert_redelegation_unblocked(
+    storage: &mut dyn Storage,
+    now: u64,
+    src: &str,
+    dst: Option<&str>,
+) -> StdResult<()> {
+    let pending_redelegations = State::default().load_pending_redelegations(storage, now)?;
+    match find_redelegation_block(&pending_redelegations, src, dst) {
+        Some(block) => {
+            Err(StdError::generic_err(format!("cannot redelegate from {}: {}", src, block)))
+        },
+        None => Ok(()),
+    }
+}
+
+/// Remove validators from the whitelist, redelegating their stake to the remaining validators.
+/// Each validator is given together with the reason of its eviction. Validators whose stake can not
+/// be redelegated yet are left whitelisted until the next eviction
+fn delist_validators(
+    deps: &mut DepsMut<TerraQuery>,
+    env: &Env,
+    evictions: &[(String, &str)],
+) -> StdResult<(Vec<Redelegation>, Vec<Event>)> {
+    let state = State::default();
+
+    let pending_redelegations =
+        state.load_pending_redelegations(deps.storage, env.block.time.seconds())?;
+    let mut events: Vec<Event> = vec![];
+    let mut evictable = vec![];
+    for (validator, reason) in evictions {
+        match find_redelegation_block(&pending_redelegations, validator, None) {
+            Some(block) => events.push(
+                Event::new("erishub/validator_eviction_postponed")
+                    .add_attribute("validator", validator)
+                    .add_attribute("reason", *reason)
+                    .add_attribute("blocked", block),
+            ),
+            None => evictable.push((validator.clone(), *reason)),
+        }
+    }
+    let evictions = evictable;
+    if evictions.is_empty() {
+        return Ok((vec![], events));
+    }
+
+    let validators = state.validators.update(deps.storage, |mut validators| {
+        validators.retain(|v| !evictions.iter().any(|(evicted, _)| evicted == v));
+        if validators.is_empty() {
+            return Err(StdError::generic_err("cannot evict all whitelisted validators"));
+        }
+        Ok(validators)
+    })?;
+
+    let weights = state.get_validator_weights(deps.storage, &validators)?;
+    let mut delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
+
+    let mut redelegations: Vec<Redelegation> = vec![];
+    for (validator, reason) in &evictions {
+        state.validator_weights.remove(deps.storage, validator);
+        state.validator_scores.remove(deps.storage, validator);
+
+        let delegation_to_remove =
+            query_delegation(&deps.querier, validator, &env.contract.address)?;
+        let new_redelegations =
+            compute_redelegations_for_removal(&delegation_to_remove, &delegations, &weights);
+
+        // the stake of validators evicted later is distributed on top of these redelegations
+        for rd in &new_redelegations {
+            if let Some(d) = delegations.iter_mut().find(|d| d.validator == rd.dst) {
+                d.amount += rd.amount;
+            }
+        }
+
+        events.push(
+            Event::new("erishub/validator_evicted")
+                .add_attribute("validator", validator)
+                .add_attribute("reason", *reason)
+                .add_attribute("uluna_redelegated", delegation_to_remove.amount.to_string()),
+        );
+        redelegations.extend(new_redelegations);
+    }
+
+    state.record_redelegations(deps.storage, &redelegations, env.block.time.seconds())?;
+
+    Ok((redelegations, events))
+}
+
 pub fn add_validator(
     deps: DepsMut<TerraQuery>,
     sender: Addr,
     validator: String,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
 
     state.assert_owner(deps.storage, &sender)?;
+    assert_max_commission(&deps, &validator)?;
 
     state.validators.update(deps.storage, |mut validators| {
         if validators.contains(&validator) {
@@ -728,15 +2094,38 @@ pub fn add_validator(
     Ok(Response::new().add_event(event).add_attribute("action", "erishub/add_validator"))
 }
 
+/// Assert that a validator to be whitelisted does not charge more than `max_commission`
+fn assert_max_commission(deps: &DepsMut<TerraQuery>, validator: &str) -> StdResult<()> {
+    let max_commission = State::default().get_max_commission(deps.storage)?;
+    if max_commission < Decimal::one() {
+        let commission = deps
+            .querier
+            .query_validator(validator)?
+            .ok_or_else(|| StdError::generic_err(format!("validator {} not found", validator)))?
+            .commission;
+        if commission > max_commission {
+            return Err(StdError::generic_err(format!(
+                "validator {} charges a commission of {}, more than the max of {}",
+                validator, commission, max_commission
+            )));
+        }
+    }
+    Ok(())
+}
+
 pub fn remove_validator(
-    deps: DepsMut<TerraQuery>,
+    mut deps: DepsMut<TerraQuery>,
     env: Env,
     sender: Addr,
     validator: String,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
 
     state.assert_owner(deps.storage, &sender)?;
+    assert_redelegation_unblocked(deps.storage, env.block.time.seconds(), &validator, None)?;
+
+    // A loss on the removed validator would go unnoticed once it is no longer whitelisted
+    let slash_event = detect_slashing(&mut deps, &env)?;
 
     let validators = state.validators.update(deps.storage, |mut validators| {
         if !validators.contains(&validator) {
@@ -745,10 +2134,15 @@ pub fn remove_validator(
         validators.retain(|v| *v != validator);
         Ok(validators)
     })?;
+    state.validator_weights.remove(deps.storage, &validator);
+    state.validator_scores.remove(deps.storage, &validator);
 
+    let weights = state.get_validator_weights(deps.storage, &validators)?;
     let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
     let delegation_to_remove = query_delegation(&deps.querier, &validator, &env.contract.address)?;
-    let new_redelegations = compute_redelegations_for_removal(&delegation_to_remove, &delegations);
+    let new_redelegations =
+        compute_redelegations_for_removal(&delegation_to_remove, &delegations, &weights);
+    state.record_redelegations(deps.storage, &new_redelegations, env.block.time.seconds())?;
 
     let redelegate_msgs = new_redelegations.iter().map(|d| d.to_cosmos_msg()).collect::<Vec<_>>();
 
@@ -762,17 +2156,101 @@ pub fn remove_validator(
     };
 
     Ok(Response::new()
+        .add_events(slash_event)
         .add_messages(redelegate_msgs)
         .add_optional_message(check_msg)
         .add_event(event)
         .add_attribute("action", "erishub/remove_validator"))
 }
 
+/// NOTE: Unlike removing the old validator and adding the new one, this takes a single
+/// redelegation, so the stake of the other validators stays free to be redelegated.
+pub fn replace_validator(
+    mut deps: DepsMut<TerraQuery>,
+    env: Env,
+    sender: Addr,
+    old: String,
+    new: String,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+
+    state.assert_owner(deps.storage, &sender)?;
+    assert_max_commission(&deps, &new)?;
+    assert_redelegation_unblocked(deps.storage, env.block.time.seconds(), &old, Some(&new))?;
+
+    // A loss on the replaced validator would go unnoticed once it is no longer whitelisted
+    let slash_event = detect_slashing(&mut deps, &env)?;
+
+    state.validators.update(deps.storage, |mut validators| {
+        if validators.contains(&new) {
+            return Err(StdError::generic_err("validator is already whitelisted"));
+        }
+        let index = validators
+            .iter()
+            .position(|v| *v == old)
+            .ok_or_else(|| StdError::generic_err("validator is not already whitelisted"))?;
+        validators[index] = new.clone();
+        Ok(validators)
+    })?;
+
+    // The new validator takes over the weight of the old one
+    if let Some(weight) = state.validator_weights.may_load(deps.storage, &old)? {
+        state.validator_weights.save(deps.storage, &new, &weight)?;
+        state.validator_weights.remove(deps.storage, &old);
+    }
+    state.validator_scores.remove(deps.storage, &old);
+
+    let delegation = query_delegation(&deps.querier, &old, &env.contract.address)?;
+    let event = Event::new("erishub/validator_replaced")
+        .add_attribute("old", &old)
+        .add_attribute("new", &new)
+        .add_attribute("uluna_redelegated", delegation.amount.to_string());
+
+    let mut response = Response::new().add_events(slash_event);
+    if delegation.amount > 0 {
+        let redelegations = [Redelegation::new(&old, &new, delegation.amount)];
+        state.record_redelegations(deps.storage, &redelegations, env.block.time.seconds())?;
+        response = response
+            .add_message(redelegations[0].to_cosmos_msg())
+            .add_message(check_received_coin_msg(&deps, &env, None)?);
+    }
+
+    Ok(response.add_event(event).add_attribute("action", "erishub/replace_validator"))
+}
+
+pub fn set_validator_weights(
+    deps: DepsMut<TerraQuery>,
+    sender: Addr,
+    weights: Vec<(String, u64)>,
+) -> StdResult<Response<TerraMsg>> {
+    let state = State::default();
+
+    state.assert_owner(deps.storage, &sender)?;
+
+    let validators = state.validators.load(deps.storage)?;
+    let mut event = Event::new("erishub/validator_weights_updated");
+    for (validator, weight) in weights {
+        if !validators.contains(&validator) {
+            return Err(StdError::generic_err(format!(
+                "validator {} is not whitelisted",
+                validator
+            )));
+        }
+        if weight == 0 {
+            return Err(StdError::generic_err("validator weight must be greater than zero"));
+        }
+        state.validator_weights.save(deps.storage, &validator, &weight)?;
+        event = event.add_attribute(validator, weight.to_string());
+    }
+
+    Ok(Response::new().add_event(event).add_attribute("action", "erishub/set_validator_weights"))
+}
+
 pub fn transfer_ownership(
     deps: DepsMut<TerraQuery>,
     sender: Addr,
     new_owner: String,
-) -> StdResult<Response> {
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
 
     state.assert_owner(deps.storage, &sender)?;
@@ -781,7 +2259,7 @@ pub fn transfer_ownership(
     Ok(Response::new().add_attribute("action", "erishub/transfer_ownership"))
 }
 
-pub fn accept_ownership(deps: DepsMut<TerraQuery>, sender: Addr) -> StdResult<Response> {
+pub fn accept_ownership(deps: DepsMut<TerraQuery>, sender: Addr) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
 
     let previous_owner = state.owner.load(deps.storage)?;
@@ -803,16 +2281,35 @@ pub fn accept_ownership(deps: DepsMut<TerraQuery>, sender: Addr) -> StdResult<Re
 
 pub fn update_config(
     deps: DepsMut<TerraQuery>,
+    env: Env,
     sender: Addr,
-    protocol_fee_contract: Option<String>,
-    protocol_reward_fee: Option<Decimal>,
-    swap_config: Option<Vec<SwapConfig>>,
-) -> StdResult<Response> {
+    msg: UpdateConfigMsg,
+) -> StdResult<Response<TerraMsg>> {
     let state = State::default();
 
     state.assert_owner(deps.storage, &sender)?;
 
-    if protocol_fee_contract.is_some() || protocol_reward_fee.is_some() {
+    let UpdateConfigMsg {
+        protocol_fee_contract,
+        protocol_reward_fee,
+        swap_config,
+        reserve_config,
+        keeper_reward,
+        fee_recipients,
+        deposit_fee,
+        withdraw_fee,
+        history_config,
+        max_commission,
+        rebalance_config,
+    } = msg;
+
+    if protocol_fee_contract.is_some()
+        || protocol_reward_fee.is_some()
+        || keeper_reward.is_some()
+        || fee_recipients.is_some()
+        || deposit_fee.is_some()
+        || withdraw_fee.is_some()
+    {
         let mut fee_config = state.fee_config.load(deps.storage)?;
 
         if let Some(protocol_fee_contract) = protocol_fee_contract {
@@ -826,6 +2323,31 @@ pub fn update_config(
             fee_config.protocol_reward_fee = protocol_reward_fee;
         }
 
+        if let Some(keeper_reward) = keeper_reward {
+            if keeper_reward.reward_share.gt(&get_keeper_reward_share_cap()) {
+                return Err(StdError::generic_err("'reward_share' greater than max"));
+            }
+            fee_config.keeper_reward = Some(keeper_reward);
+        }
+
+        if let Some(fee_recipients) = fee_recipients {
+            fee_config.fee_recipients = check_fee_recipients(&fee_recipients, deps.api)?;
+        }
+
+        if let Some(deposit_fee) = deposit_fee {
+            if deposit_fee.gt(&get_deposit_fee_cap()) {
+                return Err(StdError::generic_err("'deposit_fee' greater than max"));
+            }
+            fee_config.deposit_fee = deposit_fee;
+        }
+
+        if let Some(withdraw_fee) = withdraw_fee {
+            if withdraw_fee.gt(&get_withdraw_fee_cap()) {
+                return Err(StdError::generic_err("'withdraw_fee' greater than max"));
+            }
+            fee_config.withdraw_fee = withdraw_fee;
+        }
+
         state.fee_config.save(deps.storage, &fee_config)?;
     }
 
@@ -834,5 +2356,51 @@ pub fn update_config(
         state.swap_config.save(deps.storage, &swap_config)?;
     }
 
+    if let Some(reserve_config) = reserve_config {
+        if reserve_config.reserve_share.gt(&get_reserve_share_cap()) {
+            return Err(StdError::generic_err("'reserve_share' greater than max"));
+        }
+        if reserve_config.instant_unbond_fee.gt(&get_instant_unbond_fee_cap()) {
+            return Err(StdError::generic_err("'instant_unbond_fee' greater than max"));
+        }
+        state.reserve_config.save(deps.storage, &reserve_config)?;
+    }
+
+    if let Some(history_config) = history_config {
+        set_history_config(deps.storage, env.block.time.seconds(), history_config)?;
+    }
+
+    if let Some(max_commission) = max_commission {
+        if max_commission > Decimal::one() {
+            return Err(StdError::generic_err("'max_commission' greater than max"));
+        }
+        state.max_commission.save(deps.storage, &max_commission)?;
+    }
+
+    if let Some(rebalance_config) = rebalance_config {
+        if rebalance_config.tolerance.gt(&get_rebalance_tolerance_cap()) {
+            return Err(StdError::generic_err("'tolerance' greater than max"));
+        }
+        state.rebalance_config.save(deps.storage, &rebalance_config)?;
+    }
+
     Ok(Response::new().add_attribute("action", "erishub/update_config"))
 }
+
+/// Save the retention policy of the exchange rate history, and compact the existing history right
+/// away, so that the compaction during `reinvest` only needs to handle recent exchange rates
+pub fn set_history_config(
+    storage: &mut dyn Storage,
+    now: u64,
+    history_config: HistoryConfig,
+) -> StdResult<()> {
+    if history_config.daily_resolution_period < history_config.full_resolution_period {
+        return Err(StdError::generic_err(
+            "'daily_resolution_period' must not be less than 'full_resolution_period'",
+        ));
+    }
+
+    let state = State::default();
+    state.history_config.save(storage, &history_config)?;
+    state.compact_exchange_history(storage, now)
+}
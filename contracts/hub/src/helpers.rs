@@ -1,10 +1,14 @@
+use crate::constants::CONTRACT_DENOM;
+use crate::state::State;
 use crate::types::Delegation;
-use classic_bindings::TerraQuery;
+use classic_bindings::{TerraQuerier, TerraQuery};
 use cosmwasm_std::{
-    Addr, Api, Coin, QuerierWrapper, Reply, StdError, StdResult, SubMsgResponse, Uint128,
+    Addr, Api, Coin, Decimal, QuerierWrapper, Reply, StdError, StdResult, Storage, SubMsgResponse,
+    Uint128,
 };
 use cw20::{Cw20QueryMsg, TokenInfoResponse};
-use eris::{asset::addr_validate_to_lower, hub::SwapConfig};
+use eris::asset::{addr_validate_to_lower, native_asset, PairQueryMsg, SimulationResponse};
+use eris::hub::{PriceSource, SwapConfig};
 use std::{collections::HashSet, str::FromStr};
 
 /// Unwrap a `Reply` object to extract the response
@@ -22,6 +26,71 @@ pub(crate) fn query_cw20_total_supply(
     Ok(token_info.total_supply)
 }
 
+/// Query the simulated result of swapping a coin through a pair contract
+pub(crate) fn query_pair_simulation(
+    querier: &QuerierWrapper<TerraQuery>,
+    pair_contract: &Addr,
+    offer: &Coin,
+) -> StdResult<SimulationResponse> {
+    querier.query_wasm_smart(
+        pair_contract,
+        &PairQueryMsg::Simulation {
+            offer_asset: native_asset(offer.denom.clone(), offer.amount),
+        },
+    )
+}
+
+/// Query the reference price of a coin in uluna, as the amount offered per uluna received, so that
+/// it can be used as belief price. Returns `None` if the price source does not know the denom
+pub(crate) fn query_belief_price(
+    querier: &QuerierWrapper<TerraQuery>,
+    pair_contract: &Addr,
+    offer: &Coin,
+    price_source: &PriceSource,
+) -> StdResult<Option<Decimal>> {
+    let received = match price_source {
+        PriceSource::Oracle {} => {
+            // the oracle quotes the price of Luna in the denom, which is the belief price already.
+            // The query fails if the oracle does not know the denom at all
+            let exchange_rate = TerraQuerier::new(querier)
+                .query_exchange_rates(CONTRACT_DENOM, vec![offer.denom.as_str()])
+                .ok()
+                .and_then(|res| {
+                    res.exchange_rates.into_iter().find(|item| item.quote_denom == offer.denom)
+                })
+                .map(|item| item.exchange_rate);
+            return Ok(exchange_rate.filter(|rate| !rate.is_zero()));
+        },
+        PriceSource::Market {} => {
+            TerraQuerier::new(querier).query_swap(offer.clone(), CONTRACT_DENOM)?.receive.amount
+        },
+        PriceSource::Simulation {} => {
+            query_pair_simulation(querier, pair_contract, offer)?.return_amount
+        },
+    };
+
+    if received.is_zero() {
+        return Ok(None);
+    }
+
+    Ok(Some(Decimal::from_ratio(offer.amount, received)))
+}
+
+/// Whether swapping at the simulated return would exceed the max spread allowed against the
+/// belief price. Mirrors the check of the pair contracts, so that swaps failing it can be skipped
+/// instead of reverting the whole transaction
+pub(crate) fn exceeds_max_spread(
+    offer_amount: Uint128,
+    return_amount: Uint128,
+    belief_price: Decimal,
+    max_spread: Decimal,
+) -> bool {
+    let expected_return =
+        offer_amount.multiply_ratio(Decimal::one().atomics(), belief_price.atomics());
+    return_amount < expected_return
+        && Decimal::from_ratio(expected_return - return_amount, expected_return) > max_spread
+}
+
 /// Query the amounts of Luna a staker is delegating to a specific validator
 pub(crate) fn query_delegation(
     querier: &QuerierWrapper<TerraQuery>,
@@ -103,6 +172,40 @@ pub fn dedupe(v: &mut Vec<String>) {
     v.retain(|x| set.insert(x.clone()));
 }
 
+/// Checks that the fee recipients are valid addresses, appear only once, and their weights sum up to 1
+pub fn check_fee_recipients(
+    fee_recipients: &[(String, Decimal)],
+    api: &dyn Api,
+) -> StdResult<Vec<(Addr, Decimal)>> {
+    if fee_recipients.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut recipients = HashSet::new();
+    let mut total_weight = Decimal::zero();
+    for (recipient, weight) in fee_recipients {
+        if !recipients.insert(recipient) {
+            return Err(StdError::generic_err(format!("duplicate fee recipient '{}'", recipient)));
+        }
+        if weight.is_zero() {
+            return Err(StdError::generic_err(format!(
+                "fee recipient '{}' has a weight of zero",
+                recipient
+            )));
+        }
+        total_weight += *weight;
+    }
+
+    if total_weight != Decimal::one() {
+        return Err(StdError::generic_err("weights of fee recipients must sum up to 1"));
+    }
+
+    fee_recipients
+        .iter()
+        .map(|(recipient, weight)| Ok((api.addr_validate(recipient)?, *weight)))
+        .collect()
+}
+
 /// Checks if the swap config is valid
 pub fn check_swap_config(swaps: &[SwapConfig], api: &dyn Api) -> StdResult<()> {
     let mut set = HashSet::new();
@@ -114,6 +217,69 @@ pub fn check_swap_config(swaps: &[SwapConfig], api: &dyn Api) -> StdResult<()> {
             )));
         }
         addr_validate_to_lower(api, swap.contract.as_str())?;
+
+        if swap.price_source.is_some() && swap.max_spread.is_none() {
+            return Err(StdError::generic_err(format!(
+                "'max_spread' is required for denom '{}' in swap config with a price source",
+                swap.denom
+            )));
+        }
+        if swap.price_source == Some(PriceSource::Simulation {}) && !swap.hops.is_empty() {
+            return Err(StdError::generic_err(format!(
+                "price source 'simulation' is not supported for denom '{}' in swap config with hops",
+                swap.denom
+            )));
+        }
+
+        let mut offered = swap.denom.as_str();
+        for hop in &swap.hops {
+            if hop.denom == offered {
+                return Err(StdError::generic_err(format!(
+                    "hop for denom '{}' in swap config offers the same denom as before",
+                    swap.denom
+                )));
+            }
+            addr_validate_to_lower(api, hop.contract.as_str())?;
+            offered = hop.denom.as_str();
+        }
     }
     Ok(())
 }
+
+/// Find the validators charging more than `max_commission`. As no validator can charge more than
+/// 100%, with the default max commission of 1 no validator is queried
+pub(crate) fn find_commission_offenders(
+    storage: &dyn Storage,
+    querier: &QuerierWrapper<TerraQuery>,
+    validators: &[String],
+) -> StdResult<Vec<String>> {
+    let max_commission = State::default().get_max_commission(storage)?;
+    if max_commission >= Decimal::one() {
+        return Ok(vec![]);
+    }
+
+    let mut offenders = vec![];
+    for validator in validators {
+        if let Some(v) = querier.query_validator(validator)? {
+            if v.commission > max_commission {
+                offenders.push(validator.clone());
+            }
+        }
+    }
+    Ok(offenders)
+}
+
+/// Leave out the delegations to validators exceeding the max commission, so that no new stake is
+/// delegated to them, unless all of them do
+pub(crate) fn exclude_commission_offenders(
+    delegations: &[Delegation],
+    weights: &[u64],
+    offenders: &[String],
+) -> (Vec<Delegation>, Vec<u64>) {
+    delegations
+        .iter()
+        .cloned()
+        .zip(weights.iter().copied())
+        .filter(|(d, _)| offenders.len() == delegations.len() || !offenders.contains(&d.validator))
+        .unzip()
+}
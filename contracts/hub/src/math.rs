@@ -1,9 +1,11 @@
 use std::{cmp, cmp::Ordering};
 
-use cosmwasm_std::Uint128;
+use cosmwasm_std::{Addr, Decimal, StdResult, Uint128};
 
-use eris::hub::Batch;
+use eris::hub::{Batch, FeeConfig, ReserveConfig};
+use eris::DecimalCheckedOps;
 
+use crate::constants::DAY;
 use crate::types::{Delegation, Redelegation, Undelegation};
 
 //--------------------------------------------------------------------------------------------------
@@ -13,16 +15,20 @@ use crate::types::{Delegation, Redelegation, Undelegation};
 /// Compute the amount of Stake token to mint for a specific Luna stake amount. If current total
 /// staked amount is zero, we use 1 ustake = 1 uluna; otherwise, we calculate base on the current
 /// uluna per ustake ratio.
+///
+/// Luna held in the liquid reserve backs the Stake token the same way as delegated Luna does.
 pub(crate) fn compute_mint_amount(
     ustake_supply: Uint128,
     uluna_to_bond: Uint128,
     current_delegations: &[Delegation],
+    uluna_reserve: Uint128,
 ) -> Uint128 {
     let uluna_bonded: u128 = current_delegations.iter().map(|d| d.amount).sum();
-    if uluna_bonded == 0 {
+    let uluna_total = uluna_bonded + uluna_reserve.u128();
+    if uluna_total == 0 {
         uluna_to_bond
     } else {
-        ustake_supply.multiply_ratio(uluna_to_bond, uluna_bonded)
+        ustake_supply.multiply_ratio(uluna_to_bond, uluna_total)
     }
 }
 
@@ -34,47 +40,112 @@ pub(crate) fn compute_unbond_amount(
     ustake_supply: Uint128,
     ustake_to_burn: Uint128,
     current_delegations: &[Delegation],
+    uluna_reserve: Uint128,
 ) -> Uint128 {
     let uluna_bonded: u128 = current_delegations.iter().map(|d| d.amount).sum();
-    Uint128::new(uluna_bonded).multiply_ratio(ustake_to_burn, ustake_supply)
+    Uint128::new(uluna_bonded + uluna_reserve.u128()).multiply_ratio(ustake_to_burn, ustake_supply)
+}
+
+/// Compute the amount of `uluna` to keep undelegated in the liquid reserve out of a new deposit,
+/// without exceeding the maximum size of the reserve
+pub(crate) fn compute_reserve_amount(
+    uluna_deposited: Uint128,
+    uluna_reserve: Uint128,
+    reserve_config: &ReserveConfig,
+) -> StdResult<Uint128> {
+    let uluna_to_reserve = reserve_config.reserve_share.checked_mul_uint(uluna_deposited)?;
+    let uluna_missing = reserve_config.max_reserve.saturating_sub(uluna_reserve);
+    Ok(cmp::min(uluna_to_reserve, uluna_missing))
+}
+
+/// Split a fee amount between the fee recipients of the fee config according to their weights. The
+/// rounding remainder goes to the first recipient.
+pub(crate) fn compute_fee_split(
+    fee_config: &FeeConfig,
+    amount: Uint128,
+) -> StdResult<Vec<(Addr, Uint128)>> {
+    if fee_config.fee_recipients.is_empty() {
+        return Ok(vec![(fee_config.protocol_fee_contract.clone(), amount)]);
+    }
+
+    let mut split = fee_config
+        .fee_recipients
+        .iter()
+        .map(|(recipient, weight)| Ok((recipient.clone(), weight.checked_mul_uint(amount)?)))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let distributed: Uint128 = split.iter().map(|(_, amount)| *amount).sum();
+    split[0].1 += amount.checked_sub(distributed)?;
+
+    Ok(split)
 }
 
 //--------------------------------------------------------------------------------------------------
 // Delegation logics
 //--------------------------------------------------------------------------------------------------
 
+/// Compute the amount of `uluna` each validator should have delegated to it, such that the total
+/// amount is split proportionally to the validators' weights. The rounding remainder is assigned
+/// one `uluna` at a time, starting from the first validator.
+pub(crate) fn compute_target_delegations(uluna_total: u128, weights: &[u64]) -> Vec<u128> {
+    let total_weight: u128 = weights.iter().map(|w| *w as u128).sum();
+
+    let mut targets = weights
+        .iter()
+        .map(|w| Uint128::new(uluna_total).multiply_ratio(*w, total_weight).u128())
+        .collect::<Vec<_>>();
+
+    // validators with a weight of zero do not receive any of the remainder
+    let remainder = uluna_total - targets.iter().sum::<u128>();
+    for (target, _) in
+        targets.iter_mut().zip(weights).filter(|(_, w)| **w > 0).take(remainder as usize)
+    {
+        *target += 1;
+    }
+
+    targets
+}
+
+/// Find the validator with the smallest delegation relative to its weight, which is the one that
+/// should receive new delegations. With equal weights, this is simply the smallest delegation.
+///
+/// The code for linear search is a bit uglier than using `sort_by` but cheaper: O(n) vs O(n * log(n))
+pub(crate) fn find_validator_to_delegate<'a>(
+    current_delegations: &'a [Delegation],
+    weights: &[u64],
+) -> &'a str {
+    let mut index = 0;
+    for (i, d) in current_delegations.iter().enumerate().skip(1) {
+        // compare `amount / weight` of both validators without the division
+        let current = Uint128::new(d.amount).full_mul(weights[index]);
+        let smallest = Uint128::new(current_delegations[index].amount).full_mul(weights[i]);
+        if current < smallest {
+            index = i;
+        }
+    }
+    &current_delegations[index].validator
+}
+
 /// Given the current delegations made to validators, and a specific amount of `uluna` to unstake,
-/// compute the undelegations to make such that the delegated amount to each validator is as even
-/// as possible.
+/// compute the undelegations to make such that the delegated amount to each validator is as close
+/// to its weighted target as possible.
 ///
 /// This function is based on Lido's implementation:
 /// https://github.com/lidofinance/lido-terra-contracts/blob/v1.0.2/contracts/lido_terra_validators_registry/src/common.rs#L55-102
 pub(crate) fn compute_undelegations(
     uluna_to_unbond: Uint128,
     current_delegations: &[Delegation],
+    weights: &[u64],
 ) -> Vec<Undelegation> {
     let uluna_staked: u128 = current_delegations.iter().map(|d| d.amount).sum();
-    let validator_count = current_delegations.len() as u128;
 
     let uluna_to_distribute = uluna_staked - uluna_to_unbond.u128();
-    let uluna_per_validator = uluna_to_distribute / validator_count;
-    let remainder = uluna_to_distribute % validator_count;
+    let targets = compute_target_delegations(uluna_to_distribute, weights);
 
     let mut new_undelegations: Vec<Undelegation> = vec![];
     let mut uluna_available = uluna_to_unbond.u128();
-    for (i, d) in current_delegations.iter().enumerate() {
-        let remainder_for_validator: u128 = if (i + 1) as u128 <= remainder {
-            1
-        } else {
-            0
-        };
-        let uluna_for_validator = uluna_per_validator + remainder_for_validator;
-
-        let mut uluna_to_undelegate = if d.amount < uluna_for_validator {
-            0
-        } else {
-            d.amount - uluna_for_validator
-        };
+    for (d, uluna_for_validator) in current_delegations.iter().zip(targets) {
+        let mut uluna_to_undelegate = d.amount.saturating_sub(uluna_for_validator);
 
         uluna_to_undelegate = std::cmp::min(uluna_to_undelegate, uluna_available);
         uluna_available -= uluna_to_undelegate;
@@ -93,36 +164,24 @@ pub(crate) fn compute_undelegations(
 
 /// Given a validator who is to be removed from the whitelist, and current delegations made to other
 /// validators, compute the new delegations to make such that the delegated amount to each validator
-// is as even as possible.
+// is as close to its weighted target as possible.
 ///
 /// This function is based on Lido's implementation:
 /// https://github.com/lidofinance/lido-terra-contracts/blob/v1.0.2/contracts/lido_terra_validators_registry/src/common.rs#L19-L53
 pub(crate) fn compute_redelegations_for_removal(
     delegation_to_remove: &Delegation,
     current_delegations: &[Delegation],
+    weights: &[u64],
 ) -> Vec<Redelegation> {
     let uluna_staked: u128 = current_delegations.iter().map(|d| d.amount).sum();
-    let validator_count = current_delegations.len() as u128;
 
     let uluna_to_distribute = uluna_staked + delegation_to_remove.amount;
-    let uluna_per_validator = uluna_to_distribute / validator_count;
-    let remainder = uluna_to_distribute % validator_count;
+    let targets = compute_target_delegations(uluna_to_distribute, weights);
 
     let mut new_redelegations: Vec<Redelegation> = vec![];
     let mut uluna_available = delegation_to_remove.amount;
-    for (i, d) in current_delegations.iter().enumerate() {
-        let remainder_for_validator: u128 = if (i + 1) as u128 <= remainder {
-            1
-        } else {
-            0
-        };
-        let uluna_for_validator = uluna_per_validator + remainder_for_validator;
-
-        let mut uluna_to_redelegate = if d.amount > uluna_for_validator {
-            0
-        } else {
-            uluna_for_validator - d.amount
-        };
+    for (d, uluna_for_validator) in current_delegations.iter().zip(targets) {
+        let mut uluna_to_redelegate = uluna_for_validator.saturating_sub(d.amount);
 
         uluna_to_redelegate = std::cmp::min(uluna_to_redelegate, uluna_available);
         uluna_available -= uluna_to_redelegate;
@@ -147,14 +206,18 @@ pub(crate) fn compute_redelegations_for_removal(
 /// this sentence makes sense)
 ///
 /// This algorithm does not guarantee the minimal number of moves, but is the best I can some up with...
+///
+/// Delegations deviating from their target by no more than `tolerance` are left as they are. Pairs
+/// of validators for which `is_blocked` returns true are skipped. Also returns the amount of uluna
+/// that could not be redelegated because of that.
 pub(crate) fn compute_redelegations_for_rebalancing(
     current_delegations: &[Delegation],
-) -> Vec<Redelegation> {
+    weights: &[u64],
+    tolerance: Decimal,
+    is_blocked: impl Fn(&str, &str) -> bool,
+) -> (Vec<Redelegation>, u128) {
     let uluna_staked: u128 = current_delegations.iter().map(|d| d.amount).sum();
-    let validator_count = current_delegations.len() as u128;
-
-    let uluna_per_validator = uluna_staked / validator_count;
-    let remainder = uluna_staked % validator_count;
+    let targets = compute_target_delegations(uluna_staked, weights);
 
     // If a validator's current delegated amount is greater than the target amount, Luna will be
     // redelegated _from_ them. They will be put in `src_validators` vector
@@ -162,14 +225,11 @@ pub(crate) fn compute_redelegations_for_rebalancing(
     // redelegated _to_ them. They will be put in `dst_validators` vector
     let mut src_delegations: Vec<Delegation> = vec![];
     let mut dst_delegations: Vec<Delegation> = vec![];
-    for (i, d) in current_delegations.iter().enumerate() {
-        let remainder_for_validator: u128 = if (i + 1) as u128 <= remainder {
-            1
-        } else {
-            0
-        };
-        let uluna_for_validator = uluna_per_validator + remainder_for_validator;
-
+    for (d, uluna_for_validator) in current_delegations.iter().zip(targets) {
+        let uluna_tolerated = (Uint128::new(uluna_for_validator) * tolerance).u128();
+        if d.amount.abs_diff(uluna_for_validator) <= uluna_tolerated {
+            continue;
+        }
         match d.amount.cmp(&uluna_for_validator) {
             Ordering::Greater => {
                 src_delegations.push(Delegation::new(&d.validator, d.amount - uluna_for_validator));
@@ -182,9 +242,21 @@ pub(crate) fn compute_redelegations_for_rebalancing(
     }
 
     let mut new_redelegations: Vec<Redelegation> = vec![];
+    let mut uluna_skipped: u128 = 0;
     while !src_delegations.is_empty() && !dst_delegations.is_empty() {
         let src_delegation = src_delegations[0].clone();
-        let dst_delegation = dst_delegations[0].clone();
+        let dst_index = match dst_delegations
+            .iter()
+            .position(|d| !is_blocked(&src_delegation.validator, &d.validator))
+        {
+            Some(index) => index,
+            None => {
+                uluna_skipped += src_delegation.amount;
+                src_delegations.remove(0);
+                continue;
+            },
+        };
+        let dst_delegation = dst_delegations[dst_index].clone();
         let uluna_to_redelegate = cmp::min(src_delegation.amount, dst_delegation.amount);
 
         if src_delegation.amount == uluna_to_redelegate {
@@ -194,9 +266,9 @@ pub(crate) fn compute_redelegations_for_rebalancing(
         }
 
         if dst_delegation.amount == uluna_to_redelegate {
-            dst_delegations.remove(0);
+            dst_delegations.remove(dst_index);
         } else {
-            dst_delegations[0].amount -= uluna_to_redelegate;
+            dst_delegations[dst_index].amount -= uluna_to_redelegate;
         }
 
         new_redelegations.push(Redelegation::new(
@@ -206,7 +278,7 @@ pub(crate) fn compute_redelegations_for_rebalancing(
         ));
     }
 
-    new_redelegations
+    (new_redelegations, uluna_skipped)
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -237,9 +309,72 @@ pub(crate) fn reconcile_batches(batches: &mut [Batch], uluna_to_deduct: Uint128)
     }
 }
 
+/// If validators got slashed, the loss is shared between the bonded pool and the batches that are
+/// still unbonding, proportionally to the amount of `uluna` each of them holds. Returns the amount
+/// deducted from each batch.
+///
+/// The bonded pool bears its part automatically through a lower exchange rate.
+pub(crate) fn socialize_slashing(
+    batches: &mut [Batch],
+    uluna_bonded: Uint128,
+    uluna_slashed: Uint128,
+) -> Vec<Uint128> {
+    let uluna_unbonding: Uint128 = batches.iter().map(|b| b.uluna_unclaimed).sum();
+    let uluna_at_risk = uluna_bonded + uluna_unbonding;
+    if uluna_at_risk.is_zero() {
+        return vec![Uint128::zero(); batches.len()];
+    }
+
+    batches
+        .iter_mut()
+        .map(|batch| {
+            let uluna_for_batch =
+                uluna_slashed.multiply_ratio(batch.uluna_unclaimed, uluna_at_risk);
+            batch.uluna_unclaimed -= uluna_for_batch;
+            uluna_for_batch
+        })
+        .collect()
+}
+
 /// If all funds are available we still need to mark batches as reconciled
 pub(crate) fn mark_reconciled_batches(batches: &mut [Batch]) {
     for (_, batch) in batches.iter_mut().enumerate() {
         batch.reconciled = true;
     }
 }
+
+//--------------------------------------------------------------------------------------------------
+// Yield logics
+//--------------------------------------------------------------------------------------------------
+
+/// Estimate the relative growth of the exchange rate per day, by fitting a line through the given
+/// `(timestamp, exchange_rate)` points with the least squares method, and dividing its slope by the
+/// average exchange rate. Unlike comparing only the first and last points, a single outlier does not
+/// skew the result much. A declining exchange rate results in zero growth.
+///
+/// Returns `None` if there are fewer than two distinct timestamps.
+pub(crate) fn compute_daily_yield(points: &[(u64, Decimal)]) -> Option<Decimal> {
+    let t0 = points.iter().map(|(time, _)| *time).min()?;
+    let n = points.len() as i128;
+
+    // Exact integer sums, with times relative to the first point and rates in atomics (1e-18)
+    let (mut sum_t, mut sum_r, mut sum_tt, mut sum_tr) = (0i128, 0i128, 0i128, 0i128);
+    for (time, rate) in points {
+        let t = (time - t0) as i128;
+        let r = rate.atomics().u128() as i128;
+        sum_t += t;
+        sum_r += r;
+        sum_tt += t * t;
+        sum_tr += t * r;
+    }
+
+    let numerator = n * sum_tr - sum_t * sum_r;
+    let denominator = n * sum_tt - sum_t * sum_t;
+    if denominator == 0 || sum_r == 0 {
+        return None;
+    }
+
+    // growth per day / average rate = (numerator * DAY / denominator) / (sum_r / n)
+    let growth_per_day = numerator.checked_mul(DAY as i128)? / denominator;
+    Some(Decimal::from_ratio((growth_per_day.max(0) * n) as u128, sum_r as u128))
+}
@@ -1,31 +1,50 @@
 use std::ops::Div;
 
 use crate::constants::DAY;
-use crate::helpers::{query_cw20_total_supply, query_delegations};
+use crate::helpers::{
+    exclude_commission_offenders, find_commission_offenders, query_cw20_total_supply,
+    query_delegations,
+};
+use crate::math::{
+    compute_daily_yield, compute_mint_amount, compute_reserve_amount, compute_unbond_amount,
+    find_validator_to_delegate,
+};
 use crate::state::State;
 use classic_bindings::TerraQuery;
-use cosmwasm_std::{Addr, Decimal, Deps, Env, Order, StdResult, Uint128};
+use cosmwasm_std::{Addr, Decimal, Deps, Env, Order, StdError, StdResult, Uint128};
 use cw_storage_plus::Bound;
 use eris::hub::{
-    Batch, ConfigResponse, ExchangeRatesResponse, PendingBatch, StateResponse,
-    UnbondRequestsByBatchResponseItem, UnbondRequestsByUserResponseItem,
-    UnbondRequestsByUserResponseItemDetails,
+    Batch, ConfigResponse, ExchangeRateAtResponse, ExchangeRateItem, ExchangeRatesResponse,
+    KeeperStatusResponse, PendingBatch, ReferralResponseItem, SimulateBondResponse,
+    SimulateUnbondResponse, StateResponse, UnbondRequestsByBatchResponseItem,
+    UnbondRequestsByUserResponseItem, UnbondRequestsByUserResponseItemDetails, ValidatorScore,
+    YieldFigures, YieldResponse,
 };
+use eris::DecimalCheckedOps;
 
 const MAX_LIMIT: u32 = 30;
 const DEFAULT_LIMIT: u32 = 10;
+const MAX_YIELD_WINDOW: u64 = 365 * DAY;
+const DEFAULT_YIELD_WINDOW: u64 = 30 * DAY;
 
 pub fn config(deps: Deps<TerraQuery>) -> StdResult<ConfigResponse> {
     let state = State::default();
+    let validators = state.validators.load(deps.storage)?;
+    let weights = state.get_validator_weights(deps.storage, &validators)?;
     Ok(ConfigResponse {
         owner: state.owner.load(deps.storage)?.into(),
         new_owner: state.new_owner.may_load(deps.storage)?.map(|addr| addr.into()),
         stake_token: state.stake_token.load(deps.storage)?.into(),
         epoch_period: state.epoch_period.load(deps.storage)?,
         unbond_period: state.unbond_period.load(deps.storage)?,
-        validators: state.validators.load(deps.storage)?,
+        validator_weights: validators.iter().cloned().zip(weights).collect(),
+        validators,
         fee_config: state.fee_config.load(deps.storage)?,
         swap_config: state.swap_config.load(deps.storage)?,
+        reserve_config: state.get_reserve_config(deps.storage)?,
+        rebalance_config: state.get_rebalance_config(deps.storage)?,
+        history_config: state.history_config.may_load(deps.storage)?,
+        max_commission: state.get_max_commission(deps.storage)?,
     })
 }
 
@@ -37,10 +56,10 @@ pub fn state(deps: Deps<TerraQuery>, env: Env) -> StdResult<StateResponse> {
 
     let validators = state.validators.load(deps.storage)?;
     let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
-    let total_uluna: u128 = delegations.iter().map(|d| d.amount).sum();
+    let staked_uluna: u128 = delegations.iter().map(|d| d.amount).sum();
 
     // only not reconciled batches are relevant as they are still unbonding and estimated unbond time in the future.
-    let unbonding: u128 = state
+    let unbonding_batches = state
         .previous_batches
         .idx
         .reconciled
@@ -51,10 +70,19 @@ pub fn state(deps: Deps<TerraQuery>, env: Env) -> StdResult<StateResponse> {
             v
         })
         .filter(|item| item.est_unbond_end_time > env.block.time.seconds())
-        .map(|item| item.uluna_unclaimed.u128())
-        .sum();
+        .collect::<Vec<_>>();
+    let unbonding: u128 = unbonding_batches.iter().map(|item| item.uluna_unclaimed.u128()).sum();
+
+    // The share of the liquid reserve set aside for these batches is held by the contract already,
+    // but also counted as unbonding
+    let set_aside = unbonding_batches
+        .iter()
+        .map(|item| Ok(state.batch_reserves.may_load(deps.storage, item.id)?.unwrap_or_default()))
+        .sum::<StdResult<Uint128>>()?;
 
     let available = deps.querier.query_balance(&env.contract.address, "uluna")?.amount;
+    let reserve_uluna = state.get_liquid_reserve(deps.storage)?;
+    let total_uluna = Uint128::new(staked_uluna) + reserve_uluna;
 
     let exchange_rate = if total_ustake.is_zero() {
         Decimal::one()
@@ -64,14 +92,16 @@ pub fn state(deps: Deps<TerraQuery>, env: Env) -> StdResult<StateResponse> {
 
     Ok(StateResponse {
         total_ustake,
-        total_uluna: Uint128::new(total_uluna),
+        total_uluna,
         exchange_rate,
         unlocked_coins: state.unlocked_coins.load(deps.storage)?,
         unbonding: Uint128::from(unbonding),
-        available,
-        tvl_uluna: Uint128::from(total_uluna)
+        available: available.saturating_sub(set_aside),
+        tvl_uluna: Uint128::from(staked_uluna)
             .checked_add(Uint128::from(unbonding))?
-            .checked_add(available)?,
+            .checked_add(available.saturating_sub(set_aside))?,
+        reserve_uluna,
+        instant_unbond_fee: state.get_reserve_config(deps.storage)?.instant_unbond_fee,
     })
 }
 
@@ -229,6 +259,31 @@ pub fn unbond_requests_by_user_details(
         .collect()
 }
 
+pub fn referrals(
+    deps: Deps<TerraQuery>,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<Vec<ReferralResponseItem>> {
+    let state = State::default();
+
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start_after = start_after.map(|s| deps.api.addr_validate(&s)).transpose()?;
+    let start = start_after.as_ref().map(Bound::exclusive);
+
+    state
+        .referrals
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (referrer, uluna_bonded) = item?;
+            Ok(ReferralResponseItem {
+                referrer: referrer.into(),
+                uluna_bonded,
+            })
+        })
+        .collect()
+}
+
 pub fn query_exchange_rates(
     deps: Deps<TerraQuery>,
     _env: Env,
@@ -257,8 +312,225 @@ pub fn query_exchange_rates(
         None
     };
 
+    let exchange_rates = exchange_rates
+        .into_iter()
+        .map(|(time, exchange_rate)| exchange_rate_item(deps, &state, time, exchange_rate))
+        .collect::<StdResult<Vec<_>>>()?;
+
     Ok(ExchangeRatesResponse {
         exchange_rates,
         apr,
     })
 }
+
+pub fn query_exchange_rate_at(
+    deps: Deps<TerraQuery>,
+    time: u64,
+    interpolate: bool,
+) -> StdResult<ExchangeRateAtResponse> {
+    let state = State::default();
+
+    let (prior_time, prior_rate) = state
+        .exchange_history
+        .range(deps.storage, None, Some(Bound::inclusive(time)), Order::Descending)
+        .next()
+        .transpose()?
+        .ok_or_else(|| {
+            StdError::generic_err(format!("no exchange rate recorded at or before {}", time))
+        })?;
+
+    let next = if prior_time == time {
+        None
+    } else {
+        state
+            .exchange_history
+            .range(deps.storage, Some(Bound::exclusive(time)), None, Order::Ascending)
+            .next()
+            .transpose()?
+    };
+
+    let exchange_rate = match next {
+        Some((next_time, next_rate)) if interpolate => {
+            let elapsed = Decimal::from_ratio(time - prior_time, next_time - prior_time);
+            if next_rate >= prior_rate {
+                prior_rate + (next_rate - prior_rate) * elapsed
+            } else {
+                prior_rate - (prior_rate - next_rate) * elapsed
+            }
+        },
+        _ => prior_rate,
+    };
+
+    Ok(ExchangeRateAtResponse {
+        time,
+        exchange_rate,
+        prior: exchange_rate_item(deps, &state, prior_time, prior_rate)?,
+        next: next.map(|(time, rate)| exchange_rate_item(deps, &state, time, rate)).transpose()?,
+    })
+}
+
+fn exchange_rate_item(
+    deps: Deps<TerraQuery>,
+    state: &State,
+    time: u64,
+    exchange_rate: Decimal,
+) -> StdResult<ExchangeRateItem> {
+    Ok(ExchangeRateItem {
+        time,
+        exchange_rate,
+        resolution: state.get_history_resolution(deps.storage, time)?,
+        reason: state.get_exchange_rate_reason(deps.storage, time)?,
+    })
+}
+
+pub fn query_yield(
+    deps: Deps<TerraQuery>,
+    env: Env,
+    window_seconds: Option<u64>,
+) -> StdResult<YieldResponse> {
+    let state = State::default();
+    let window = window_seconds.unwrap_or(DEFAULT_YIELD_WINDOW).min(MAX_YIELD_WINDOW);
+    let window_start = env.block.time.seconds().saturating_sub(window);
+
+    let exchange_rates = state
+        .exchange_history
+        .range(deps.storage, Some(Bound::inclusive(window_start)), None, Order::Ascending)
+        .collect::<StdResult<Vec<(u64, Decimal)>>>()?;
+
+    // The exchange rate only grows by the rewards left after the protocol fee was taken
+    let protocol_reward_fee = state.fee_config.load(deps.storage)?.protocol_reward_fee;
+    let net = compute_daily_yield(&exchange_rates);
+    let gross = net.map(|daily| daily.div(Decimal::one() - protocol_reward_fee));
+
+    Ok(YieldResponse {
+        window_start,
+        data_points: exchange_rates.len() as u32,
+        net: net.map(yield_figures).transpose()?,
+        gross: gross.map(yield_figures).transpose()?,
+    })
+}
+
+fn yield_figures(daily: Decimal) -> StdResult<YieldFigures> {
+    Ok(YieldFigures {
+        daily,
+        weekly: daily * Decimal::from_ratio(7u128, 1u128),
+        apr: daily * Decimal::from_ratio(365u128, 1u128),
+        apy: (Decimal::one() + daily).checked_pow(365)? - Decimal::one(),
+    })
+}
+
+pub fn keeper_status(deps: Deps<TerraQuery>, env: Env) -> StdResult<KeeperStatusResponse> {
+    let state = State::default();
+    let current_time = env.block.time.seconds();
+
+    let validators = state.validators.load(deps.storage)?;
+    let mut harvest = false;
+    for validator in &validators {
+        let delegation = deps.querier.query_delegation(&env.contract.address, validator)?;
+        if delegation.is_some_and(|d| d.accumulated_rewards.iter().any(|c| !c.amount.is_zero())) {
+            harvest = true;
+            break;
+        }
+    }
+
+    let pending_batch = state.pending_batch.load(deps.storage)?;
+    let submit_batch = !pending_batch.ustake_to_burn.is_zero()
+        && current_time >= pending_batch.est_unbond_start_time;
+
+    let reconcile = state
+        .previous_batches
+        .idx
+        .reconciled
+        .prefix(false.into())
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (_, v) = item?;
+            Ok(v)
+        })
+        .collect::<StdResult<Vec<Batch>>>()?
+        .iter()
+        .any(|b| current_time > b.est_unbond_end_time);
+
+    Ok(KeeperStatusResponse {
+        harvest,
+        submit_batch,
+        reconcile,
+    })
+}
+
+pub fn simulate_bond(
+    deps: Deps<TerraQuery>,
+    env: Env,
+    uluna: Uint128,
+) -> StdResult<SimulateBondResponse> {
+    let state = State::default();
+    let stake_token = state.stake_token.load(deps.storage)?;
+    let validators = state.validators.load(deps.storage)?;
+    let weights = state.get_validator_weights(deps.storage, &validators)?;
+    let fee_config = state.fee_config.load(deps.storage)?;
+
+    let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
+    let offenders = find_commission_offenders(deps.storage, &deps.querier, &validators)?;
+    let (eligible_delegations, eligible_weights) =
+        exclude_commission_offenders(&delegations, &weights, &offenders);
+    let validator = find_validator_to_delegate(&eligible_delegations, &eligible_weights);
+
+    let reserve_config = state.get_reserve_config(deps.storage)?;
+    let uluna_reserve = state.get_liquid_reserve(deps.storage)?;
+    let uluna_reserved = compute_reserve_amount(uluna, uluna_reserve, &reserve_config)?;
+
+    let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
+    let ustake_to_mint = compute_mint_amount(ustake_supply, uluna, &delegations, uluna_reserve);
+    let ustake_deposit_fee = fee_config.deposit_fee.checked_mul_uint(ustake_to_mint)?;
+
+    Ok(SimulateBondResponse {
+        ustake_minted: ustake_to_mint.checked_sub(ustake_deposit_fee)?,
+        ustake_deposit_fee,
+        uluna_reserved,
+        validator: validator.to_string(),
+    })
+}
+
+pub fn simulate_unbond(
+    deps: Deps<TerraQuery>,
+    env: Env,
+    ustake: Uint128,
+) -> StdResult<SimulateUnbondResponse> {
+    let state = State::default();
+    let stake_token = state.stake_token.load(deps.storage)?;
+    let validators = state.validators.load(deps.storage)?;
+    let fee_config = state.fee_config.load(deps.storage)?;
+    let pending_batch = state.pending_batch.load(deps.storage)?;
+    let unbond_period = state.unbond_period.load(deps.storage)?;
+
+    let delegations = query_delegations(&deps.querier, &validators, &env.contract.address)?;
+    let uluna_reserve = state.get_liquid_reserve(deps.storage)?;
+    let ustake_supply = query_cw20_total_supply(&deps.querier, &stake_token)?;
+
+    let uluna_to_unbond = compute_unbond_amount(ustake_supply, ustake, &delegations, uluna_reserve);
+    let uluna_withdraw_fee = fee_config.withdraw_fee.checked_mul_uint(uluna_to_unbond)?;
+
+    // Once `est_unbond_start_time` has passed, queuing the request submits the batch immediately
+    let est_unbond_start_time = pending_batch.est_unbond_start_time.max(env.block.time.seconds());
+
+    Ok(SimulateUnbondResponse {
+        uluna_returned: uluna_to_unbond.checked_sub(uluna_withdraw_fee)?,
+        uluna_withdraw_fee,
+        batch_id: pending_batch.id,
+        est_unbond_start_time,
+        est_unbond_end_time: est_unbond_start_time + unbond_period,
+    })
+}
+
+pub fn validator_scores(deps: Deps<TerraQuery>) -> StdResult<Vec<(String, ValidatorScore)>> {
+    let state = State::default();
+    let validators = state.validators.load(deps.storage)?;
+
+    let mut scores = vec![];
+    for validator in validators {
+        if let Some(score) = state.validator_scores.may_load(deps.storage, &validator)? {
+            scores.push((validator, score));
+        }
+    }
+    Ok(scores)
+}
@@ -1,9 +1,13 @@
-use cosmwasm_std::{Addr, Coin, Decimal, StdError, StdResult, Storage};
-use cw_storage_plus::{Index, IndexList, IndexedMap, Item, Map, MultiIndex};
+use cosmwasm_std::{Addr, Coin, Decimal, Order, StdError, StdResult, Storage, Uint128};
+use cw_storage_plus::{Bound, Index, IndexList, IndexedMap, Item, Map, MultiIndex};
 
-use eris::hub::{Batch, FeeConfig, PendingBatch, SwapConfig, UnbondRequest};
+use eris::hub::{
+    Batch, ExchangeRateReason, FeeConfig, HistoryConfig, HistoryResolution, PendingBatch,
+    RebalanceConfig, ReserveConfig, SwapConfig, UnbondRequest, ValidatorScore,
+};
 
-use crate::types::BooleanKey;
+use crate::constants::{DAY, DEFAULT_VALIDATOR_WEIGHT, WEEK};
+use crate::types::{BooleanKey, Delegation, Redelegation, Undelegation};
 
 pub(crate) struct State<'a> {
     /// Account who can call certain privileged functions
@@ -18,6 +22,8 @@ pub(crate) struct State<'a> {
     pub unbond_period: Item<'a, u64>,
     /// Validators who will receive the delegations
     pub validators: Item<'a, Vec<String>>,
+    /// Weights of the validators, in basis points. Validators without an entry use the default weight
+    pub validator_weights: Map<'a, &'a str, u64>,
     /// Coins that can be reinvested
     pub unlocked_coins: Item<'a, Vec<Coin>>,
     /// The current batch of unbonding requests queded to be executed
@@ -32,6 +38,40 @@ pub(crate) struct State<'a> {
     pub swap_config: Item<'a, Vec<SwapConfig>>,
     // history of the exchange_rate
     pub exchange_history: Map<'a, u64, Decimal>,
+    /// Reason of the exchange rates in the history that were not recorded during harvest
+    pub exchange_history_reasons: Map<'a, u64, ExchangeRateReason>,
+    /// Retention policy of the exchange rate history
+    pub history_config: Item<'a, HistoryConfig>,
+    /// Exchange rates before this timestamp have been compacted to at most one per day
+    pub history_daily_until: Item<'a, u64>,
+    /// Exchange rates before this timestamp have been compacted to at most one per week
+    pub history_weekly_until: Item<'a, u64>,
+    /// Config of the liquid reserve
+    pub reserve_config: Item<'a, ReserveConfig>,
+    /// Amount of uluna held undelegated in the liquid reserve
+    pub liquid_reserve: Item<'a, Uint128>,
+    /// Last known amount of uluna delegated to each validator, used to detect slashing
+    pub last_delegations: Map<'a, &'a str, Uint128>,
+    /// Amount of uluna deducted from unbonding batches due to slashing, which is still expected to
+    /// be received when the batches finish unbonding
+    pub slash_recovery: Item<'a, Uint128>,
+    /// Amount of uluna deducted from each unbonding batch due to slashing, until it is reconciled
+    pub slash_deductions: Map<'a, u64, Uint128>,
+    /// Amount of uluna taken from the liquid reserve by each submitted batch not reconciled yet
+    pub batch_reserves: Map<'a, u64, Uint128>,
+    /// Total amount of uluna bonded through each referrer
+    pub referrals: Map<'a, &'a Addr, Uint128>,
+    /// Maximum commission of whitelisted validators, 1 if not configured
+    pub max_commission: Item<'a, Decimal>,
+    /// Scores of the whitelisted validators from their last evaluation
+    pub validator_scores: Map<'a, &'a str, ValidatorScore>,
+    /// Time since which the rewards of each validator have accumulated. The staking module
+    /// withdraws the rewards of a delegation whenever it changes
+    pub rewards_since: Map<'a, &'a str, u64>,
+    /// Completion times of the redelegations in progress, by source and destination validator
+    pub pending_redelegations: Map<'a, (&'a str, &'a str), Vec<u64>>,
+    /// Thresholds below which delegations are not rebalanced
+    pub rebalance_config: Item<'a, RebalanceConfig>,
 }
 
 impl Default for State<'static> {
@@ -57,6 +97,7 @@ impl Default for State<'static> {
             epoch_period: Item::new("epoch_period"),
             unbond_period: Item::new("unbond_period"),
             validators: Item::new("validators"),
+            validator_weights: Map::new("validator_weights"),
             unlocked_coins: Item::new("unlocked_coins"),
             pending_batch: Item::new("pending_batch"),
             previous_batches: IndexedMap::new("previous_batches", pb_indexes),
@@ -64,6 +105,22 @@ impl Default for State<'static> {
             fee_config: Item::new("fee_config"),
             swap_config: Item::new("swap_config"),
             exchange_history: Map::new("exchange_history"),
+            exchange_history_reasons: Map::new("exchange_history_reasons"),
+            history_config: Item::new("history_config"),
+            history_daily_until: Item::new("history_daily_until"),
+            history_weekly_until: Item::new("history_weekly_until"),
+            reserve_config: Item::new("reserve_config"),
+            liquid_reserve: Item::new("liquid_reserve"),
+            last_delegations: Map::new("last_delegations"),
+            slash_recovery: Item::new("slash_recovery"),
+            slash_deductions: Map::new("slash_deductions"),
+            batch_reserves: Map::new("batch_reserves"),
+            referrals: Map::new("referrals"),
+            max_commission: Item::new("max_commission"),
+            validator_scores: Map::new("validator_scores"),
+            rewards_since: Map::new("rewards_since"),
+            pending_redelegations: Map::new("pending_redelegations"),
+            rebalance_config: Item::new("rebalance_config"),
         }
     }
 }
@@ -77,6 +134,255 @@ impl<'a> State<'a> {
             Err(StdError::generic_err("unauthorized: sender is not owner"))
         }
     }
+
+    pub fn assert_stake_token(&self, storage: &dyn Storage, sender: &Addr) -> StdResult<()> {
+        let stake_token = self.stake_token.load(storage)?;
+        if *sender == stake_token {
+            Ok(())
+        } else {
+            Err(StdError::generic_err(format!("expecting Stake token, received {}", sender)))
+        }
+    }
+
+    /// Load the weight of each of the given validators, in the same order
+    pub fn get_validator_weights(
+        &self,
+        storage: &dyn Storage,
+        validators: &[String],
+    ) -> StdResult<Vec<u64>> {
+        validators
+            .iter()
+            .map(|validator| {
+                Ok(self
+                    .validator_weights
+                    .may_load(storage, validator)?
+                    .unwrap_or(DEFAULT_VALIDATOR_WEIGHT))
+            })
+            .collect()
+    }
+
+    pub fn get_reserve_config(&self, storage: &dyn Storage) -> StdResult<ReserveConfig> {
+        Ok(self.reserve_config.may_load(storage)?.unwrap_or_default())
+    }
+
+    /// Without a max commission configured, any commission is accepted
+    pub fn get_max_commission(&self, storage: &dyn Storage) -> StdResult<Decimal> {
+        Ok(self.max_commission.may_load(storage)?.unwrap_or_else(Decimal::one))
+    }
+
+    pub fn get_rebalance_config(&self, storage: &dyn Storage) -> StdResult<RebalanceConfig> {
+        Ok(self.rebalance_config.may_load(storage)?.unwrap_or_default())
+    }
+
+    pub fn get_liquid_reserve(&self, storage: &dyn Storage) -> StdResult<Uint128> {
+        Ok(self.liquid_reserve.may_load(storage)?.unwrap_or_default())
+    }
+
+    /// Track a new delegation in the last known delegations
+    pub fn record_delegation(
+        &self,
+        storage: &mut dyn Storage,
+        d: &Delegation,
+        now: u64,
+    ) -> StdResult<()> {
+        self.add_last_delegation(storage, &d.validator, d.amount, now)
+    }
+
+    /// Track new undelegations in the last known delegations
+    pub fn record_undelegations(
+        &self,
+        storage: &mut dyn Storage,
+        undelegations: &[Undelegation],
+        now: u64,
+    ) -> StdResult<()> {
+        for ud in undelegations {
+            self.sub_last_delegation(storage, &ud.validator, ud.amount, now)?;
+        }
+        Ok(())
+    }
+
+    /// Track new redelegations in the last known delegations, and as pending until they complete
+    /// after the unbonding period
+    pub fn record_redelegations(
+        &self,
+        storage: &mut dyn Storage,
+        redelegations: &[Redelegation],
+        now: u64,
+    ) -> StdResult<()> {
+        let completion_time = now + self.unbond_period.load(storage)?;
+        for rd in redelegations {
+            self.sub_last_delegation(storage, &rd.src, rd.amount, now)?;
+            self.add_last_delegation(storage, &rd.dst, rd.amount, now)?;
+            self.pending_redelegations.update(
+                storage,
+                (&rd.src, &rd.dst),
+                |x| -> StdResult<_> {
+                    let mut completion_times = x.unwrap_or_default();
+                    completion_times.retain(|time| *time > now);
+                    completion_times.push(completion_time);
+                    Ok(completion_times)
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Load the redelegations that have not completed at `now`, as source, destination and number
+    /// of entries. Completed redelegations are removed
+    pub fn load_pending_redelegations(
+        &self,
+        storage: &mut dyn Storage,
+        now: u64,
+    ) -> StdResult<Vec<(String, String, usize)>> {
+        let all = self
+            .pending_redelegations
+            .range(storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        let mut pending = vec![];
+        for ((src, dst), completion_times) in all {
+            let entries = completion_times.iter().filter(|time| **time > now).count();
+            if entries == 0 {
+                self.pending_redelegations.remove(storage, (&src, &dst));
+            } else {
+                pending.push((src, dst, entries));
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Add the exchange rate to the history. Except during harvest, which records the exchange rate
+    /// every time, nothing is recorded if the exchange rate has not changed since the last record.
+    pub fn record_exchange_rate(
+        &self,
+        storage: &mut dyn Storage,
+        time: u64,
+        exchange_rate: Decimal,
+        reason: ExchangeRateReason,
+    ) -> StdResult<()> {
+        if reason != ExchangeRateReason::Harvest {
+            let last = self.exchange_history.range(storage, None, None, Order::Descending).next();
+            if let Some((_, last_exchange_rate)) = last.transpose()? {
+                if last_exchange_rate == exchange_rate {
+                    return Ok(());
+                }
+            }
+        }
+
+        self.exchange_history.save(storage, time, &exchange_rate)?;
+        match reason {
+            ExchangeRateReason::Harvest => {
+                self.exchange_history_reasons.remove(storage, time);
+                Ok(())
+            },
+            reason => self.exchange_history_reasons.save(storage, time, &reason),
+        }
+    }
+
+    pub fn get_exchange_rate_reason(
+        &self,
+        storage: &dyn Storage,
+        time: u64,
+    ) -> StdResult<ExchangeRateReason> {
+        Ok(self.exchange_history_reasons.may_load(storage, time)?.unwrap_or_default())
+    }
+
+    /// Downsample the exchange rate history according to the retention policy, keeping the last
+    /// exchange rate of each day or week. Only the part of the history that may not have been
+    /// compacted yet is visited, so the cost does not grow with the length of the history.
+    pub fn compact_exchange_history(&self, storage: &mut dyn Storage, now: u64) -> StdResult<()> {
+        let config = match self.history_config.may_load(storage)? {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        // The boundaries never move backwards, even if the retention periods are increased
+        let prev_weekly_until = self.history_weekly_until.may_load(storage)?.unwrap_or_default();
+        let prev_daily_until = self.history_daily_until.may_load(storage)?.unwrap_or_default();
+        let weekly_until =
+            prev_weekly_until.max(now.saturating_sub(config.daily_resolution_period));
+        let daily_until = prev_daily_until
+            .max(now.saturating_sub(config.full_resolution_period))
+            .max(weekly_until);
+
+        // Start at the week of the previous boundary, as it may contain more than one exchange rate
+        let start = prev_weekly_until - prev_weekly_until % WEEK;
+        let times = self
+            .exchange_history
+            .keys(
+                storage,
+                Some(Bound::inclusive(start)),
+                Some(Bound::exclusive(daily_until)),
+                Order::Ascending,
+            )
+            .collect::<StdResult<Vec<_>>>()?;
+
+        let bucket = |time: u64| {
+            if time < weekly_until {
+                (HistoryResolution::Weekly, time / WEEK)
+            } else {
+                (HistoryResolution::Daily, time / DAY)
+            }
+        };
+
+        let mut prev: Option<u64> = None;
+        for time in times {
+            if let Some(prev) = prev.filter(|prev| bucket(*prev) == bucket(time)) {
+                self.exchange_history.remove(storage, prev);
+                self.exchange_history_reasons.remove(storage, prev);
+            }
+            prev = Some(time);
+        }
+
+        self.history_weekly_until.save(storage, &weekly_until)?;
+        self.history_daily_until.save(storage, &daily_until)
+    }
+
+    /// Resolution of the exchange rate history at the given timestamp
+    pub fn get_history_resolution(
+        &self,
+        storage: &dyn Storage,
+        time: u64,
+    ) -> StdResult<HistoryResolution> {
+        if time < self.history_weekly_until.may_load(storage)?.unwrap_or_default() {
+            Ok(HistoryResolution::Weekly)
+        } else if time < self.history_daily_until.may_load(storage)?.unwrap_or_default() {
+            Ok(HistoryResolution::Daily)
+        } else {
+            Ok(HistoryResolution::Full)
+        }
+    }
+
+    fn add_last_delegation(
+        &self,
+        storage: &mut dyn Storage,
+        validator: &str,
+        amount: u128,
+        now: u64,
+    ) -> StdResult<()> {
+        self.rewards_since.save(storage, validator, &now)?;
+        let last = self.last_delegations.may_load(storage, validator)?.unwrap_or_default();
+        self.last_delegations.save(storage, validator, &(last + Uint128::new(amount)))
+    }
+
+    // contracts migrated from a version without tracking start from zero, hence the saturating math
+    fn sub_last_delegation(
+        &self,
+        storage: &mut dyn Storage,
+        validator: &str,
+        amount: u128,
+        now: u64,
+    ) -> StdResult<()> {
+        self.rewards_since.save(storage, validator, &now)?;
+        let last = self.last_delegations.may_load(storage, validator)?.unwrap_or_default();
+        let remaining = last.saturating_sub(Uint128::new(amount));
+        if remaining.is_zero() {
+            self.last_delegations.remove(storage, validator);
+            Ok(())
+        } else {
+            self.last_delegations.save(storage, validator, &remaining)
+        }
+    }
 }
 
 pub(crate) struct PreviousBatchesIndexes<'a> {
@@ -3,23 +3,28 @@ use std::collections::HashMap;
 use classic_bindings::TerraQuery;
 use cosmwasm_std::testing::{BankQuerier, StakingQuerier, MOCK_CONTRACT_ADDR};
 use cosmwasm_std::{
-    from_binary, from_slice, Addr, Coin, FullDelegation, Querier, QuerierResult, QueryRequest,
-    SystemError, WasmQuery,
+    from_binary, from_slice, Addr, Coin, Decimal, FullDelegation, Querier, QuerierResult,
+    QueryRequest, SystemError, Uint128, Validator, WasmQuery,
 };
 use cw20::Cw20QueryMsg;
+use eris::asset::PairQueryMsg;
 
 use crate::types::Delegation;
 
 use super::cw20_querier::Cw20Querier;
 use super::helpers::err_unsupported_query;
+use super::pair_querier::PairQuerier;
 use super::terra_querier::TerraQuerier;
 
 #[derive(Default)]
 pub(super) struct CustomQuerier {
     pub cw20_querier: Cw20Querier,
+    pub pair_querier: PairQuerier,
     pub terra_querier: TerraQuerier,
     pub bank_querier: BankQuerier,
     pub staking_querier: StakingQuerier,
+    pub staking_validators: Vec<Validator>,
+    pub staking_delegations: Vec<FullDelegation>,
 }
 
 impl Querier for CustomQuerier {
@@ -57,24 +62,33 @@ impl CustomQuerier {
         self.cw20_querier.total_supplies.insert(token.to_string(), total_supply);
     }
 
-    // Only needed for market swaps
-    // pub fn set_terra_exchange_rate(
-    //     &mut self,
-    //     base_denom: &str,
-    //     quote_denom: &str,
-    //     exchange_rate: Decimal,
-    // ) {
-    //     self.terra_querier
-    //         .exchange_rates
-    //         .insert((base_denom.to_string(), quote_denom.to_string()), exchange_rate);
-    // }
+    pub fn set_terra_exchange_rate(
+        &mut self,
+        base_denom: &str,
+        quote_denom: &str,
+        exchange_rate: Decimal,
+    ) {
+        self.terra_querier
+            .exchange_rates
+            .insert((base_denom.to_string(), quote_denom.to_string()), exchange_rate);
+    }
+
+    pub fn set_terra_swap_rate(&mut self, offer_denom: &str, ask_denom: &str, rate: Decimal) {
+        self.terra_querier
+            .swap_rates
+            .insert((offer_denom.to_string(), ask_denom.to_string()), rate);
+    }
+
+    pub fn set_pair_rate(&mut self, pair: &str, rate: Decimal) {
+        self.pair_querier.rates.insert(pair.to_string(), rate);
+    }
 
     pub fn set_bank_balances(&mut self, balances: &[Coin]) {
         self.bank_querier = BankQuerier::new(&[(MOCK_CONTRACT_ADDR, balances)])
     }
 
     pub fn set_staking_delegations(&mut self, delegations: &[Delegation]) {
-        let fds = delegations
+        self.staking_delegations = delegations
             .iter()
             .map(|d| FullDelegation {
                 delegator: Addr::unchecked(MOCK_CONTRACT_ADDR),
@@ -85,7 +99,56 @@ impl CustomQuerier {
             })
             .collect::<Vec<_>>();
 
-        self.staking_querier = StakingQuerier::new("uluna", &[], &fds);
+        self.update_staking_querier();
+    }
+
+    /// Add a delegation executed by the contract on top of the current delegations
+    pub fn add_staking_delegation(&mut self, delegation: &Delegation) {
+        match self.staking_delegations.iter_mut().find(|fd| fd.validator == delegation.validator) {
+            Some(fd) => fd.amount.amount += Uint128::new(delegation.amount),
+            None => self.staking_delegations.push(FullDelegation {
+                delegator: Addr::unchecked(MOCK_CONTRACT_ADDR),
+                validator: delegation.validator.clone(),
+                amount: Coin::new(delegation.amount, "uluna"),
+                can_redelegate: Coin::new(0, "uluna"),
+                accumulated_rewards: vec![],
+            }),
+        }
+
+        self.update_staking_querier();
+    }
+
+    /// Set the validators in the active set, given their commission
+    pub fn set_staking_validators(&mut self, validators: &[(&str, Decimal)]) {
+        self.staking_validators = validators
+            .iter()
+            .map(|(address, commission)| Validator {
+                address: address.to_string(),
+                commission: *commission,
+                max_commission: Decimal::one(),
+                max_change_rate: Decimal::one(),
+            })
+            .collect();
+
+        self.update_staking_querier();
+    }
+
+    /// Set the uluna rewards accumulated by the delegations
+    pub fn set_staking_rewards(&mut self, rewards: &[(&str, u128)]) {
+        for (validator, amount) in rewards {
+            if let Some(fd) =
+                self.staking_delegations.iter_mut().find(|fd| fd.validator == *validator)
+            {
+                fd.accumulated_rewards = vec![Coin::new(*amount, "uluna")];
+            }
+        }
+
+        self.update_staking_querier();
+    }
+
+    fn update_staking_querier(&mut self) {
+        self.staking_querier =
+            StakingQuerier::new("uluna", &self.staking_validators, &self.staking_delegations);
     }
 
     pub fn handle_query(&self, request: &QueryRequest<TerraQuery>) -> QuerierResult {
@@ -98,6 +161,10 @@ impl CustomQuerier {
                     return self.cw20_querier.handle_query(contract_addr, query);
                 }
 
+                if let Ok(query) = from_binary::<PairQueryMsg>(msg) {
+                    return self.pair_querier.handle_query(contract_addr, query);
+                }
+
                 err_unsupported_query(msg)
             },
 
@@ -1,5 +1,6 @@
 mod custom_querier;
 mod cw20_querier;
 mod helpers;
+mod pair_querier;
 mod terra_querier;
 mod tests;
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+use cosmwasm_std::{to_binary, Decimal, QuerierResult, SystemError, Uint128};
+use eris::asset::{PairQueryMsg, SimulationResponse};
+
+#[derive(Default)]
+pub(super) struct PairQuerier {
+    /// Mapping pair address to the amount of ask asset returned per unit of offer asset
+    pub rates: HashMap<String, Decimal>,
+}
+
+impl PairQuerier {
+    pub fn handle_query(&self, contract_addr: &str, query: PairQueryMsg) -> QuerierResult {
+        match &query {
+            PairQueryMsg::Simulation {
+                offer_asset,
+            } => {
+                let rate = self
+                    .rates
+                    .get(contract_addr)
+                    .ok_or_else(|| SystemError::InvalidRequest {
+                        error: format!("[mock] rate not set for pair `{}`", contract_addr),
+                        request: Default::default(),
+                    })
+                    .unwrap();
+
+                Ok(to_binary(&SimulationResponse {
+                    return_amount: offer_asset.amount * *rate,
+                    spread_amount: Uint128::zero(),
+                    commission_amount: Uint128::zero(),
+                })
+                .into())
+                .into()
+            },
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use std::{collections::HashMap, str::FromStr};
 
-use classic_bindings::{TaxCapResponse, TaxRateResponse, TerraQuery};
-use cosmwasm_std::{to_binary, Decimal, QuerierResult, Uint128};
+use classic_bindings::{
+    ExchangeRateItem, ExchangeRatesResponse, SwapResponse, TaxCapResponse, TaxRateResponse,
+    TerraQuery,
+};
+use cosmwasm_std::{to_binary, Coin, Decimal, QuerierResult, SystemError, SystemResult, Uint128};
 
 use super::helpers::err_unsupported_query;
 
@@ -9,10 +12,13 @@ use super::helpers::err_unsupported_query;
 pub struct TerraQuerier {
     /// Maps (base_denom, quote_denom) pair to exchange rate
     pub exchange_rates: HashMap<(String, String), Decimal>,
+    /// Maps (offer_denom, ask_denom) pair to the amount of ask returned per unit offered by the
+    /// market module
+    pub swap_rates: HashMap<(String, String), Decimal>,
 }
 
 impl TerraQuerier {
-    /// We only implement the `exchange_rates` query as that is the only one we need in the unit tests
+    /// We implement the `exchange_rates` and `swap` queries, along with the tax queries
     ///
     /// NOTE: When querying exchange rates, Terra's oracle module behaves in the following way:
     /// - If `quote_denoms` contains _at least one_ known denom (meaning a denom that has exchange
@@ -28,37 +34,59 @@ impl TerraQuerier {
     ///
     /// We emulate this behaviour in our mock querier.
     pub fn handle_query(&self, query: &TerraQuery) -> QuerierResult {
-        // if let TerraQueryWrapper::ExchangeRates {
-        //     base_denom,
-        //     quote_denoms,
-        // } = query
-        // {
-        //     let exchange_rates: Vec<ExchangeRateItem> = quote_denoms
-        //         .iter()
-        //         .filter_map(|quote_denom| {
-        //             self.exchange_rates.get(&(base_denom.clone(), quote_denom.clone())).map(
-        //                 |rate| ExchangeRateItem {
-        //                     quote_denom: quote_denom.clone(),
-        //                     exchange_rate: *rate,
-        //                 },
-        //             )
-        //         })
-        //         .collect();
+        if let TerraQuery::ExchangeRates {
+            base_denom,
+            quote_denoms,
+        } = query
+        {
+            let exchange_rates: Vec<ExchangeRateItem> = quote_denoms
+                .iter()
+                .filter_map(|quote_denom| {
+                    self.exchange_rates.get(&(base_denom.clone(), quote_denom.clone())).map(
+                        |rate| ExchangeRateItem {
+                            quote_denom: quote_denom.clone(),
+                            exchange_rate: *rate,
+                        },
+                    )
+                })
+                .collect();
+
+            if exchange_rates.is_empty() {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: "[mock] quote_denoms are all unknown".to_string(),
+                    request: Default::default(),
+                });
+            }
+
+            return Ok(to_binary(&ExchangeRatesResponse {
+                base_denom: base_denom.into(),
+                exchange_rates,
+            })
+            .into())
+            .into();
+        }
 
-        //     if exchange_rates.is_empty() {
-        //         return SystemResult::Err(SystemError::InvalidRequest {
-        //             error: "[mock] quote_denoms are all unknown".to_string(),
-        //             request: Default::default(),
-        //         });
-        //     }
+        if let TerraQuery::Swap {
+            offer_coin,
+            ask_denom,
+        } = query
+        {
+            let rate = match self.swap_rates.get(&(offer_coin.denom.clone(), ask_denom.clone())) {
+                Some(rate) => rate,
+                None => {
+                    return SystemResult::Err(SystemError::InvalidRequest {
+                        error: "[mock] swap rate not set".to_string(),
+                        request: Default::default(),
+                    })
+                },
+            };
 
-        //     return Ok(to_binary(&ExchangeRatesResponse {
-        //         base_denom: base_denom.into(),
-        //         exchange_rates,
-        //     })
-        //     .into())
-        //     .into();
-        // }
+            return Ok(to_binary(&SwapResponse {
+                receive: Coin::new((offer_coin.amount * *rate).u128(), ask_denom),
+            })
+            .into())
+            .into();
+        }
 
         if let TerraQuery::TaxCap {
             denom: _,
@@ -3,30 +3,35 @@ use std::vec;
 
 use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage, MOCK_CONTRACT_ADDR};
 use cosmwasm_std::{
-    coin, from_slice, to_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Decimal, DistributionMsg,
-    Event, Order, OwnedDeps, Reply, ReplyOn, StdError, StdResult, SubMsg, SubMsgResponse, Uint128,
-    WasmMsg,
+    attr, coin, from_slice, to_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Decimal, DepsMut,
+    DistributionMsg, Event, Order, OwnedDeps, Reply, ReplyOn, StdError, StdResult, SubMsg,
+    SubMsgResponse, SubMsgResult, Uint128, WasmMsg,
 };
 use cw20::{Cw20ExecuteMsg, MinterResponse};
 use cw20_base::msg::InstantiateMsg as Cw20InstantiateMsg;
 use eris::DecimalCheckedOps;
 
-use classic_bindings::TerraQuery;
+use classic_bindings::{TerraMsg, TerraQuery};
 use eris::asset::{Asset, AssetInfo, PairExecuteMsg};
 use eris::hub::{
-    Batch, CallbackMsg, ConfigResponse, ExecuteMsg, FeeConfig, InstantiateMsg, PendingBatch,
-    QueryMsg, ReceiveMsg, StateResponse, SwapConfig, UnbondRequest,
-    UnbondRequestsByBatchResponseItem, UnbondRequestsByUserResponseItem,
-    UnbondRequestsByUserResponseItemDetails,
+    Batch, CallbackMsg, ConfigResponse, ExchangeRateAtResponse, ExchangeRateItem,
+    ExchangeRateReason, ExchangeRatesResponse, ExecuteMsg, FeeConfig, HistoryConfig,
+    HistoryResolution, InstantiateMsg, KeeperReward, KeeperStatusResponse, PendingBatch,
+    PriceSource, QueryMsg, RebalanceConfig, ReceiveMsg, ReferralResponseItem, ReserveConfig,
+    SimulateBondResponse, SimulateUnbondResponse, StateResponse, SwapConfig, SwapHop, SwapKind,
+    UnbondRequest, UnbondRequestsByBatchResponseItem, UnbondRequestsByUserResponseItem,
+    UnbondRequestsByUserResponseItemDetails, UpdateConfigMsg, ValidatorScore, YieldFigures,
+    YieldResponse,
 };
 
 use serde::de::DeserializeOwned;
 
-use crate::constants::CONTRACT_DENOM;
-use crate::contract::{execute, instantiate, reply};
+use crate::constants::{CONTRACT_DENOM, DAY};
+use crate::contract::{execute, instantiate, query, reply};
 use crate::helpers::{check_swap_config, dedupe, parse_coin, parse_received_fund};
 use crate::math::{
-    compute_redelegations_for_rebalancing, compute_redelegations_for_removal, compute_undelegations,
+    compute_redelegations_for_rebalancing, compute_redelegations_for_removal,
+    compute_target_delegations, compute_undelegations, find_validator_to_delegate,
 };
 use crate::state::State;
 use crate::testing::helpers::query_helper_env;
@@ -62,6 +67,11 @@ fn setup_test() -> OwnedDeps<MockStorage, MockApi, CustomQuerier, TerraQuery> {
             swap_config: vec![SwapConfig {
                 denom: "uusd".to_string(),
                 contract: Addr::unchecked("uusd_uluna"),
+                max_spread: None,
+                hops: vec![],
+                min_return: None,
+                price_source: None,
+                kind: SwapKind::Pair,
             }],
         },
     )
@@ -118,6 +128,23 @@ fn setup_test() -> OwnedDeps<MockStorage, MockApi, CustomQuerier, TerraQuery> {
     deps
 }
 
+/// Take the mock delegations as the last known ones, as if the delegation changes made so far had
+/// gone through without a loss, so they are not detected as slashing
+fn sync_last_delegations(deps: &mut OwnedDeps<MockStorage, MockApi, CustomQuerier, TerraQuery>) {
+    let state = State::default();
+    let validators = state.validators.load(deps.as_ref().storage).unwrap();
+    for validator in &validators {
+        let amount = deps
+            .querier
+            .staking_delegations
+            .iter()
+            .find(|d| d.validator == *validator)
+            .map(|d| d.amount.amount)
+            .unwrap_or_default();
+        state.last_delegations.save(deps.as_mut().storage, validator, &amount).unwrap();
+    }
+}
+
 //--------------------------------------------------------------------------------------------------
 // Execution
 //--------------------------------------------------------------------------------------------------
@@ -136,14 +163,32 @@ fn proper_instantiation() {
             epoch_period: 259200,
             unbond_period: 1814400,
             validators: vec!["alice".to_string(), "bob".to_string(), "charlie".to_string()],
+            validator_weights: vec![
+                ("alice".to_string(), 10000),
+                ("bob".to_string(), 10000),
+                ("charlie".to_string(), 10000)
+            ],
             fee_config: FeeConfig {
                 protocol_fee_contract: Addr::unchecked("fee"),
-                protocol_reward_fee: Decimal::from_ratio(1u128, 100u128)
+                protocol_reward_fee: Decimal::from_ratio(1u128, 100u128),
+                keeper_reward: None,
+                fee_recipients: vec![],
+                deposit_fee: Decimal::zero(),
+                withdraw_fee: Decimal::zero(),
             },
             swap_config: vec![SwapConfig {
                 denom: "uusd".to_string(),
                 contract: Addr::unchecked("uusd_uluna"),
+                max_spread: None,
+                hops: vec![],
+                min_return: None,
+                price_source: None,
+                kind: SwapKind::Pair,
             }],
+            reserve_config: ReserveConfig::default(),
+            rebalance_config: RebalanceConfig::default(),
+            history_config: None,
+            max_commission: Decimal::one(),
         }
     );
 
@@ -158,6 +203,8 @@ fn proper_instantiation() {
             unbonding: Uint128::zero(),
             available: Uint128::zero(),
             tvl_uluna: Uint128::zero(),
+            reserve_uluna: Uint128::zero(),
+            instant_unbond_fee: Decimal::zero(),
         },
     );
 
@@ -186,6 +233,8 @@ fn bonding() {
         mock_info("user_1", &[Coin::new(1000000, CONTRACT_DENOM)]),
         ExecuteMsg::Bond {
             receiver: None,
+            referral: None,
+            min_ustake_out: None,
         },
     )
     .unwrap();
@@ -225,6 +274,8 @@ fn bonding() {
         mock_info("user_2", &[Coin::new(12345, CONTRACT_DENOM)]),
         ExecuteMsg::Bond {
             receiver: Some("user_3".to_string()),
+            referral: None,
+            min_ustake_out: None,
         },
     )
     .unwrap();
@@ -265,6 +316,8 @@ fn bonding() {
             unbonding: Uint128::zero(),
             available: Uint128::new(12567),
             tvl_uluna: Uint128::new(1037345 + 12567),
+            reserve_uluna: Uint128::zero(),
+            instant_unbond_fee: Decimal::zero(),
         }
     );
 }
@@ -282,6 +335,8 @@ fn donating() {
         mock_info("user_1", &[Coin::new(1000000, CONTRACT_DENOM)]),
         ExecuteMsg::Bond {
             receiver: None,
+            referral: None,
+            min_ustake_out: None,
         },
     )
     .unwrap();
@@ -323,6 +378,8 @@ fn donating() {
             unbonding: Uint128::zero(),
             available: Uint128::new(100),
             tvl_uluna: Uint128::new(1025100),
+            reserve_uluna: Uint128::zero(),
+            instant_unbond_fee: Decimal::zero(),
         }
     );
 
@@ -361,15 +418,17 @@ fn donating() {
             unbonding: Uint128::zero(),
             available: Uint128::new(100),
             tvl_uluna: Uint128::new(1037345 + 100),
+            reserve_uluna: Uint128::zero(),
+            instant_unbond_fee: Decimal::zero(),
         }
     );
 }
 
 #[test]
-fn harvesting() {
+fn recording_exchange_rate_changes() {
     let mut deps = setup_test();
+    let state = State::default();
 
-    // Assume users have bonded a total of 1,000,000 utoken and minted the same amount of ustake
     deps.querier.set_staking_delegations(&[
         Delegation::new("alice", 341667),
         Delegation::new("bob", 341667),
@@ -377,1012 +436,4464 @@ fn harvesting() {
     ]);
     deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
 
-    let res = execute(
+    // Donations increase the exchange rate: 1,037,345 / 1,000,000
+    deps.querier.set_bank_balances(&[coin(12345, CONTRACT_DENOM)]);
+    execute(
         deps.as_mut(),
-        mock_env(),
-        mock_info(MOCK_CONTRACT_ADDR, &[]),
-        ExecuteMsg::Harvest {},
+        mock_env_at_timestamp(1000),
+        mock_info("user_1", &[Coin::new(12345, CONTRACT_DENOM)]),
+        ExecuteMsg::Donate {},
     )
     .unwrap();
 
-    assert_eq!(res.messages.len(), 6);
-    assert_eq!(
-        res.messages[0],
-        SubMsg::new(CosmosMsg::Distribution(DistributionMsg::WithdrawDelegatorReward {
-            validator: "alice".to_string(),
-        }))
-    );
-    assert_eq!(
-        res.messages[1],
-        SubMsg::new(CosmosMsg::Distribution(DistributionMsg::WithdrawDelegatorReward {
-            validator: "bob".to_string(),
-        }))
-    );
-    assert_eq!(
-        res.messages[2],
-        SubMsg::new(CosmosMsg::Distribution(DistributionMsg::WithdrawDelegatorReward {
-            validator: "charlie".to_string(),
-        }))
-    );
-    assert_eq!(res.messages[3], check_received_coin(0));
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 354011),
+    ]);
 
-    assert_eq!(
-        res.messages[4],
-        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: MOCK_CONTRACT_ADDR.to_string(),
-            msg: to_binary(&ExecuteMsg::Callback(CallbackMsg::Swap {})).unwrap(),
-            funds: vec![]
-        }))
+    for id in 1..=2u64 {
+        state
+            .previous_batches
+            .save(
+                deps.as_mut().storage,
+                id,
+                &Batch {
+                    id,
+                    reconciled: false,
+                    total_shares: Uint128::new(1000),
+                    uluna_unclaimed: Uint128::new(1000),
+                    est_unbond_end_time: 1500,
+                },
+            )
+            .unwrap();
+    }
+    deps.querier.set_bank_balances(&[coin(2000, CONTRACT_DENOM)]);
+
+    // Reconciling without a change of the exchange rate records nothing
+    state.previous_batches.remove(deps.as_mut().storage, 2u64).unwrap();
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(2000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+
+    // Charlie has been slashed, which is recorded during the next reconciliation:
+    // 1,033,334 / 1,000,000
+    state
+        .previous_batches
+        .save(
+            deps.as_mut().storage,
+            2u64,
+            &Batch {
+                id: 2,
+                reconciled: false,
+                total_shares: Uint128::new(1000),
+                uluna_unclaimed: Uint128::new(1000),
+                est_unbond_end_time: 1500,
+            },
+        )
+        .unwrap();
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 350000),
+    ]);
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(3000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+
+    // Unbonding 1,000 ustake rounds down the unbonded uluna: 1,033,334 * 1,000 / 1,000,000 = 1,033
+    // New exchange rate: 1,032,301 / 999,000
+    state
+        .pending_batch
+        .save(
+            deps.as_mut().storage,
+            &PendingBatch {
+                id: 3,
+                ustake_to_burn: Uint128::new(1000),
+                est_unbond_start_time: 4000,
+            },
+        )
+        .unwrap();
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(4000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::SubmitBatch {},
+    )
+    .unwrap();
+
+    let res: ExchangeRatesResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::ExchangeRates {
+            start_after: None,
+            limit: None,
+        },
     );
     assert_eq!(
-        res.messages[5],
-        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: MOCK_CONTRACT_ADDR.to_string(),
-            msg: to_binary(&ExecuteMsg::Callback(CallbackMsg::Reinvest {})).unwrap(),
-            funds: vec![]
-        }))
+        res.exchange_rates
+            .into_iter()
+            .map(|item| (item.time, item.exchange_rate, item.reason))
+            .collect::<Vec<_>>(),
+        vec![
+            (4000, Decimal::from_ratio(1032301u128, 999000u128), ExchangeRateReason::SubmitBatch),
+            (3000, Decimal::from_ratio(1033334u128, 1000000u128), ExchangeRateReason::Reconcile),
+            (1000, Decimal::from_ratio(1037345u128, 1000000u128), ExchangeRateReason::Donate),
+        ]
     );
 }
 
 #[test]
-fn registering_unlocked_coins() {
+fn charging_deposit_and_withdraw_fees() {
     let mut deps = setup_test();
-
     let state = State::default();
 
-    deps.querier.set_bank_balances(&[coin(100 + 123, CONTRACT_DENOM)]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            deposit_fee: Some(Decimal::percent(6)),
+            ..Default::default()
+        })),
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("'deposit_fee' greater than max"));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            withdraw_fee: Some(Decimal::percent(6)),
+            ..Default::default()
+        })),
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("'withdraw_fee' greater than max"));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            deposit_fee: Some(Decimal::percent(1)),
+            withdraw_fee: Some(Decimal::percent(2)),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
 
+    // 1% of the minted Stake tokens go to the fee contract
+    deps.querier.set_bank_balances(&[coin(1000100, CONTRACT_DENOM)]);
     let res = execute(
         deps.as_mut(),
         mock_env(),
-        mock_info(MOCK_CONTRACT_ADDR, &[]),
-        ExecuteMsg::Callback(CallbackMsg::CheckReceivedCoin {
-            snapshot: coin(100, CONTRACT_DENOM),
-        }),
+        mock_info("user_1", &[Coin::new(1000000, CONTRACT_DENOM)]),
+        ExecuteMsg::Bond {
+            receiver: None,
+            referral: None,
+            min_ustake_out: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 4);
+    for (i, (recipient, amount)) in [("user_1", 990000u128), ("fee", 10000)].iter().enumerate() {
+        assert_eq!(
+            res.messages[i + 1].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: STAKE_DENOM.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Mint {
+                    recipient: recipient.to_string(),
+                    amount: Uint128::new(*amount)
+                })
+                .unwrap(),
+                funds: vec![]
+            })
+        );
+    }
+    assert_eq!(res.messages[3], check_received_coin(100));
+    assert_eq!(res.events[0].attributes.last().unwrap(), attr("ustake_deposit_fee", "10000"));
+
+    // 2% of the withdrawn Luna go to the fee contract: 10,200 * 0.02 = 204
+    state
+        .unbond_requests
+        .save(
+            deps.as_mut().storage,
+            (1u64, &Addr::unchecked("user_1")),
+            &UnbondRequest {
+                id: 1,
+                user: Addr::unchecked("user_1"),
+                shares: Uint128::new(10000),
+            },
+        )
+        .unwrap();
+    state
+        .previous_batches
+        .save(
+            deps.as_mut().storage,
+            1u64,
+            &Batch {
+                id: 1,
+                reconciled: true,
+                total_shares: Uint128::new(10000),
+                uluna_unclaimed: Uint128::new(10200),
+                est_unbond_end_time: 10000,
+            },
+        )
+        .unwrap();
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20000),
+        mock_info("user_1", &[]),
+        ExecuteMsg::WithdrawUnbonded {
+            receiver: None,
+            batch_ids: None,
+            limit: None,
+        },
     )
     .unwrap();
 
+    // Both transfers are subject to the tax
+    assert_eq!(res.messages.len(), 2);
     assert_eq!(
-        res.events,
-        vec![Event::new("erishub/received")
-            .add_attribute("received_coin", 123.to_string() + CONTRACT_DENOM)]
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "user_1".to_string(),
+            amount: vec![Coin::new(9897, "uluna")]
+        })
     );
-
-    // Unlocked coins in contract state should have been updated
-    let unlocked_coins = state.unlocked_coins.load(deps.as_ref().storage).unwrap();
-    assert_eq!(unlocked_coins, vec![Coin::new(123, CONTRACT_DENOM),]);
+    assert_eq!(
+        res.messages[1].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "fee".to_string(),
+            amount: vec![Coin::new(201, "uluna")]
+        })
+    );
+    assert_eq!(res.events[0].attributes.last().unwrap(), attr("uluna_withdraw_fee", "204"));
 }
 
 #[test]
-fn swapping() {
+fn bonding_with_referral() {
     let mut deps = setup_test();
 
-    deps.querier.set_bank_balances(&[
-        coin(123, "ukrw"),
-        coin(234, "uluna"),
-        coin(345, "uusd"),
-        coin(69420, "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B"),
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
     ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
 
-    let res = execute(
+    for (user, amount, referral) in [
+        ("user_1", 12345u128, "frontend_1"),
+        ("user_2", 10000, "frontend_2"),
+        ("user_3", 5000, "frontend_1"),
+    ]
+    .iter()
+    {
+        deps.querier.set_bank_balances(&[coin(*amount, CONTRACT_DENOM)]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(user, &[Coin::new(*amount, CONTRACT_DENOM)]),
+            ExecuteMsg::Bond {
+                receiver: None,
+                referral: Some(referral.to_string()),
+                min_ustake_out: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(res.events[0].attributes.last().unwrap(), attr("referral", *referral));
+
+        // The deposit goes to the smallest delegation: Charlie, then Alice, then Bob
+        let validator = match *user {
+            "user_1" => "charlie",
+            "user_2" => "alice",
+            _ => "bob",
+        };
+        assert_eq!(res.messages[0].msg, Delegation::new(validator, *amount).to_cosmos_msg());
+        deps.querier.add_staking_delegation(&Delegation::new(validator, *amount));
+    }
+
+    let res: Vec<ReferralResponseItem> = query_helper(
+        deps.as_ref(),
+        QueryMsg::Referrals {
+            start_after: None,
+            limit: None,
+        },
+    );
+    assert_eq!(
+        res,
+        vec![
+            ReferralResponseItem {
+                referrer: "frontend_1".to_string(),
+                uluna_bonded: Uint128::new(17345),
+            },
+            ReferralResponseItem {
+                referrer: "frontend_2".to_string(),
+                uluna_bonded: Uint128::new(10000),
+            },
+        ]
+    );
+
+    let res: Vec<ReferralResponseItem> = query_helper(
+        deps.as_ref(),
+        QueryMsg::Referrals {
+            start_after: Some("frontend_1".to_string()),
+            limit: Some(1),
+        },
+    );
+    assert_eq!(
+        res,
+        vec![ReferralResponseItem {
+            referrer: "frontend_2".to_string(),
+            uluna_bonded: Uint128::new(10000),
+        }]
+    );
+}
+
+#[test]
+fn bonding_with_swap() {
+    let mut deps = setup_test();
+
+    execute(
         deps.as_mut(),
         mock_env(),
-        mock_info(MOCK_CONTRACT_ADDR, &[]),
-        ExecuteMsg::Callback(CallbackMsg::Swap {}),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            swap_config: Some(vec![
+                SwapConfig {
+                    denom: "uusd".to_string(),
+                    contract: Addr::unchecked("uusd_uluna"),
+                    max_spread: Some(Decimal::percent(2)),
+                    hops: vec![],
+                    min_return: Some(Decimal::percent(5)),
+                    price_source: None,
+                    kind: SwapKind::Pair,
+                },
+                SwapConfig {
+                    denom: "ukrw".to_string(),
+                    contract: Addr::unchecked("ukrw_uusd"),
+                    max_spread: Some(Decimal::percent(5)),
+                    hops: vec![SwapHop {
+                        denom: "uusd".to_string(),
+                        contract: Addr::unchecked("uusd_uluna"),
+                        max_spread: Some(Decimal::percent(2)),
+                    }],
+                    min_return: None,
+                    price_source: None,
+                    kind: SwapKind::Pair,
+                },
+                SwapConfig {
+                    denom: "token".to_string(),
+                    contract: Addr::unchecked("token_uluna"),
+                    max_spread: Some(Decimal::percent(1)),
+                    hops: vec![],
+                    min_return: Some(Decimal::percent(10)),
+                    price_source: None,
+                    kind: SwapKind::Pair,
+                },
+            ]),
+            ..Default::default()
+        })),
     )
     .unwrap();
 
-    assert_eq!(res.messages.len(), 1);
+    deps.querier.set_bank_balances(&[coin(234, "uluna"), coin(10000, "uusd")]);
 
-    // 345 - TAX of 1 %
-    let amount = Uint128::new(345) - Uint128::new(4);
+    // The deposit is swapped, then the uluna received are bonded in a callback. 10000 uusd minus
+    // the tax of 100 is offered
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[coin(10000, "uusd")]),
+        ExecuteMsg::Bond {
+            receiver: Some("user_2".to_string()),
+            referral: Some("frontend".to_string()),
+            min_ustake_out: None,
+        },
+    )
+    .unwrap();
 
+    assert_eq!(res.messages.len(), 2);
     assert_eq!(
         res.messages[0].msg,
         CosmosMsg::Wasm(WasmMsg::Execute {
             contract_addr: "uusd_uluna".to_string(),
-            funds: vec![Coin {
-                denom: "uusd".to_string(),
-                amount,
-            }],
+            funds: vec![coin(9900, "uusd")],
             msg: to_binary(&PairExecuteMsg::Swap {
                 offer_asset: Asset {
-                    amount,
-                    info: eris::asset::AssetInfo::NativeToken {
+                    amount: Uint128::new(9900),
+                    info: AssetInfo::NativeToken {
                         denom: "uusd".to_string()
                     }
                 },
                 belief_price: None,
-                max_spread: None,
+                max_spread: Some(Decimal::percent(2)),
                 to: None,
             })
             .unwrap(),
         }),
     );
-}
-
-#[test]
-fn reinvesting() {
-    let mut deps = setup_test();
-    let state = State::default();
-
-    deps.querier.set_staking_delegations(&[
-        Delegation::new("alice", 333334),
-        Delegation::new("bob", 333333),
-        Delegation::new("charlie", 333333),
-    ]);
-    deps.querier.set_cw20_total_supply("stake_token", 333333 + 333333 + 333334);
-
-    // After the swaps, `unlocked_coins` should contain only uluna and unknown denoms
-    state
-        .unlocked_coins
-        .save(
-            deps.as_mut().storage,
-            &vec![
-                Coin::new(234, "uluna"),
-                Coin::new(
-                    69420,
-                    "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B",
-                ),
-            ],
-        )
-        .unwrap();
+    assert_eq!(
+        res.messages[1].msg,
+        CallbackMsg::BondSwapped {
+            receiver: Addr::unchecked("user_2"),
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(500),
+            referral: Some(Addr::unchecked("frontend")),
+            min_ustake_out: None,
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    );
 
-    // Bob has the smallest amount of delegations, so all proceeds go to him
+    // CW20 tokens are sent to the pair
     let res = execute(
         deps.as_mut(),
         mock_env(),
-        mock_info(MOCK_CONTRACT_ADDR, &[]),
-        ExecuteMsg::Callback(CallbackMsg::Reinvest {}),
+        mock_info("token", &[]),
+        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
+            sender: "user_1".to_string(),
+            amount: Uint128::new(2000),
+            msg: to_binary(&ReceiveMsg::Bond {
+                receiver: None,
+                referral: None,
+                min_ustake_out: None,
+            })
+            .unwrap(),
+        }),
     )
     .unwrap();
 
     assert_eq!(res.messages.len(), 2);
-
-    let total = Uint128::from(234u128);
-    let fee =
-        Decimal::from_ratio(1u128, 100u128).checked_mul_uint(total).expect("expects fee result");
-    let delegated = total;
-
     assert_eq!(
-        res.messages[0],
-        SubMsg {
-            id: 0,
-            msg: Delegation::new("bob", delegated.u128()).to_cosmos_msg(),
-            gas_limit: None,
-            reply_on: ReplyOn::Never
+        res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "token".to_string(),
+            funds: vec![],
+            msg: to_binary(&Cw20ExecuteMsg::Send {
+                contract: "token_uluna".to_string(),
+                amount: Uint128::new(2000),
+                msg: to_binary(&PairExecuteMsg::Swap {
+                    offer_asset: Asset {
+                        amount: Uint128::new(2000),
+                        info: AssetInfo::Token {
+                            contract_addr: Addr::unchecked("token")
+                        }
+                    },
+                    belief_price: None,
+                    max_spread: Some(Decimal::percent(1)),
+                    to: None,
+                })
+                .unwrap(),
+            })
+            .unwrap(),
+        }),
+    );
+    assert_eq!(
+        res.messages[1].msg,
+        CallbackMsg::BondSwapped {
+            receiver: Addr::unchecked("user_1"),
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(200),
+            referral: None,
+            min_ustake_out: None,
         }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
     );
 
-    match res.messages[1].msg.clone() {
-        CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr,
-            funds,
-            msg,
-        }) => {
-            assert_eq!(contract_addr, "stake_token".to_string());
-            assert_eq!(funds.len(), 0);
-
-            let sub_msg: Cw20ExecuteMsg = from_binary(&msg).unwrap();
+    // Routes with hops and denoms without a route can not be bonded
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[coin(10000, "ukrw")]),
+        ExecuteMsg::Bond {
+            receiver: None,
+            referral: None,
+            min_ustake_out: None,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        StdError::generic_err("cannot bond ukrw: only swap routes without hops are supported")
+    );
 
-            assert_eq!(
-                sub_msg,
-                Cw20ExecuteMsg::Mint {
-                    recipient: "fee".to_string(),
-                    amount: fee
-                }
-            );
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[coin(10000, "uatom")]),
+        ExecuteMsg::Bond {
+            receiver: None,
+            referral: None,
+            min_ustake_out: None,
         },
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("no swap route for denom uatom"));
 
-        _ => panic!("DO NOT ENTER HERE"),
-    }
+    // The callback bonds the uluna received, if at least the minimum
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
+    deps.querier.set_bank_balances(&[coin(734, "uluna")]);
 
-    // Storage should have been updated
-    let unlocked_coins = state.unlocked_coins.load(deps.as_ref().storage).unwrap();
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::BondSwapped {
+            receiver: Addr::unchecked("user_2"),
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(501),
+            referral: None,
+            min_ustake_out: None,
+        }),
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("swap returned 500uluna, less than the minimum of 501"));
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::BondSwapped {
+            receiver: Addr::unchecked("user_2"),
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(500),
+            referral: Some(Addr::unchecked("frontend")),
+            min_ustake_out: None,
+        }),
+    )
+    .unwrap();
+
+    // 500 uluna at an exchange rate of 1.025
     assert_eq!(
-        unlocked_coins,
-        vec![Coin::new(
-            69420,
-            "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B"
-        )],
+        res.messages[1].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: STAKE_DENOM.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Mint {
+                recipient: "user_2".to_string(),
+                amount: Uint128::new(487),
+            })
+            .unwrap(),
+            funds: vec![],
+        })
     );
+
+    let state = State::default();
+    let referral = state.referrals.load(deps.as_ref().storage, &Addr::unchecked("frontend"));
+    assert_eq!(referral.unwrap(), Uint128::new(500));
 }
 
 #[test]
-fn queuing_unbond() {
+fn enforcing_minimum_amounts_out() {
     let mut deps = setup_test();
-    let state = State::default();
 
-    // Only Stake token is accepted for unbonding requests
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
+    deps.querier.set_bank_balances(&[coin(12345, CONTRACT_DENOM)]);
+
+    // 12345 uluna at an exchange rate of 1.025 mint 12043 ustake
     let err = execute(
         deps.as_mut(),
         mock_env(),
-        mock_info("random_token", &[]),
+        mock_info("user_1", &[Coin::new(12345, CONTRACT_DENOM)]),
+        ExecuteMsg::Bond {
+            receiver: None,
+            referral: None,
+            min_ustake_out: Some(Uint128::new(12044)),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("minted 12043 ustake, less than the minimum of 12044"));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[Coin::new(12345, CONTRACT_DENOM)]),
+        ExecuteMsg::Bond {
+            receiver: None,
+            referral: None,
+            min_ustake_out: Some(Uint128::new(12043)),
+        },
+    )
+    .unwrap();
+
+    // 10000 ustake are estimated to return 10250 uluna
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("stake_token", &[]),
         ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
-            sender: "hacker".to_string(),
-            amount: Uint128::new(69420),
+            sender: "user_1".to_string(),
+            amount: Uint128::new(10000),
             msg: to_binary(&ReceiveMsg::QueueUnbond {
                 receiver: None,
+                min_uluna_out: Some(Uint128::new(10251)),
             })
             .unwrap(),
         }),
     )
     .unwrap_err();
+    assert_eq!(err, StdError::generic_err("estimated 10250 uluna, less than the minimum of 10251"));
 
-    assert_eq!(err, StdError::generic_err("expecting Stake token, received random_token"));
-
-    // User 1 creates an unbonding request before `est_unbond_start_time` is reached. The unbond
-    // request is saved, but not the pending batch is not submitted for unbonding
-    let res = execute(
+    execute(
         deps.as_mut(),
-        mock_env_at_timestamp(12345), // est_unbond_start_time = 269200
+        mock_env(),
         mock_info("stake_token", &[]),
         ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
             sender: "user_1".to_string(),
-            amount: Uint128::new(23456),
+            amount: Uint128::new(10000),
             msg: to_binary(&ReceiveMsg::QueueUnbond {
                 receiver: None,
+                min_uluna_out: Some(Uint128::new(10250)),
             })
             .unwrap(),
         }),
     )
     .unwrap();
 
-    assert_eq!(res.messages.len(), 0);
+    let state = State::default();
+    let request =
+        state.unbond_requests.load(deps.as_ref().storage, (1, &Addr::unchecked("user_1"))).unwrap();
+    assert_eq!(request.shares, Uint128::new(10000));
+}
+
+#[test]
+fn harvesting() {
+    let mut deps = setup_test();
+
+    // Assume users have bonded a total of 1,000,000 utoken and minted the same amount of ustake
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
 
-    // User 2 creates an unbonding request after `est_unbond_start_time` is reached. The unbond
-    // request is saved, and the pending is automatically submitted for unbonding
     let res = execute(
         deps.as_mut(),
-        mock_env_at_timestamp(269201), // est_unbond_start_time = 269200
-        mock_info("stake_token", &[]),
-        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
-            sender: "user_2".to_string(),
-            amount: Uint128::new(69420),
-            msg: to_binary(&ReceiveMsg::QueueUnbond {
-                receiver: Some("user_3".to_string()),
-            })
-            .unwrap(),
-        }),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Harvest {},
     )
     .unwrap();
 
-    assert_eq!(res.messages.len(), 1);
+    assert_eq!(res.messages.len(), 6);
     assert_eq!(
         res.messages[0],
-        SubMsg {
-            id: 0,
-            msg: CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: MOCK_CONTRACT_ADDR.to_string(),
-                msg: to_binary(&ExecuteMsg::SubmitBatch {}).unwrap(),
-                funds: vec![]
-            }),
-            gas_limit: None,
-            reply_on: ReplyOn::Never
-        }
+        SubMsg::new(CosmosMsg::Distribution(DistributionMsg::WithdrawDelegatorReward {
+            validator: "alice".to_string(),
+        }))
     );
-
-    // The users' unbonding requests should have been saved
-    let ubr1 = state
-        .unbond_requests
-        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
-        .unwrap();
-    let ubr2 = state
-        .unbond_requests
-        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_3")))
-        .unwrap();
-
     assert_eq!(
-        ubr1,
-        UnbondRequest {
-            id: 1,
-            user: Addr::unchecked("user_1"),
-            shares: Uint128::new(23456)
-        }
+        res.messages[1],
+        SubMsg::new(CosmosMsg::Distribution(DistributionMsg::WithdrawDelegatorReward {
+            validator: "bob".to_string(),
+        }))
     );
     assert_eq!(
-        ubr2,
-        UnbondRequest {
-            id: 1,
-            user: Addr::unchecked("user_3"),
-            shares: Uint128::new(69420)
-        }
+        res.messages[2],
+        SubMsg::new(CosmosMsg::Distribution(DistributionMsg::WithdrawDelegatorReward {
+            validator: "charlie".to_string(),
+        }))
     );
+    assert_eq!(res.messages[3], check_received_coin(0));
 
-    // Pending batch should have been updated
-    let pending_batch = state.pending_batch.load(deps.as_ref().storage).unwrap();
     assert_eq!(
-        pending_batch,
-        PendingBatch {
+        res.messages[4],
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: MOCK_CONTRACT_ADDR.to_string(),
+            msg: to_binary(&ExecuteMsg::Callback(CallbackMsg::Swap {})).unwrap(),
+            funds: vec![]
+        }))
+    );
+    assert_eq!(
+        res.messages[5],
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: MOCK_CONTRACT_ADDR.to_string(),
+            msg: to_binary(&ExecuteMsg::Callback(CallbackMsg::Reinvest {
+                keeper: None,
+            }))
+            .unwrap(),
+            funds: vec![]
+        }))
+    );
+}
+
+#[test]
+fn registering_unlocked_coins() {
+    let mut deps = setup_test();
+
+    let state = State::default();
+
+    deps.querier.set_bank_balances(&[coin(100 + 123, CONTRACT_DENOM)]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::CheckReceivedCoin {
+            snapshot: coin(100, CONTRACT_DENOM),
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/received")
+            .add_attribute("received_coin", 123.to_string() + CONTRACT_DENOM)]
+    );
+
+    // Unlocked coins in contract state should have been updated
+    let unlocked_coins = state.unlocked_coins.load(deps.as_ref().storage).unwrap();
+    assert_eq!(unlocked_coins, vec![Coin::new(123, CONTRACT_DENOM),]);
+}
+
+#[test]
+fn swapping() {
+    let mut deps = setup_test();
+
+    deps.querier.set_bank_balances(&[
+        coin(123, "ukrw"),
+        coin(234, "uluna"),
+        coin(345, "uusd"),
+        coin(69420, "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B"),
+    ]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Swap {}),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 1);
+
+    // 345 - TAX of 1 %
+    let amount = Uint128::new(345) - Uint128::new(4);
+
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "uusd_uluna".to_string(),
+            funds: vec![Coin {
+                denom: "uusd".to_string(),
+                amount,
+            }],
+            msg: to_binary(&PairExecuteMsg::Swap {
+                offer_asset: Asset {
+                    amount,
+                    info: eris::asset::AssetInfo::NativeToken {
+                        denom: "uusd".to_string()
+                    }
+                },
+                belief_price: None,
+                max_spread: None,
+                to: None,
+            })
+            .unwrap(),
+        }),
+    );
+}
+
+#[test]
+fn swapping_along_routes() {
+    let mut deps = setup_test();
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            swap_config: Some(vec![
+                SwapConfig {
+                    denom: "uusd".to_string(),
+                    contract: Addr::unchecked("uusd_uluna"),
+                    max_spread: Some(Decimal::percent(2)),
+                    hops: vec![],
+                    min_return: None,
+                    price_source: None,
+                    kind: SwapKind::Pair,
+                },
+                SwapConfig {
+                    denom: "ukrw".to_string(),
+                    contract: Addr::unchecked("ukrw_uusd"),
+                    max_spread: Some(Decimal::percent(5)),
+                    hops: vec![SwapHop {
+                        denom: "uusd".to_string(),
+                        contract: Addr::unchecked("uusd_uluna"),
+                        max_spread: Some(Decimal::percent(2)),
+                    }],
+                    min_return: Some(Decimal::from_ratio(1u128, 1000u128)),
+                    price_source: None,
+                    kind: SwapKind::Pair,
+                },
+            ]),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    deps.querier.set_bank_balances(&[coin(100000, "ukrw"), coin(234, "uluna"), coin(345, "uusd")]);
+
+    // Simple routes are swapped directly, routes with hops are swapped in a callback
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Swap {}),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 2);
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "uusd_uluna".to_string(),
+            funds: vec![coin(341, "uusd")],
+            msg: to_binary(&PairExecuteMsg::Swap {
+                offer_asset: Asset {
+                    amount: Uint128::new(341),
+                    info: AssetInfo::NativeToken {
+                        denom: "uusd".to_string()
+                    }
+                },
+                belief_price: None,
+                max_spread: Some(Decimal::percent(2)),
+                to: None,
+            })
+            .unwrap(),
+        }),
+    );
+    assert_eq!(
+        res.messages[1],
+        SubMsg::reply_on_error(
+            CallbackMsg::SwapRoute {
+                denom: "ukrw".to_string()
+            }
+            .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+            .unwrap(),
+            101
+        )
+    );
+
+    // The first hop is swapped, followed by the next hops and the check of the minimum return.
+    // 100000 ukrw minus the tax cap of 100 is offered
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::SwapRoute {
+            denom: "ukrw".to_string(),
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 3);
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "ukrw_uusd".to_string(),
+            funds: vec![coin(99900, "ukrw")],
+            msg: to_binary(&PairExecuteMsg::Swap {
+                offer_asset: Asset {
+                    amount: Uint128::new(99900),
+                    info: AssetInfo::NativeToken {
+                        denom: "ukrw".to_string()
+                    }
+                },
+                belief_price: None,
+                max_spread: Some(Decimal::percent(5)),
+                to: None,
+            })
+            .unwrap(),
+        }),
+    );
+    assert_eq!(
+        res.messages[1].msg,
+        CallbackMsg::SwapHop {
+            hop: SwapHop {
+                denom: "uusd".to_string(),
+                contract: Addr::unchecked("uusd_uluna"),
+                max_spread: Some(Decimal::percent(2)),
+            }
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    );
+    assert_eq!(
+        res.messages[2].msg,
+        CallbackMsg::AssertMinReturn {
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(100),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    );
+
+    // The hop swaps whatever it received from the previous one
+    deps.querier.set_bank_balances(&[coin(234, "uluna"), coin(500, "uusd")]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::SwapHop {
+            hop: SwapHop {
+                denom: "uusd".to_string(),
+                contract: Addr::unchecked("uusd_uluna"),
+                max_spread: Some(Decimal::percent(2)),
+            },
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "uusd_uluna".to_string(),
+            funds: vec![coin(495, "uusd")],
+            msg: to_binary(&PairExecuteMsg::Swap {
+                offer_asset: Asset {
+                    amount: Uint128::new(495),
+                    info: AssetInfo::NativeToken {
+                        denom: "uusd".to_string()
+                    }
+                },
+                belief_price: None,
+                max_spread: Some(Decimal::percent(2)),
+                to: None,
+            })
+            .unwrap(),
+        }),
+    );
+
+    // Too little Luna received over the whole route
+    deps.querier.set_bank_balances(&[coin(300, "uluna")]);
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::AssertMinReturn {
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(100),
+        }),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        StdError::generic_err("swap route returned 66uluna, less than the minimum of 100")
+    );
+
+    deps.querier.set_bank_balances(&[coin(334, "uluna")]);
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::AssertMinReturn {
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(100),
+        }),
+    )
+    .unwrap();
+
+    // Direct swaps still go first if a route hopping through their denom is listed before them,
+    // so that the hop does not spend the balance they were built from
+    let mut swap_config = State::default().swap_config.load(deps.as_ref().storage).unwrap();
+    swap_config.reverse();
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            swap_config: Some(swap_config),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    deps.querier.set_bank_balances(&[coin(100000, "ukrw"), coin(234, "uluna"), coin(345, "uusd")]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Swap {}),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 2);
+    assert!(matches!(
+        &res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, funds, .. })
+            if contract_addr == "uusd_uluna" && *funds == vec![coin(341, "uusd")]
+    ));
+    assert_eq!(
+        res.messages[1],
+        SubMsg::reply_on_error(
+            CallbackMsg::SwapRoute {
+                denom: "ukrw".to_string()
+            }
+            .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+            .unwrap(),
+            100
+        )
+    );
+
+    // A failing route, e.g. a hop exceeding its max spread, is reverted on its own and skipped
+    // without failing the harvest
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        Reply {
+            id: 100,
+            result: SubMsgResult::Err("max spread assertion".to_string()),
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 0);
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/swap_skipped")
+            .add_attribute("denom", "ukrw")
+            .add_attribute("reason", "route failed")
+            .add_attribute("error", "max spread assertion")]
+    );
+}
+
+#[test]
+fn swapping_with_price_protection() {
+    let mut deps = setup_test();
+
+    let swap_config = |denom: &str, contract: &str| SwapConfig {
+        denom: denom.to_string(),
+        contract: Addr::unchecked(contract),
+        max_spread: Some(Decimal::percent(2)),
+        hops: vec![],
+        min_return: None,
+        price_source: Some(PriceSource::Oracle {}),
+        kind: SwapKind::Pair,
+    };
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            swap_config: Some(vec![SwapConfig {
+                max_spread: None,
+                ..swap_config("uusd", "uusd_uluna")
+            }]),
+            ..Default::default()
+        })),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        StdError::generic_err(
+            "'max_spread' is required for denom 'uusd' in swap config with a price source"
+        )
+    );
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            swap_config: Some(vec![
+                swap_config("uusd", "uusd_uluna"),
+                swap_config("ukrw", "ukrw_uluna"),
+                swap_config("usdr", "usdr_uluna"),
+            ]),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    deps.querier.set_bank_balances(&[
+        coin(10000, "ukrw"),
+        coin(234, "uluna"),
+        coin(1000, "usdr"),
+        coin(345, "uusd"),
+    ]);
+    deps.querier.set_terra_exchange_rate("uluna", "uusd", Decimal::from_ratio(2u128, 1u128));
+    deps.querier.set_terra_exchange_rate("uluna", "ukrw", Decimal::from_ratio(100u128, 1u128));
+    deps.querier.set_pair_rate("uusd_uluna", Decimal::from_ratio(495u128, 1000u128));
+    deps.querier.set_pair_rate("ukrw_uluna", Decimal::from_ratio(95u128, 10000u128));
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Swap {}),
+    )
+    .unwrap();
+
+    // uusd: 341 offered after tax, 170 expected at the oracle price, 168 returned: 1.2% spread
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "uusd_uluna".to_string(),
+            funds: vec![coin(341, "uusd")],
+            msg: to_binary(&PairExecuteMsg::Swap {
+                offer_asset: Asset {
+                    amount: Uint128::new(341),
+                    info: AssetInfo::NativeToken {
+                        denom: "uusd".to_string()
+                    }
+                },
+                belief_price: Some(Decimal::from_ratio(2u128, 1u128)),
+                max_spread: Some(Decimal::percent(2)),
+                to: None,
+            })
+            .unwrap(),
+        }),
+    );
+
+    // ukrw: 9900 offered after tax, 99 expected at the oracle price, 94 returned: 5% spread
+    // usdr: no oracle price
+    assert_eq!(
+        res.events,
+        vec![
+            Event::new("erishub/swap_skipped")
+                .add_attribute("denom", "ukrw")
+                .add_attribute("reason", "max spread exceeded")
+                .add_attribute("belief_price", "100")
+                .add_attribute("return_amount", "94"),
+            Event::new("erishub/swap_skipped")
+                .add_attribute("denom", "usdr")
+                .add_attribute("reason", "unknown price"),
+        ]
+    );
+
+    // Along a route, the price of the whole route is only known once all hops are swapped. A route
+    // returning less than the reference price allows for is skipped as well
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            swap_config: Some(vec![SwapConfig {
+                hops: vec![SwapHop {
+                    denom: "uusd".to_string(),
+                    contract: Addr::unchecked("uusd_uluna"),
+                    max_spread: Some(Decimal::percent(5)),
+                }],
+                ..swap_config("ukrw", "ukrw_uusd")
+            }]),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    deps.querier.set_bank_balances(&[coin(10000, "ukrw"), coin(234, "uluna")]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Swap {}),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(res.messages[0].reply_on, ReplyOn::Error);
+    assert_eq!(res.messages[0].id, 100);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::SwapRoute {
+            denom: "ukrw".to_string(),
+        }),
+    )
+    .unwrap();
+
+    // 9900 offered after tax, 99 expected at the oracle price, at least 97 after a 2% spread
+    assert_eq!(res.messages.len(), 3);
+    assert_eq!(
+        res.messages[2].msg,
+        CallbackMsg::AssertMinReturn {
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(97),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    );
+
+    deps.querier.set_bank_balances(&[coin(324, "uluna")]);
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::AssertMinReturn {
+            snapshot: coin(234, "uluna"),
+            min_received: Uint128::new(97),
+        }),
+    )
+    .unwrap_err();
+
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        Reply {
+            id: 100,
+            result: SubMsgResult::Err(err.to_string()),
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/swap_skipped")
+            .add_attribute("denom", "ukrw")
+            .add_attribute("reason", "route failed")
+            .add_attribute(
+                "error",
+                "Generic error: swap route returned 90uluna, less than the minimum of 97"
+            )]
+    );
+}
+
+#[test]
+fn swapping_through_market_module() {
+    let mut deps = setup_test();
+
+    let swap_config = |denom: &str, contract: &str, kind: SwapKind| SwapConfig {
+        denom: denom.to_string(),
+        contract: Addr::unchecked(contract),
+        max_spread: None,
+        hops: vec![],
+        min_return: None,
+        price_source: None,
+        kind,
+    };
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            swap_config: Some(vec![
+                swap_config("uusd", "uusd_uluna", SwapKind::BestRate),
+                swap_config("ukrw", "ukrw_uluna", SwapKind::Market),
+                swap_config("usdr", "usdr_uluna", SwapKind::BestRate),
+            ]),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    deps.querier.set_bank_balances(&[
+        coin(10000, "ukrw"),
+        coin(234, "uluna"),
+        coin(1000, "usdr"),
+        coin(345, "uusd"),
+    ]);
+    deps.querier.set_pair_rate("uusd_uluna", Decimal::from_ratio(5u128, 10u128));
+    deps.querier.set_terra_swap_rate("uusd", "uluna", Decimal::from_ratio(6u128, 10u128));
+    deps.querier.set_pair_rate("usdr_uluna", Decimal::from_ratio(6u128, 10u128));
+    deps.querier.set_terra_swap_rate("usdr", "uluna", Decimal::from_ratio(5u128, 10u128));
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Swap {}),
+    )
+    .unwrap();
+
+    let market_swap_msg = |denom: &str, amount: u128| -> CosmosMsg<TerraMsg> {
+        CosmosMsg::Custom(TerraMsg::Swap {
+            offer_coin: coin(amount, denom),
+            ask_denom: "uluna".to_string(),
+        })
+    };
+
+    assert_eq!(res.messages.len(), 3);
+
+    // uusd: the market module returns more than the pair, and the full balance is offered
+    assert_eq!(res.messages[0].msg, market_swap_msg("uusd", 345));
+
+    // ukrw: always swapped through the market module
+    assert_eq!(res.messages[1].msg, market_swap_msg("ukrw", 10000));
+
+    // usdr: the pair returns more than the market module, tax is deducted from the offer
+    assert_eq!(
+        res.messages[2].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "usdr_uluna".to_string(),
+            funds: vec![coin(990, "usdr")],
+            msg: to_binary(&PairExecuteMsg::Swap {
+                offer_asset: Asset {
+                    amount: Uint128::new(990),
+                    info: AssetInfo::NativeToken {
+                        denom: "usdr".to_string()
+                    }
+                },
+                belief_price: None,
+                max_spread: None,
+                to: None,
+            })
+            .unwrap(),
+        }),
+    );
+}
+
+#[test]
+fn reinvesting() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 333334),
+        Delegation::new("bob", 333333),
+        Delegation::new("charlie", 333333),
+    ]);
+    deps.querier.set_cw20_total_supply("stake_token", 333333 + 333333 + 333334);
+
+    // After the swaps, `unlocked_coins` should contain only uluna and unknown denoms
+    state
+        .unlocked_coins
+        .save(
+            deps.as_mut().storage,
+            &vec![
+                Coin::new(234, "uluna"),
+                Coin::new(
+                    69420,
+                    "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B",
+                ),
+            ],
+        )
+        .unwrap();
+
+    // Bob has the smallest amount of delegations, so all proceeds go to him
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Reinvest {
+            keeper: None,
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 2);
+
+    let total = Uint128::from(234u128);
+    let fee =
+        Decimal::from_ratio(1u128, 100u128).checked_mul_uint(total).expect("expects fee result");
+    let delegated = total;
+
+    assert_eq!(
+        res.messages[0],
+        SubMsg {
+            id: 0,
+            msg: Delegation::new("bob", delegated.u128()).to_cosmos_msg(),
+            gas_limit: None,
+            reply_on: ReplyOn::Never
+        }
+    );
+
+    match res.messages[1].msg.clone() {
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr,
+            funds,
+            msg,
+        }) => {
+            assert_eq!(contract_addr, "stake_token".to_string());
+            assert_eq!(funds.len(), 0);
+
+            let sub_msg: Cw20ExecuteMsg = from_binary(&msg).unwrap();
+
+            assert_eq!(
+                sub_msg,
+                Cw20ExecuteMsg::Mint {
+                    recipient: "fee".to_string(),
+                    amount: fee
+                }
+            );
+        },
+
+        _ => panic!("DO NOT ENTER HERE"),
+    }
+
+    // Storage should have been updated
+    let unlocked_coins = state.unlocked_coins.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        unlocked_coins,
+        vec![Coin::new(
+            69420,
+            "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B"
+        )],
+    );
+}
+
+#[test]
+fn reinvesting_with_reserve_above_max() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 333334),
+        Delegation::new("bob", 333333),
+        Delegation::new("charlie", 333333),
+    ]);
+    deps.querier.set_cw20_total_supply("stake_token", 1000000);
+    state.liquid_reserve.save(deps.as_mut().storage, &Uint128::new(50000)).unwrap();
+
+    // The max reserve is lowered below the amount held in the reserve
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            reserve_config: Some(ReserveConfig {
+                reserve_share: Decimal::from_ratio(10u128, 100u128),
+                max_reserve: Uint128::new(20000),
+                instant_unbond_fee: Decimal::zero(),
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    // Nothing is added to the reserve, and the 30,000 above the max are delegated with the rewards
+    state.unlocked_coins.save(deps.as_mut().storage, &vec![Coin::new(1000, "uluna")]).unwrap();
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Reinvest {
+            keeper: None,
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages[0], SubMsg::new(Delegation::new("bob", 31000).to_cosmos_msg()));
+    assert_eq!(res.events[0].attributes[3], attr("uluna_reserved", "0"));
+    assert_eq!(res.events[0].attributes[4], attr("uluna_reserve_surplus", "30000"));
+
+    let reserve = state.liquid_reserve.load(deps.as_ref().storage).unwrap();
+    assert_eq!(reserve, Uint128::new(20000));
+}
+
+#[test]
+fn reinvesting_with_fee_recipients() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    let update_fee_recipients = |deps: DepsMut<TerraQuery>, fee_recipients: Vec<(&str, u64)>| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info("owner", &[]),
+            ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+                fee_recipients: Some(
+                    fee_recipients
+                        .into_iter()
+                        .map(|(recipient, weight)| {
+                            (recipient.to_string(), Decimal::percent(weight))
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            })),
+        )
+    };
+
+    let err =
+        update_fee_recipients(deps.as_mut(), vec![("treasury", 50), ("pool", 25)]).unwrap_err();
+    assert_eq!(err, StdError::generic_err("weights of fee recipients must sum up to 1"));
+
+    let err =
+        update_fee_recipients(deps.as_mut(), vec![("treasury", 50), ("treasury", 50)]).unwrap_err();
+    assert_eq!(err, StdError::generic_err("duplicate fee recipient 'treasury'"));
+
+    let err =
+        update_fee_recipients(deps.as_mut(), vec![("treasury", 100), ("pool", 0)]).unwrap_err();
+    assert_eq!(err, StdError::generic_err("fee recipient 'pool' has a weight of zero"));
+
+    update_fee_recipients(deps.as_mut(), vec![("treasury", 50), ("pool", 25), ("referral", 25)])
+        .unwrap();
+
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 333334),
+        Delegation::new("bob", 333333),
+        Delegation::new("charlie", 333333),
+    ]);
+    deps.querier.set_cw20_total_supply("stake_token", 1000000);
+    state.unlocked_coins.save(deps.as_mut().storage, &vec![Coin::new(100100, "uluna")]).unwrap();
+
+    // Protocol fee: 1% of 100,100 = 1,001 uluna, minted as 1,001 ustake
+    // Split: 500 + 250 + 250, the remainder of 1 goes to the first recipient
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Reinvest {
+            keeper: None,
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 4);
+    for (i, (recipient, amount)) in
+        [("treasury", 501u128), ("pool", 250), ("referral", 250)].iter().enumerate()
+    {
+        assert_eq!(
+            res.messages[i + 1].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "stake_token".to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Mint {
+                    recipient: recipient.to_string(),
+                    amount: Uint128::new(*amount)
+                })
+                .unwrap(),
+                funds: vec![]
+            })
+        );
+    }
+
+    assert_eq!(
+        res.events[0].attributes.last().unwrap(),
+        attr("ustake_protocol_fee_split", "treasury:501,pool:250,referral:250")
+    );
+}
+
+#[test]
+fn queuing_unbond() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    // Only Stake token is accepted for unbonding requests
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("random_token", &[]),
+        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
+            sender: "hacker".to_string(),
+            amount: Uint128::new(69420),
+            msg: to_binary(&ReceiveMsg::QueueUnbond {
+                receiver: None,
+                min_uluna_out: None,
+            })
+            .unwrap(),
+        }),
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("expecting Stake token, received random_token"));
+
+    // User 1 creates an unbonding request before `est_unbond_start_time` is reached. The unbond
+    // request is saved, but not the pending batch is not submitted for unbonding
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(12345), // est_unbond_start_time = 269200
+        mock_info("stake_token", &[]),
+        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
+            sender: "user_1".to_string(),
+            amount: Uint128::new(23456),
+            msg: to_binary(&ReceiveMsg::QueueUnbond {
+                receiver: None,
+                min_uluna_out: None,
+            })
+            .unwrap(),
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 0);
+
+    // User 2 creates an unbonding request after `est_unbond_start_time` is reached. The unbond
+    // request is saved, and the pending is automatically submitted for unbonding
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(269201), // est_unbond_start_time = 269200
+        mock_info("stake_token", &[]),
+        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
+            sender: "user_2".to_string(),
+            amount: Uint128::new(69420),
+            msg: to_binary(&ReceiveMsg::QueueUnbond {
+                receiver: Some("user_3".to_string()),
+                min_uluna_out: None,
+            })
+            .unwrap(),
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(
+        res.messages[0],
+        SubMsg {
+            id: 0,
+            msg: CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: MOCK_CONTRACT_ADDR.to_string(),
+                msg: to_binary(&ExecuteMsg::SubmitBatch {}).unwrap(),
+                funds: vec![]
+            }),
+            gas_limit: None,
+            reply_on: ReplyOn::Never
+        }
+    );
+
+    // The users' unbonding requests should have been saved
+    let ubr1 = state
+        .unbond_requests
+        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
+        .unwrap();
+    let ubr2 = state
+        .unbond_requests
+        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_3")))
+        .unwrap();
+
+    assert_eq!(
+        ubr1,
+        UnbondRequest {
+            id: 1,
+            user: Addr::unchecked("user_1"),
+            shares: Uint128::new(23456)
+        }
+    );
+    assert_eq!(
+        ubr2,
+        UnbondRequest {
+            id: 1,
+            user: Addr::unchecked("user_3"),
+            shares: Uint128::new(69420)
+        }
+    );
+
+    // Pending batch should have been updated
+    let pending_batch = state.pending_batch.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        pending_batch,
+        PendingBatch {
+            id: 1,
+            ustake_to_burn: Uint128::new(92876), // 23,456 + 69,420
+            est_unbond_start_time: 269200
+        }
+    );
+}
+
+#[test]
+fn cancelling_unbond() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(12345),
+        mock_info("stake_token", &[]),
+        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
+            sender: "user_1".to_string(),
+            amount: Uint128::new(23456),
+            msg: to_binary(&ReceiveMsg::QueueUnbond {
+                receiver: None,
+                min_uluna_out: None,
+            })
+            .unwrap(),
+        }),
+    )
+    .unwrap();
+
+    // Users without a request in the pending batch cannot cancel
+    let err = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(12345),
+        mock_info("user_2", &[]),
+        ExecuteMsg::CancelUnbond {
+            amount: None,
+        },
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("no unbond request found in the pending batch"));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(12345),
+        mock_info("user_1", &[]),
+        ExecuteMsg::CancelUnbond {
+            amount: Some(Uint128::new(23457)),
+        },
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("cannot cancel 23457 ustake, only 23456 queued"));
+
+    // Cancel part of the request; the Stake tokens are returned
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(12345),
+        mock_info("user_1", &[]),
+        ExecuteMsg::CancelUnbond {
+            amount: Some(Uint128::new(3456)),
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "stake_token".to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: "user_1".to_string(),
+                amount: Uint128::new(3456)
+            })
+            .unwrap(),
+            funds: vec![]
+        })
+    );
+
+    let pending_batch = state.pending_batch.load(deps.as_ref().storage).unwrap();
+    assert_eq!(pending_batch.ustake_to_burn, Uint128::new(20000));
+
+    let ubr = state
+        .unbond_requests
+        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
+        .unwrap();
+    assert_eq!(ubr.shares, Uint128::new(20000));
+
+    // Cancel the rest of the request; the request should be purged from storage
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(12345),
+        mock_info("user_1", &[]),
+        ExecuteMsg::CancelUnbond {
+            amount: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "stake_token".to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: "user_1".to_string(),
+                amount: Uint128::new(20000)
+            })
+            .unwrap(),
+            funds: vec![]
+        })
+    );
+
+    let pending_batch = state.pending_batch.load(deps.as_ref().storage).unwrap();
+    assert_eq!(pending_batch.ustake_to_burn, Uint128::zero());
+
+    let ubr = state
+        .unbond_requests
+        .may_load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
+        .unwrap();
+    assert_eq!(ubr, None);
+}
+
+#[test]
+fn instant_unbonding() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            reserve_config: Some(ReserveConfig {
+                reserve_share: Decimal::from_ratio(60u128, 100u128),
+                max_reserve: Uint128::new(100000),
+                instant_unbond_fee: Decimal::from_ratio(1u128, 100u128),
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("'reserve_share' greater than max"));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            reserve_config: Some(ReserveConfig {
+                reserve_share: Decimal::from_ratio(10u128, 100u128),
+                max_reserve: Uint128::new(100000),
+                instant_unbond_fee: Decimal::from_ratio(1u128, 100u128),
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    // 10% of the deposit is kept in the reserve, the rest is delegated. The full deposit is minted
+    deps.querier.set_bank_balances(&[coin(1000100, CONTRACT_DENOM)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[Coin::new(1000000, CONTRACT_DENOM)]),
+        ExecuteMsg::Bond {
+            receiver: None,
+            referral: None,
+            min_ustake_out: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 3);
+    assert_eq!(res.messages[0], SubMsg::new(Delegation::new("alice", 900000).to_cosmos_msg()));
+    assert_eq!(
+        res.messages[1],
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: STAKE_DENOM.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Mint {
+                recipient: "user_1".to_string(),
+                amount: Uint128::new(1000000)
+            })
+            .unwrap(),
+            funds: vec![]
+        }))
+    );
+    assert_eq!(res.messages[2], check_received_coin(100 + 100000));
+
+    let reserve = state.liquid_reserve.load(deps.as_ref().storage).unwrap();
+    assert_eq!(reserve, Uint128::new(100000));
+
+    deps.querier.set_bank_balances(&[coin(100100, CONTRACT_DENOM)]);
+    deps.querier.set_staking_delegations(&[Delegation::new("alice", 900000)]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
+
+    // Only Stake token is accepted for instant unbonds
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("random_token", &[]),
+        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
+            sender: "user_1".to_string(),
+            amount: Uint128::new(50000),
+            msg: to_binary(&ReceiveMsg::InstantUnbond {
+                receiver: None,
+            })
+            .unwrap(),
+        }),
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("expecting Stake token, received random_token"));
+
+    // uluna unbonded: 1,000,000 * 50,000 / 1,000,000 = 50,000
+    // fee: 500, stays in the reserve
+    // refunded: 49,500 - 100 (tax)
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("stake_token", &[]),
+        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
+            sender: "user_1".to_string(),
+            amount: Uint128::new(50000),
+            msg: to_binary(&ReceiveMsg::InstantUnbond {
+                receiver: None,
+            })
+            .unwrap(),
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 2);
+    assert_eq!(
+        res.messages[0],
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: STAKE_DENOM.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Burn {
+                amount: Uint128::new(50000)
+            })
+            .unwrap(),
+            funds: vec![]
+        }))
+    );
+    assert_eq!(
+        res.messages[1],
+        SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+            to_address: "user_1".to_string(),
+            amount: vec![Coin::new(49400, CONTRACT_DENOM)]
+        }))
+    );
+
+    let reserve = state.liquid_reserve.load(deps.as_ref().storage).unwrap();
+    assert_eq!(reserve, Uint128::new(50500));
+
+    deps.querier.set_bank_balances(&[coin(50600, CONTRACT_DENOM)]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 950000);
+
+    let res: StateResponse = query_helper(deps.as_ref(), QueryMsg::State {});
+    assert_eq!(res.reserve_uluna, Uint128::new(50500));
+    assert_eq!(res.instant_unbond_fee, Decimal::from_ratio(1u128, 100u128));
+    assert_eq!(res.exchange_rate, Decimal::from_ratio(950500u128, 950000u128));
+
+    // uluna unbonded: 950,500 * 100,000 / 950,000 = 100,052
+    // refunded: 100,052 - 1,000 (fee) = 99,052, which exceeds the reserve
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("stake_token", &[]),
+        ExecuteMsg::Receive(cw20::Cw20ReceiveMsg {
+            sender: "user_1".to_string(),
+            amount: Uint128::new(100000),
+            msg: to_binary(&ReceiveMsg::InstantUnbond {
+                receiver: None,
+            })
+            .unwrap(),
+        }),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        StdError::generic_err("insufficient liquid reserve: 50500 available, 99052 required")
+    );
+}
+
+#[test]
+fn submitting_batch() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    // utoken bonded: 1,037,345
+    // ustake supply: 1,012,043
+    // utoken per ustake: 1.025
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 345782),
+        Delegation::new("bob", 345782),
+        Delegation::new("charlie", 345781),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1012043);
+
+    // We continue from the contract state at the end of the last test
+    let unbond_requests = vec![
+        UnbondRequest {
+            id: 1,
+            user: Addr::unchecked("user_1"),
+            shares: Uint128::new(23456),
+        },
+        UnbondRequest {
+            id: 1,
+            user: Addr::unchecked("user_3"),
+            shares: Uint128::new(69420),
+        },
+    ];
+
+    for unbond_request in &unbond_requests {
+        state
+            .unbond_requests
+            .save(
+                deps.as_mut().storage,
+                (unbond_request.id, &Addr::unchecked(unbond_request.user.clone())),
+                unbond_request,
+            )
+            .unwrap();
+    }
+
+    state
+        .pending_batch
+        .save(
+            deps.as_mut().storage,
+            &PendingBatch {
+                id: 1,
+                ustake_to_burn: Uint128::new(92876), // 23,456 + 69,420
+                est_unbond_start_time: 269200,
+            },
+        )
+        .unwrap();
+
+    // Anyone can invoke `submit_batch`. Here we continue from the previous test and assume it is
+    // invoked automatically as user 2 submits the unbonding request
+    //
+    // ustake to burn: 23,456 + 69,420 = 92,876
+    // utoken to unbond: 1,037,345 * 92,876 / 1,012,043 = 95,197
+    //
+    // Target: (1,037,345 - 95,197) / 3 = 314,049
+    // Remainer: 1
+    // Alice:   345,782 - (314,049 + 1) = 31,732
+    // Bob:     345,782 - (314,049 + 0) = 31,733
+    // Charlie: 345,781 - (314,049 + 0) = 31,732
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(269201),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::SubmitBatch {},
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 5);
+    assert_eq!(res.messages[0], SubMsg::new(Undelegation::new("alice", 31732).to_cosmos_msg()));
+    assert_eq!(res.messages[1], SubMsg::new(Undelegation::new("bob", 31733).to_cosmos_msg()));
+    assert_eq!(res.messages[2], SubMsg::new(Undelegation::new("charlie", 31732).to_cosmos_msg()));
+    assert_eq!(
+        res.messages[3],
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: STAKE_DENOM.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Burn {
+                amount: Uint128::new(92876)
+            })
+            .unwrap(),
+            funds: vec![]
+        }))
+    );
+    assert_eq!(res.messages[4], check_received_coin(0));
+
+    // A new pending batch should have been created
+    let pending_batch = state.pending_batch.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        pending_batch,
+        PendingBatch {
+            id: 2,
+            ustake_to_burn: Uint128::zero(),
+            est_unbond_start_time: 528401 // 269,201 + 259,200
+        }
+    );
+
+    // Previous batch should have been updated
+    let previous_batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(
+        previous_batch,
+        Batch {
+            id: 1,
+            reconciled: false,
+            total_shares: Uint128::new(92876),
+            uluna_unclaimed: Uint128::new(95197),
+            est_unbond_end_time: 2083601 // 269,201 + 1,814,400
+        }
+    );
+
+    let res: StateResponse = query_helper_env(deps.as_ref(), QueryMsg::State {}, 2083600);
+    assert_eq!(
+        res,
+        StateResponse {
+            total_ustake: Uint128::from(1012043u128),
+            total_uluna: Uint128::from(1037345u128),
+            exchange_rate: Decimal::from_ratio(1037345u128, 1012043u128),
+            unlocked_coins: vec![],
+            unbonding: Uint128::from(95197u128),
+            available: Uint128::zero(),
+            tvl_uluna: Uint128::from(95197u128 + 1037345u128),
+            reserve_uluna: Uint128::zero(),
+            instant_unbond_fee: Decimal::zero(),
+        },
+    );
+}
+
+#[test]
+fn submitting_batch_with_liquid_reserve() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    // uluna delegated: 500
+    // uluna in the reserve: 500
+    // ustake supply: 1,000
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 167),
+        Delegation::new("bob", 167),
+        Delegation::new("charlie", 166),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000);
+    state.liquid_reserve.save(deps.as_mut().storage, &Uint128::new(500)).unwrap();
+    state
+        .pending_batch
+        .save(
+            deps.as_mut().storage,
+            &PendingBatch {
+                id: 1,
+                ustake_to_burn: Uint128::new(600),
+                est_unbond_start_time: 269200,
+            },
+        )
+        .unwrap();
+
+    // The batch is worth more than the delegated Luna
+    //
+    // uluna to unbond: 1,000 * 600 / 1,000 = 600
+    // from the reserve: 500 * 600 / 1,000 = 300
+    // to undelegate: 600 - 300 = 300
+    //
+    // Target: (500 - 300) / 3 = 66
+    // Remainder: 2
+    // Alice:   167 - (66 + 1) = 100
+    // Bob:     167 - (66 + 1) = 100
+    // Charlie: 166 - (66 + 0) = 100
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(269201),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::SubmitBatch {},
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 5);
+    assert_eq!(res.messages[0], SubMsg::new(Undelegation::new("alice", 100).to_cosmos_msg()));
+    assert_eq!(res.messages[1], SubMsg::new(Undelegation::new("bob", 100).to_cosmos_msg()));
+    assert_eq!(res.messages[2], SubMsg::new(Undelegation::new("charlie", 100).to_cosmos_msg()));
+
+    let reserve = state.liquid_reserve.load(deps.as_ref().storage).unwrap();
+    assert_eq!(reserve, Uint128::new(200));
+    let set_aside = state.batch_reserves.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(set_aside, Uint128::new(300));
+
+    let previous_batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(
+        previous_batch,
+        Batch {
+            id: 1,
+            reconciled: false,
+            total_shares: Uint128::new(600),
+            uluna_unclaimed: Uint128::new(600),
+            est_unbond_end_time: 2083601 // 269,201 + 1,814,400
+        }
+    );
+
+    // The Luna set aside is not surplus while the batch is unbonding...
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 67),
+        Delegation::new("bob", 67),
+        Delegation::new("charlie", 66),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 400);
+    state
+        .previous_batches
+        .save(
+            deps.as_mut().storage,
+            2u64,
+            &Batch {
+                id: 2,
+                reconciled: false,
+                total_shares: Uint128::new(100),
+                uluna_unclaimed: Uint128::new(100),
+                est_unbond_end_time: 1000,
+            },
+        )
+        .unwrap();
+    deps.querier.set_bank_balances(&[coin(200 + 300 + 100, CONTRACT_DENOM)]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(1001),
+        mock_info("worker", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+    assert_eq!(res.events[0].attributes[1], attr("uluna_deducted", "0"));
+
+    // It is counted as unbonding rather than available, while the reserve backs the Stake token
+    let res: StateResponse = query_helper_env(deps.as_ref(), QueryMsg::State {}, 1001);
+    assert_eq!(
+        res,
+        StateResponse {
+            total_ustake: Uint128::new(400),
+            total_uluna: Uint128::new(200 + 200),
+            exchange_rate: Decimal::one(),
+            unlocked_coins: vec![],
+            unbonding: Uint128::new(600),
+            available: Uint128::new(200 + 100),
+            tvl_uluna: Uint128::new(200 + 600 + 300),
+            reserve_uluna: Uint128::new(200),
+            instant_unbond_fee: Decimal::zero(),
+        },
+    );
+
+    // ...and is part of the Luna expected once it finishes
+    deps.querier.set_bank_balances(&[coin(200 + 300 + 300, CONTRACT_DENOM)]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(2083602),
+        mock_info("worker", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+    assert_eq!(res.events[0].attributes[1], attr("uluna_deducted", "0"));
+
+    let previous_batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert!(previous_batch.reconciled);
+    assert_eq!(previous_batch.uluna_unclaimed, Uint128::new(600));
+    assert!(!state.batch_reserves.has(deps.as_ref().storage, 1u64));
+}
+
+#[test]
+fn reconciling() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    let previous_batches = vec![
+        Batch {
+            id: 1,
+            reconciled: true,
+            total_shares: Uint128::new(92876),
+            uluna_unclaimed: Uint128::new(95197), // 1.025 Luna per Stake
+            est_unbond_end_time: 10000,
+        },
+        Batch {
+            id: 2,
+            reconciled: false,
+            total_shares: Uint128::new(1345),
+            uluna_unclaimed: Uint128::new(1385), // 1.030 Luna per Stake
+            est_unbond_end_time: 20000,
+        },
+        Batch {
+            id: 3,
+            reconciled: false,
+            total_shares: Uint128::new(1456),
+            uluna_unclaimed: Uint128::new(1506), // 1.035 Luna per Stake
+            est_unbond_end_time: 30000,
+        },
+        Batch {
+            id: 4,
+            reconciled: false,
+            total_shares: Uint128::new(1567),
+            uluna_unclaimed: Uint128::new(1629), // 1.040 Luna per Stake
+            est_unbond_end_time: 40000,          // not yet finished unbonding, ignored
+        },
+    ];
+
+    for previous_batch in &previous_batches {
+        state
+            .previous_batches
+            .save(deps.as_mut().storage, previous_batch.id, previous_batch)
+            .unwrap();
+    }
+
+    state
+        .unlocked_coins
+        .save(
+            deps.as_mut().storage,
+            &vec![
+                Coin::new(10000, "uluna"),
+                Coin::new(234, "ukrw"),
+                Coin::new(345, "uusd"),
+                Coin::new(
+                    69420,
+                    "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B",
+                ),
+            ],
+        )
+        .unwrap();
+
+    deps.querier.set_bank_balances(&[
+        Coin::new(12345, "uluna"),
+        Coin::new(234, "ukrw"),
+        Coin::new(345, "uusd"),
+        Coin::new(69420, "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B"),
+    ]);
+
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(35000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+
+    // Expected received: batch 2 + batch 3 = 1385 + 1506 = 2891
+    // Expected unlocked: 10000
+    // Expected: 12891
+    // Actual: 12345
+    // Shortfall: 12891 - 12345 = 456
+    //
+    // uluna per batch: 546 / 2 = 273
+    // remainder: 0
+    // batch 2: 1385 - 273 = 1112
+    // batch 3: 1506 - 273 = 1233
+    let batch = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap();
+    assert_eq!(
+        batch,
+        Batch {
+            id: 2,
+            reconciled: true,
+            total_shares: Uint128::new(1345),
+            uluna_unclaimed: Uint128::new(1112), // 1385 - 273
+            est_unbond_end_time: 20000,
+        }
+    );
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 3u64).unwrap();
+    assert_eq!(
+        batch,
+        Batch {
+            id: 3,
+            reconciled: true,
+            total_shares: Uint128::new(1456),
+            uluna_unclaimed: Uint128::new(1233), // 1506 - 273
+            est_unbond_end_time: 30000,
+        }
+    );
+
+    // Batches 1 and 4 should not have changed
+    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(batch, previous_batches[0]);
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 4u64).unwrap();
+    assert_eq!(batch, previous_batches[3]);
+}
+
+#[test]
+fn reconciling_even_when_everything_ok() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    let previous_batches = vec![
+        Batch {
+            id: 1,
+            reconciled: true,
+            total_shares: Uint128::new(100000),
+            uluna_unclaimed: Uint128::new(100000),
+            est_unbond_end_time: 10000,
+        },
+        Batch {
+            id: 2,
+            reconciled: false,
+            total_shares: Uint128::new(1000),
+            uluna_unclaimed: Uint128::new(1000),
+            est_unbond_end_time: 20000,
+        },
+        Batch {
+            id: 3,
+            reconciled: false,
+            total_shares: Uint128::new(1500),
+            uluna_unclaimed: Uint128::new(1500),
+            est_unbond_end_time: 30000,
+        },
+        Batch {
+            id: 4,
+            reconciled: false,
+            total_shares: Uint128::new(1500),
+            uluna_unclaimed: Uint128::new(1500),
+            est_unbond_end_time: 40000, // not yet finished unbonding, ignored
+        },
+    ];
+
+    for previous_batch in &previous_batches {
+        state
+            .previous_batches
+            .save(deps.as_mut().storage, previous_batch.id, previous_batch)
+            .unwrap();
+    }
+
+    state.unlocked_coins.save(deps.as_mut().storage, &vec![Coin::new(1000, "uluna")]).unwrap();
+
+    deps.querier.set_bank_balances(&[Coin::new(3500, "uluna")]);
+
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(35000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap();
+    assert_eq!(
+        batch,
+        Batch {
+            id: 2,
+            reconciled: true,
+            total_shares: Uint128::new(1000),
+            uluna_unclaimed: Uint128::new(1000),
+            est_unbond_end_time: 20000,
+        }
+    );
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 3u64).unwrap();
+    assert_eq!(
+        batch,
+        Batch {
+            id: 3,
+            reconciled: true,
+            total_shares: Uint128::new(1500),
+            uluna_unclaimed: Uint128::new(1500),
+            est_unbond_end_time: 30000,
+        }
+    );
+
+    // Batches 1 and 4 should not have changed
+    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(batch, previous_batches[0]);
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 4u64).unwrap();
+    assert_eq!(batch, previous_batches[3]);
+}
+
+#[test]
+fn handling_slashing() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    for (validator, amount) in [("alice", 400000u128), ("bob", 300000), ("charlie", 300000)] {
+        state
+            .last_delegations
+            .save(deps.as_mut().storage, validator, &Uint128::new(amount))
+            .unwrap();
+    }
+
+    // Alice got slashed by 20000 uluna since the last harvest
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 380000),
+        Delegation::new("bob", 300000),
+        Delegation::new("charlie", 300000),
+    ]);
+
+    let previous_batches = vec![
+        Batch {
+            id: 1,
+            reconciled: true,
+            total_shares: Uint128::new(50000),
+            uluna_unclaimed: Uint128::new(50000),
+            est_unbond_end_time: 10000,
+        },
+        Batch {
+            id: 2,
+            reconciled: false,
+            total_shares: Uint128::new(1000),
+            uluna_unclaimed: Uint128::new(1000),
+            est_unbond_end_time: 20000, // already finished unbonding, not exposed
+        },
+        Batch {
+            id: 3,
+            reconciled: false,
+            total_shares: Uint128::new(100000),
+            uluna_unclaimed: Uint128::new(100000),
+            est_unbond_end_time: 40000,
+        },
+        Batch {
+            id: 4,
+            reconciled: false,
+            total_shares: Uint128::new(150000),
+            uluna_unclaimed: Uint128::new(150000),
+            est_unbond_end_time: 50000,
+        },
+    ];
+
+    for previous_batch in &previous_batches {
+        state
+            .previous_batches
+            .save(deps.as_mut().storage, previous_batch.id, previous_batch)
+            .unwrap();
+    }
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(25000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Harvest {},
+    )
+    .unwrap();
+
+    // uluna at risk: 1000000 bonded + 250000 unbonding = 1250000
+    // batch 3: 20000 * 100000 / 1250000 = 1600
+    // batch 4: 20000 * 150000 / 1250000 = 2400
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/slashed")
+            .add_attribute("time", "25000")
+            .add_attribute("height", "12345")
+            .add_attribute("losses", "alice:20000")
+            .add_attribute("uluna_slashed", "20000")
+            .add_attribute("ids", "3,4")
+            .add_attribute("uluna_deducted", "4000")]
+    );
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 3u64).unwrap();
+    assert_eq!(batch.uluna_unclaimed, Uint128::new(98400));
+    let batch = state.previous_batches.load(deps.as_ref().storage, 4u64).unwrap();
+    assert_eq!(batch.uluna_unclaimed, Uint128::new(147600));
+    let batch = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap();
+    assert_eq!(batch, previous_batches[1]);
+
+    let last = state.last_delegations.load(deps.as_ref().storage, "alice").unwrap();
+    assert_eq!(last, Uint128::new(380000));
+    let recovery = state.slash_recovery.load(deps.as_ref().storage).unwrap();
+    assert_eq!(recovery, Uint128::new(4000));
+
+    // Harvesting again does not detect the same loss twice
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(26000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Harvest {},
+    )
+    .unwrap();
+    assert!(res.events.is_empty());
+
+    // Losses within the rounding of delegation shares are not taken as slashing:
+    // 0.001% of 380000 = 3
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 379997),
+        Delegation::new("bob", 300000),
+        Delegation::new("charlie", 300000),
+    ]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(26000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Harvest {},
+    )
+    .unwrap();
+    assert!(res.events.is_empty());
+
+    let last = state.last_delegations.load(deps.as_ref().storage, "alice").unwrap();
+    assert_eq!(last, Uint128::new(379997));
+    let recovery = state.slash_recovery.load(deps.as_ref().storage).unwrap();
+    assert_eq!(recovery, Uint128::new(4000));
+
+    // The unbonding entries were not slashed, so the contract receives the full amounts. The
+    // surplus goes back to the bonded pool, while batch 1 (not yet withdrawn) is left untouched.
+    deps.querier.set_bank_balances(&[Coin::new(50000 + 1000 + 100000 + 150000, "uluna")]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(55000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/reconciled")
+            .add_attribute("ids", "2,3,4")
+            .add_attribute("uluna_deducted", "0")
+            .add_attribute("uluna_recovered", "4000")]
+    );
+
+    let unlocked_coins = state.unlocked_coins.load(deps.as_ref().storage).unwrap();
+    assert_eq!(unlocked_coins, vec![Coin::new(4000, "uluna")]);
+    let recovery = state.slash_recovery.load(deps.as_ref().storage).unwrap();
+    assert_eq!(recovery, Uint128::zero());
+}
+
+#[test]
+fn detecting_slashing_before_submitting_batch() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    for (validator, amount) in [("alice", 400000u128), ("bob", 300000), ("charlie", 300000)] {
+        state
+            .last_delegations
+            .save(deps.as_mut().storage, validator, &Uint128::new(amount))
+            .unwrap();
+    }
+
+    // Alice got slashed by 20000 uluna since the last harvest
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 380000),
+        Delegation::new("bob", 300000),
+        Delegation::new("charlie", 300000),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
+
+    state
+        .previous_batches
+        .save(
+            deps.as_mut().storage,
+            1u64,
+            &Batch {
+                id: 1,
+                reconciled: false,
+                total_shares: Uint128::new(100000),
+                uluna_unclaimed: Uint128::new(100000),
+                est_unbond_end_time: 1000000,
+            },
+        )
+        .unwrap();
+    state
+        .pending_batch
+        .save(
+            deps.as_mut().storage,
+            &PendingBatch {
+                id: 2,
+                ustake_to_burn: Uint128::new(100000),
+                est_unbond_start_time: 269200,
+            },
+        )
+        .unwrap();
+
+    // The loss is detected before the batch is submitted, so only batch 1 takes a share of it
+    //
+    // uluna at risk: 1000000 bonded + 100000 unbonding = 1100000
+    // batch 1: 20000 * 100000 / 1100000 = 1818
+    //
+    // The new batch is priced off the slashed delegations:
+    // uluna to unbond: 980000 * 100000 / 1000000 = 98000
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(269201),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::SubmitBatch {},
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.events[0],
+        Event::new("erishub/slashed")
+            .add_attribute("time", "269201")
+            .add_attribute("height", "12345")
+            .add_attribute("losses", "alice:20000")
+            .add_attribute("uluna_slashed", "20000")
+            .add_attribute("ids", "1")
+            .add_attribute("uluna_deducted", "1818")
+    );
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(batch.uluna_unclaimed, Uint128::new(98182));
+    let batch = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap();
+    assert_eq!(batch.uluna_unclaimed, Uint128::new(98000));
+
+    // Target: (980000 - 98000) / 3 = 294000
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 294000),
+        Delegation::new("bob", 294000),
+        Delegation::new("charlie", 294000),
+    ]);
+
+    // Harvesting afterwards does not socialize the same loss onto the new batch again
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(270000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Harvest {},
+    )
+    .unwrap();
+    assert!(res.events.is_empty());
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(batch.uluna_unclaimed, Uint128::new(98182));
+    let batch = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap();
+    assert_eq!(batch.uluna_unclaimed, Uint128::new(98000));
+    assert!(!state.slash_deductions.has(deps.as_ref().storage, 2u64));
+    let recovery = state.slash_recovery.load(deps.as_ref().storage).unwrap();
+    assert_eq!(recovery, Uint128::new(1818));
+}
+
+#[test]
+fn reconciling_slashed_unbonding_entries() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    for (validator, amount) in [("alice", 400000u128), ("bob", 300000), ("charlie", 300000)] {
+        state
+            .last_delegations
+            .save(deps.as_mut().storage, validator, &Uint128::new(amount))
+            .unwrap();
+    }
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 380000),
+        Delegation::new("bob", 300000),
+        Delegation::new("charlie", 300000),
+    ]);
+    state
+        .previous_batches
+        .save(
+            deps.as_mut().storage,
+            1u64,
+            &Batch {
+                id: 1,
+                reconciled: false,
+                total_shares: Uint128::new(100000),
+                uluna_unclaimed: Uint128::new(100000),
+                est_unbond_end_time: 30000,
+            },
+        )
+        .unwrap();
+
+    // uluna at risk: 1000000 bonded + 100000 unbonding = 1100000
+    // batch 1: 20000 * 100000 / 1100000 = 1818
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(25000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Harvest {},
+    )
+    .unwrap();
+
+    let recovery = state.slash_recovery.load(deps.as_ref().storage).unwrap();
+    assert_eq!(recovery, Uint128::new(1818));
+
+    // The unbonding entries lost 3000 uluna. The 1818 deducted earlier count towards that loss, so
+    // only the remaining 1182 are deducted, and nothing is left to be recovered
+    deps.querier.set_bank_balances(&[Coin::new(97000, "uluna")]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(35000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/reconciled")
+            .add_attribute("ids", "1")
+            .add_attribute("uluna_deducted", "1182")]
+    );
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(batch.uluna_unclaimed, Uint128::new(97000));
+    let recovery = state.slash_recovery.load(deps.as_ref().storage).unwrap();
+    assert_eq!(recovery, Uint128::zero());
+    assert!(!state.slash_deductions.has(deps.as_ref().storage, 1u64));
+
+    // An unrelated surplus while reconciling a later batch is not taken as recovered slashing
+    state
+        .previous_batches
+        .save(
+            deps.as_mut().storage,
+            2u64,
+            &Batch {
+                id: 2,
+                reconciled: false,
+                total_shares: Uint128::new(1000),
+                uluna_unclaimed: Uint128::new(1000),
+                est_unbond_end_time: 40000,
+            },
+        )
+        .unwrap();
+    deps.querier.set_bank_balances(&[Coin::new(97000 + 1000 + 500, "uluna")]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(45000),
+        mock_info("worker", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/reconciled")
+            .add_attribute("ids", "2")
+            .add_attribute("uluna_deducted", "0")]
+    );
+
+    let unlocked_coins = state.unlocked_coins.load(deps.as_ref().storage).unwrap();
+    assert_eq!(unlocked_coins, vec![]);
+}
+
+#[test]
+fn paying_keepers() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 333334),
+        Delegation::new("bob", 333333),
+        Delegation::new("charlie", 333333),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
+    deps.querier.set_bank_balances(&[Coin::new(1000, "uluna")]);
+
+    state
+        .pending_batch
+        .save(
+            deps.as_mut().storage,
+            &PendingBatch {
+                id: 2,
+                ustake_to_burn: Uint128::new(1000),
+                est_unbond_start_time: 20000,
+            },
+        )
+        .unwrap();
+    state
+        .previous_batches
+        .save(
+            deps.as_mut().storage,
+            1,
+            &Batch {
+                id: 1,
+                reconciled: false,
+                total_shares: Uint128::new(500),
+                uluna_unclaimed: Uint128::new(500),
+                est_unbond_end_time: 10000,
+            },
+        )
+        .unwrap();
+
+    let res: KeeperStatusResponse =
+        query_helper_env(deps.as_ref(), QueryMsg::KeeperStatus {}, 5000);
+    assert_eq!(
+        res,
+        KeeperStatusResponse {
+            harvest: false,
+            submit_batch: false,
+            reconcile: false,
+        }
+    );
+
+    let res: KeeperStatusResponse =
+        query_helper_env(deps.as_ref(), QueryMsg::KeeperStatus {}, 20001);
+    assert_eq!(
+        res,
+        KeeperStatusResponse {
+            harvest: false,
+            submit_batch: true,
+            reconcile: true,
+        }
+    );
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            keeper_reward: Some(KeeperReward {
+                reward_share: Decimal::from_ratio(6u128, 100u128),
+                max_reward: Uint128::new(10),
+                tip: Uint128::new(50),
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("'reward_share' greater than max"));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            keeper_reward: Some(KeeperReward {
+                reward_share: Decimal::from_ratio(5u128, 100u128),
+                max_reward: Uint128::new(10),
+                tip: Uint128::new(50),
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    // The keeper calling `Harvest` is forwarded to the reinvest callback
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20001),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Harvest {},
+    )
+    .unwrap();
+    assert_eq!(
+        res.messages[5],
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: MOCK_CONTRACT_ADDR.to_string(),
+            msg: to_binary(&ExecuteMsg::Callback(CallbackMsg::Reinvest {
+                keeper: Some(Addr::unchecked("keeper")),
+            }))
+            .unwrap(),
+            funds: vec![]
+        }))
+    );
+
+    // 5% of 1000 uluna is 50, capped at 10. The rest is bonded. The keeper receives 10 minus tax
+    state.unlocked_coins.save(deps.as_mut().storage, &vec![Coin::new(1000, "uluna")]).unwrap();
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20001),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(CallbackMsg::Reinvest {
+            keeper: Some(Addr::unchecked("keeper")),
+        }),
+    )
+    .unwrap();
+    assert_eq!(res.messages.len(), 3);
+    assert_eq!(res.messages[0], SubMsg::new(Delegation::new("bob", 990).to_cosmos_msg()));
+    assert_eq!(
+        res.messages[1],
+        SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+            to_address: "keeper".to_string(),
+            amount: vec![Coin::new(9, "uluna")]
+        }))
+    );
+    assert_eq!(
+        res.events[1],
+        Event::new("erishub/keeper_paid")
+            .add_attribute("keeper", "keeper")
+            .add_attribute("uluna_paid", "10")
+    );
+
+    // Submitting the batch pays the tip out of the unlocked uluna, capped by what is available
+    state.unlocked_coins.save(deps.as_mut().storage, &vec![Coin::new(30, "uluna")]).unwrap();
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20001),
+        mock_info("keeper", &[]),
+        ExecuteMsg::SubmitBatch {},
+    )
+    .unwrap();
+    assert_eq!(res.messages.len(), 6);
+    assert_eq!(
+        res.messages[4],
+        SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+            to_address: "keeper".to_string(),
+            amount: vec![Coin::new(29, "uluna")] // 30 minus tax
+        }))
+    );
+    assert_eq!(res.messages[5], check_received_coin(1000 - 30));
+
+    let unlocked_coins = state.unlocked_coins.load(deps.as_ref().storage).unwrap();
+    assert_eq!(unlocked_coins, vec![]);
+
+    // Nothing to tip from, so the keeper calling `Reconcile` is not paid
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20001),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Reconcile {},
+    )
+    .unwrap();
+    assert_eq!(res.messages.len(), 0);
+
+    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert!(batch.reconciled);
+
+    // A batch submitted by the contract itself as an unbond request is queued pays no tip
+    state
+        .pending_batch
+        .save(
+            deps.as_mut().storage,
+            &PendingBatch {
+                id: 3,
+                ustake_to_burn: Uint128::new(1000),
+                est_unbond_start_time: 20000,
+            },
+        )
+        .unwrap();
+    state.unlocked_coins.save(deps.as_mut().storage, &vec![Coin::new(30, "uluna")]).unwrap();
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20001),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::SubmitBatch {},
+    )
+    .unwrap();
+    assert_eq!(res.messages.len(), 5);
+    assert_eq!(res.messages[4], check_received_coin(1000));
+    assert!(!res.events.iter().any(|event| event.ty == "erishub/keeper_paid"));
+
+    let unlocked_coins = state.unlocked_coins.load(deps.as_ref().storage).unwrap();
+    assert_eq!(unlocked_coins, vec![Coin::new(30, "uluna")]);
+}
+
+#[test]
+fn withdrawing_unbonded() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    // We simulate a most general case:
+    // - batches 1 and 2 have finished unbonding
+    // - batch 3 have been submitted for unbonding but have not finished
+    // - batch 4 is still pending
+    let unbond_requests = vec![
+        UnbondRequest {
+            id: 1,
+            user: Addr::unchecked("user_1"),
+            shares: Uint128::new(23456),
+        },
+        UnbondRequest {
+            id: 1,
+            user: Addr::unchecked("user_3"),
+            shares: Uint128::new(69420),
+        },
+        UnbondRequest {
+            id: 2,
+            user: Addr::unchecked("user_1"),
+            shares: Uint128::new(34567),
+        },
+        UnbondRequest {
+            id: 3,
+            user: Addr::unchecked("user_1"),
+            shares: Uint128::new(45678),
+        },
+        UnbondRequest {
+            id: 4,
+            user: Addr::unchecked("user_1"),
+            shares: Uint128::new(56789),
+        },
+    ];
+
+    for unbond_request in &unbond_requests {
+        state
+            .unbond_requests
+            .save(
+                deps.as_mut().storage,
+                (unbond_request.id, &Addr::unchecked(unbond_request.user.clone())),
+                unbond_request,
+            )
+            .unwrap();
+    }
+
+    let previous_batches = vec![
+        Batch {
+            id: 1,
+            reconciled: true,
+            total_shares: Uint128::new(92876),
+            uluna_unclaimed: Uint128::new(95197), // 1.025 Luna per Stake
+            est_unbond_end_time: 10000,
+        },
+        Batch {
+            id: 2,
+            reconciled: true,
+            total_shares: Uint128::new(34567),
+            uluna_unclaimed: Uint128::new(35604), // 1.030 Luna per Stake
+            est_unbond_end_time: 20000,
+        },
+        Batch {
+            id: 3,
+            reconciled: false, // finished unbonding, but not reconciled; ignored
+            total_shares: Uint128::new(45678),
+            uluna_unclaimed: Uint128::new(47276), // 1.035 Luna per Stake
+            est_unbond_end_time: 20000,
+        },
+        Batch {
+            id: 4,
+            reconciled: true,
+            total_shares: Uint128::new(56789),
+            uluna_unclaimed: Uint128::new(59060), // 1.040 Luna per Stake
+            est_unbond_end_time: 30000, // reconciled, but not yet finished unbonding; ignored
+        },
+    ];
+
+    for previous_batch in &previous_batches {
+        state
+            .previous_batches
+            .save(deps.as_mut().storage, previous_batch.id, previous_batch)
+            .unwrap();
+    }
+
+    state
+        .pending_batch
+        .save(
+            deps.as_mut().storage,
+            &PendingBatch {
+                id: 4,
+                ustake_to_burn: Uint128::new(56789),
+                est_unbond_start_time: 100000,
+            },
+        )
+        .unwrap();
+
+    // Attempt to withdraw before any batch has completed unbonding. Should error
+    let err = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(5000),
+        mock_info("user_1", &[]),
+        ExecuteMsg::WithdrawUnbonded {
+            receiver: None,
+            batch_ids: None,
+            limit: None,
+        },
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("withdrawable amount is zero"));
+
+    // Attempt to withdraw once batches 1 and 2 have finished unbonding, but 3 has not yet
+    //
+    // Withdrawable from batch 1: 95,197 * 23,456 / 92,876 = 24,042
+    // Withdrawable from batch 2: 35,604
+    // Total withdrawable: 24,042 + 35,604 = 59,646 - 100 (fee)
+    //
+    // Batch 1 should be updated:
+    // Total shares: 92,876 - 23,456 = 69,420
+    // Unclaimed uluna: 95,197 - 24,042 = 71,155
+    //
+    // Batch 2 is completely withdrawn, should be purged from storage
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(25000),
+        mock_info("user_1", &[]),
+        ExecuteMsg::WithdrawUnbonded {
+            receiver: None,
+            batch_ids: None,
+            limit: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(
+        res.messages[0],
+        SubMsg {
+            id: 0,
+            msg: CosmosMsg::Bank(BankMsg::Send {
+                to_address: "user_1".to_string(),
+                amount: vec![Coin::new(59546, "uluna")]
+            }),
+            gas_limit: None,
+            reply_on: ReplyOn::Never
+        }
+    );
+
+    // Previous batches should have been updated
+    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(
+        batch,
+        Batch {
+            id: 1,
+            reconciled: true,
+            total_shares: Uint128::new(69420),
+            uluna_unclaimed: Uint128::new(71155),
+            est_unbond_end_time: 10000,
+        }
+    );
+
+    let err = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap_err();
+    assert_eq!(
+        err,
+        StdError::NotFound {
+            kind: "eris::hub::Batch".to_string()
+        }
+    );
+
+    // User 1's unbond requests in batches 1 and 2 should have been deleted
+    let err1 = state
+        .unbond_requests
+        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
+        .unwrap_err();
+    let err2 = state
+        .unbond_requests
+        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
+        .unwrap_err();
+
+    assert_eq!(
+        err1,
+        StdError::NotFound {
+            kind: "eris::hub::UnbondRequest".to_string()
+        }
+    );
+    assert_eq!(
+        err2,
+        StdError::NotFound {
+            kind: "eris::hub::UnbondRequest".to_string()
+        }
+    );
+
+    // User 3 attempt to withdraw; also specifying a receiver
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(25000),
+        mock_info("user_3", &[]),
+        ExecuteMsg::WithdrawUnbonded {
+            receiver: Some("user_2".to_string()),
+            batch_ids: None,
+            limit: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(
+        res.messages[0],
+        SubMsg {
+            id: 0,
+            msg: CosmosMsg::Bank(BankMsg::Send {
+                to_address: "user_2".to_string(),
+                amount: vec![Coin::new(71055, "uluna")]
+            }),
+            gas_limit: None,
+            reply_on: ReplyOn::Never
+        }
+    );
+
+    // Batch 1 and user 2's unbonding request should have been purged from storage
+    let err = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap_err();
+    assert_eq!(
+        err,
+        StdError::NotFound {
+            kind: "eris::hub::Batch".to_string()
+        }
+    );
+
+    let err = state
+        .unbond_requests
+        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_3")))
+        .unwrap_err();
+
+    assert_eq!(
+        err,
+        StdError::NotFound {
+            kind: "eris::hub::UnbondRequest".to_string()
+        }
+    );
+}
+
+#[test]
+fn withdrawing_unbonded_selectively() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    // User 1 has requests in batches 1, 2 and 3, all of which have been reconciled and have
+    // finished unbonding
+    for (id, shares) in [(1u64, 10000u128), (2, 20000), (3, 30000)] {
+        state
+            .unbond_requests
+            .save(
+                deps.as_mut().storage,
+                (id, &Addr::unchecked("user_1")),
+                &UnbondRequest {
+                    id,
+                    user: Addr::unchecked("user_1"),
+                    shares: Uint128::new(shares),
+                },
+            )
+            .unwrap();
+        state
+            .previous_batches
+            .save(
+                deps.as_mut().storage,
+                id,
+                &Batch {
+                    id,
+                    reconciled: true,
+                    total_shares: Uint128::new(shares),
+                    uluna_unclaimed: Uint128::new(shares * 102 / 100), // 1.02 Luna per Stake
+                    est_unbond_end_time: 10000,
+                },
+            )
+            .unwrap();
+    }
+
+    // Specifying a batch the user has no request in should fail
+    let err = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20000),
+        mock_info("user_1", &[]),
+        ExecuteMsg::WithdrawUnbonded {
+            receiver: None,
+            batch_ids: Some(vec![2, 5]),
+            limit: None,
+        },
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("no unbond request found in batch 5"));
+
+    // Withdraw batch 2 only; duplicate ids are only withdrawn once
+    // Withdrawable: 20,400 - 100 (fee) = 20,300
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20000),
+        mock_info("user_1", &[]),
+        ExecuteMsg::WithdrawUnbonded {
+            receiver: None,
+            batch_ids: Some(vec![2, 2]),
+            limit: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "user_1".to_string(),
+            amount: vec![Coin::new(20300, "uluna")]
+        })
+    );
+    assert_eq!(res.events[0].attributes[2], attr("ids", "2"));
+
+    assert!(state.previous_batches.may_load(deps.as_ref().storage, 2u64).unwrap().is_none());
+    assert!(state
+        .unbond_requests
+        .may_load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
+        .unwrap()
+        .is_some());
+
+    // Withdraw with a limit of one batch; the oldest batch is withdrawn first
+    // Withdrawable: 10,200 - 100 (fee) = 10,100
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20000),
+        mock_info("user_1", &[]),
+        ExecuteMsg::WithdrawUnbonded {
+            receiver: None,
+            batch_ids: None,
+            limit: Some(1),
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "user_1".to_string(),
+            amount: vec![Coin::new(10100, "uluna")]
+        })
+    );
+    assert_eq!(res.events[0].attributes[2], attr("ids", "1"));
+
+    // The remaining batch is withdrawn by a call without a limit
+    // Withdrawable: 30,600 - 100 (fee) = 30,500
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20000),
+        mock_info("user_1", &[]),
+        ExecuteMsg::WithdrawUnbonded {
+            receiver: None,
+            batch_ids: None,
+            limit: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "user_1".to_string(),
+            amount: vec![Coin::new(30500, "uluna")]
+        })
+    );
+    assert_eq!(res.events[0].attributes[2], attr("ids", "3"));
+}
+
+#[test]
+fn transferring_unbond_requests() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    state
+        .unbond_requests
+        .save(
+            deps.as_mut().storage,
+            (1u64, &Addr::unchecked("user_1")),
+            &UnbondRequest {
+                id: 1,
+                user: Addr::unchecked("user_1"),
+                shares: Uint128::new(1000),
+            },
+        )
+        .unwrap();
+
+    // Transferring from a batch without a request should fail
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[]),
+        ExecuteMsg::TransferUnbondRequest {
+            id: 2,
+            shares: None,
+            recipient: "user_2".to_string(),
+        },
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("no unbond request found in batch 2"));
+
+    // Transferring more shares than owned should fail
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[]),
+        ExecuteMsg::TransferUnbondRequest {
             id: 1,
-            ustake_to_burn: Uint128::new(92876), // 23,456 + 69,420
-            est_unbond_start_time: 269200
+            shares: Some(Uint128::new(1001)),
+            recipient: "user_2".to_string(),
+        },
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("cannot transfer 1001 shares, only 1000 available"));
+
+    // Transfer part of the shares
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[]),
+        ExecuteMsg::TransferUnbondRequest {
+            id: 1,
+            shares: Some(Uint128::new(400)),
+            recipient: "user_2".to_string(),
+        },
+    )
+    .unwrap();
+
+    let res: Vec<UnbondRequestsByUserResponseItem> = query_helper(
+        deps.as_ref(),
+        QueryMsg::UnbondRequestsByUser {
+            user: "user_1".to_string(),
+            start_after: None,
+            limit: None,
+        },
+    );
+    assert_eq!(
+        res,
+        vec![UnbondRequestsByUserResponseItem {
+            id: 1,
+            shares: Uint128::new(600)
+        }]
+    );
+
+    let res: Vec<UnbondRequestsByUserResponseItem> = query_helper(
+        deps.as_ref(),
+        QueryMsg::UnbondRequestsByUser {
+            user: "user_2".to_string(),
+            start_after: None,
+            limit: None,
+        },
+    );
+    assert_eq!(
+        res,
+        vec![UnbondRequestsByUserResponseItem {
+            id: 1,
+            shares: Uint128::new(400)
+        }]
+    );
+
+    // Transfer the remaining shares; user 1's request should be purged from storage
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[]),
+        ExecuteMsg::TransferUnbondRequest {
+            id: 1,
+            shares: None,
+            recipient: "user_2".to_string(),
+        },
+    )
+    .unwrap();
+
+    let res: Vec<UnbondRequestsByUserResponseItem> = query_helper(
+        deps.as_ref(),
+        QueryMsg::UnbondRequestsByUser {
+            user: "user_1".to_string(),
+            start_after: None,
+            limit: None,
+        },
+    );
+    assert_eq!(res, vec![]);
+
+    let request = state
+        .unbond_requests
+        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_2")))
+        .unwrap();
+    assert_eq!(
+        request,
+        UnbondRequest {
+            id: 1,
+            user: Addr::unchecked("user_2"),
+            shares: Uint128::new(1000),
         }
     );
 }
 
 #[test]
-fn submitting_batch() {
+fn rebalancing_with_redelegation_cooldowns() {
     let mut deps = setup_test();
     let state = State::default();
 
-    // utoken bonded: 1,037,345
-    // ustake supply: 1,012,043
-    // utoken per ustake: 1.025
     deps.querier.set_staking_delegations(&[
-        Delegation::new("alice", 345782),
-        Delegation::new("bob", 345782),
-        Delegation::new("charlie", 345781),
+        Delegation::new("alice", 600000),
+        Delegation::new("bob", 100000),
+        Delegation::new("charlie", 325000),
     ]);
-    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1012043);
 
-    // We continue from the contract state at the end of the last test
-    let unbond_requests = vec![
-        UnbondRequest {
-            id: 1,
-            user: Addr::unchecked("user_1"),
-            shares: Uint128::new(23456),
+    // Target: 1025000 / 3 = 341666, remainder 2 for Alice and Bob. The maximum number of
+    // redelegations from Alice to Bob is in progress, so only Charlie receives his share
+    state
+        .pending_redelegations
+        .save(deps.as_mut().storage, ("alice", "bob"), &vec![20000; 7])
+        .unwrap();
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(10000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Rebalance {
+            max_moves: None,
         },
-        UnbondRequest {
-            id: 1,
-            user: Addr::unchecked("user_3"),
-            shares: Uint128::new(69420),
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 2);
+    assert_eq!(
+        res.messages[0],
+        SubMsg::new(Redelegation::new("alice", "charlie", 16666).to_cosmos_msg()),
+    );
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/rebalanced")
+            .add_attribute("uluna_moved", "16666")
+            .add_attribute("uluna_skipped", "241667")]
+    );
+    assert_eq!(
+        state.pending_redelegations.load(deps.as_ref().storage, ("alice", "charlie")).unwrap(),
+        vec![10000 + 1814400]
+    );
+
+    // Once these redelegations complete, Bob receives his share
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(30000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Rebalance {
+            max_moves: None,
         },
-    ];
+    )
+    .unwrap();
 
-    for unbond_request in &unbond_requests {
-        state
-            .unbond_requests
-            .save(
-                deps.as_mut().storage,
-                (unbond_request.id, &Addr::unchecked(unbond_request.user.clone())),
-                unbond_request,
-            )
-            .unwrap();
-    }
+    assert_eq!(res.messages.len(), 3);
+    assert_eq!(
+        res.messages[0],
+        SubMsg::new(Redelegation::new("alice", "bob", 241667).to_cosmos_msg()),
+    );
+    assert_eq!(
+        state.pending_redelegations.load(deps.as_ref().storage, ("alice", "bob")).unwrap(),
+        vec![30000 + 1814400]
+    );
 
-    state
-        .pending_batch
-        .save(
-            deps.as_mut().storage,
-            &PendingBatch {
-                id: 1,
-                ustake_to_burn: Uint128::new(92876), // 23,456 + 69,420
-                est_unbond_start_time: 269200,
-            },
+    // Stake received by Bob can not be redelegated again until the redelegation completes
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 300000),
+        Delegation::new("bob", 400000),
+        Delegation::new("charlie", 325000),
+    ]);
+    sync_last_delegations(&mut deps);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(40000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Rebalance {
+            max_moves: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 0);
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/rebalanced")
+            .add_attribute("uluna_moved", "0")
+            .add_attribute("uluna_skipped", "58333")]
+    );
+
+    // Nor can it be moved by removing or replacing Bob
+    let err = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(40000),
+        mock_info("owner", &[]),
+        ExecuteMsg::RemoveValidator {
+            validator: "bob".to_string(),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        StdError::generic_err(
+            "cannot redelegate from bob: a redelegation to bob is still in progress"
         )
-        .unwrap();
+    );
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(40000),
+        mock_info("owner", &[]),
+        ExecuteMsg::ReplaceValidator {
+            old: "bob".to_string(),
+            new: "dave".to_string(),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        StdError::generic_err(
+            "cannot redelegate from bob: a redelegation to bob is still in progress"
+        )
+    );
+
+    // Evicting Bob is postponed until the redelegation completes
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(40000),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            max_commission: Some(Decimal::percent(10)),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+    deps.querier.set_staking_validators(&[
+        ("alice", Decimal::percent(5)),
+        ("bob", Decimal::percent(20)),
+        ("charlie", Decimal::percent(5)),
+    ]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(40000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::EvictValidators {},
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 0);
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/validator_eviction_postponed")
+            .add_attribute("validator", "bob")
+            .add_attribute("reason", "commission")
+            .add_attribute("blocked", "a redelegation to bob is still in progress")]
+    );
+    let validators = state.validators.load(deps.as_ref().storage).unwrap();
+    assert_eq!(validators, vec!["alice".to_string(), "bob".to_string(), "charlie".to_string()]);
+}
+
+#[test]
+fn rebalancing_with_thresholds() {
+    let mut deps = setup_test();
+
+    // Target: 1025000 / 3 = 341666, remainder 2 for Alice and Bob
+    // Alice: 348000 - 341667 = 6333 above target
+    // Bob: 341667 - 300000 = 41667 below target
+    // Charlie: 377000 - 341666 = 35334 above target
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 348000),
+        Delegation::new("bob", 300000),
+        Delegation::new("charlie", 377000),
+    ]);
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            rebalance_config: Some(RebalanceConfig {
+                min_redelegation: Uint128::zero(),
+                tolerance: Decimal::percent(20),
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("'tolerance' greater than max"));
+
+    // Alice is within 2% of her target, so only Charlie's stake is moved
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            rebalance_config: Some(RebalanceConfig {
+                min_redelegation: Uint128::zero(),
+                tolerance: Decimal::percent(2),
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
 
-    // Anyone can invoke `submit_batch`. Here we continue from the previous test and assume it is
-    // invoked automatically as user 2 submits the unbonding request
-    //
-    // ustake to burn: 23,456 + 69,420 = 92,876
-    // utoken to unbond: 1,037,345 * 92,876 / 1,012,043 = 95,197
-    //
-    // Target: (1,037,345 - 95,197) / 3 = 314,049
-    // Remainer: 1
-    // Alice:   345,782 - (314,049 + 1) = 31,732
-    // Bob:     345,782 - (314,049 + 0) = 31,733
-    // Charlie: 345,781 - (314,049 + 0) = 31,732
     let res = execute(
         deps.as_mut(),
-        mock_env_at_timestamp(269201),
-        mock_info(MOCK_CONTRACT_ADDR, &[]),
-        ExecuteMsg::SubmitBatch {},
+        mock_env(),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Rebalance {
+            max_moves: None,
+        },
     )
     .unwrap();
 
-    assert_eq!(res.messages.len(), 5);
-    assert_eq!(res.messages[0], SubMsg::new(Undelegation::new("alice", 31732).to_cosmos_msg()));
-    assert_eq!(res.messages[1], SubMsg::new(Undelegation::new("bob", 31733).to_cosmos_msg()));
-    assert_eq!(res.messages[2], SubMsg::new(Undelegation::new("charlie", 31732).to_cosmos_msg()));
+    assert_eq!(res.messages.len(), 2);
     assert_eq!(
-        res.messages[3],
-        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: STAKE_DENOM.to_string(),
-            msg: to_binary(&Cw20ExecuteMsg::Burn {
-                amount: Uint128::new(92876)
-            })
-            .unwrap(),
-            funds: vec![]
-        }))
+        res.messages[0],
+        SubMsg::new(Redelegation::new("charlie", "bob", 35334).to_cosmos_msg()),
     );
-    assert_eq!(res.messages[4], check_received_coin(0));
 
-    // A new pending batch should have been created
-    let pending_batch = state.pending_batch.load(deps.as_ref().storage).unwrap();
+    // Redelegations below the minimum are not made
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            rebalance_config: Some(RebalanceConfig {
+                min_redelegation: Uint128::new(10000),
+                tolerance: Decimal::zero(),
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    let res: ConfigResponse = query_helper(deps.as_ref(), QueryMsg::Config {});
     assert_eq!(
-        pending_batch,
-        PendingBatch {
-            id: 2,
-            ustake_to_burn: Uint128::zero(),
-            est_unbond_start_time: 528401 // 269,201 + 259,200
+        res.rebalance_config,
+        RebalanceConfig {
+            min_redelegation: Uint128::new(10000),
+            tolerance: Decimal::zero(),
         }
     );
 
-    // Previous batch should have been updated
-    let previous_batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Rebalance {
+            max_moves: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 2);
     assert_eq!(
-        previous_batch,
-        Batch {
-            id: 1,
-            reconciled: false,
-            total_shares: Uint128::new(92876),
-            uluna_unclaimed: Uint128::new(95197),
-            est_unbond_end_time: 2083601 // 269,201 + 1,814,400
-        }
+        res.messages[0],
+        SubMsg::new(Redelegation::new("charlie", "bob", 35334).to_cosmos_msg()),
     );
 
-    let res: StateResponse = query_helper_env(deps.as_ref(), QueryMsg::State {}, 2083600);
+    // Without thresholds, both Alice and Charlie redelegate to Bob. With `max_moves`, only the
+    // largest redelegation is made
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            rebalance_config: Some(RebalanceConfig::default()),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Rebalance {
+            max_moves: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 3);
     assert_eq!(
-        res,
-        StateResponse {
-            total_ustake: Uint128::from(1012043u128),
-            total_uluna: Uint128::from(1037345u128),
-            exchange_rate: Decimal::from_ratio(1037345u128, 1012043u128),
-            unlocked_coins: vec![],
-            unbonding: Uint128::from(95197u128),
-            available: Uint128::zero(),
-            tvl_uluna: Uint128::from(95197u128 + 1037345u128),
+        res.messages[0],
+        SubMsg::new(Redelegation::new("alice", "bob", 6333).to_cosmos_msg()),
+    );
+    assert_eq!(
+        res.messages[1],
+        SubMsg::new(Redelegation::new("charlie", "bob", 35334).to_cosmos_msg()),
+    );
+
+    // The redelegations above are not reflected in the mock delegations
+    sync_last_delegations(&mut deps);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Rebalance {
+            max_moves: Some(1),
         },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 2);
+    assert_eq!(
+        res.messages[0],
+        SubMsg::new(Redelegation::new("charlie", "bob", 35334).to_cosmos_msg()),
+    );
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/rebalanced").add_attribute("uluna_moved", "35334")]
     );
 }
 
 #[test]
-fn reconciling() {
+fn adding_validator() {
     let mut deps = setup_test();
     let state = State::default();
 
-    let previous_batches = vec![
-        Batch {
-            id: 1,
-            reconciled: true,
-            total_shares: Uint128::new(92876),
-            uluna_unclaimed: Uint128::new(95197), // 1.025 Luna per Stake
-            est_unbond_end_time: 10000,
-        },
-        Batch {
-            id: 2,
-            reconciled: false,
-            total_shares: Uint128::new(1345),
-            uluna_unclaimed: Uint128::new(1385), // 1.030 Luna per Stake
-            est_unbond_end_time: 20000,
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("jake", &[]),
+        ExecuteMsg::AddValidator {
+            validator: "dave".to_string(),
         },
-        Batch {
-            id: 3,
-            reconciled: false,
-            total_shares: Uint128::new(1456),
-            uluna_unclaimed: Uint128::new(1506), // 1.035 Luna per Stake
-            est_unbond_end_time: 30000,
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("unauthorized: sender is not owner"));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::AddValidator {
+            validator: "alice".to_string(),
         },
-        Batch {
-            id: 4,
-            reconciled: false,
-            total_shares: Uint128::new(1567),
-            uluna_unclaimed: Uint128::new(1629), // 1.040 Luna per Stake
-            est_unbond_end_time: 40000,          // not yet finished unbonding, ignored
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("validator is already whitelisted"));
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::AddValidator {
+            validator: "dave".to_string(),
         },
-    ];
+    )
+    .unwrap();
 
-    for previous_batch in &previous_batches {
-        state
-            .previous_batches
-            .save(deps.as_mut().storage, previous_batch.id, previous_batch)
-            .unwrap();
-    }
+    assert_eq!(res.messages.len(), 0);
 
-    state
-        .unlocked_coins
-        .save(
-            deps.as_mut().storage,
-            &vec![
-                Coin::new(10000, "uluna"),
-                Coin::new(234, "ukrw"),
-                Coin::new(345, "uusd"),
-                Coin::new(
-                    69420,
-                    "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B",
-                ),
-            ],
-        )
-        .unwrap();
+    let validators = state.validators.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        validators,
+        vec![
+            String::from("alice"),
+            String::from("bob"),
+            String::from("charlie"),
+            String::from("dave")
+        ],
+    );
+}
 
-    deps.querier.set_bank_balances(&[
-        Coin::new(12345, "uluna"),
-        Coin::new(234, "ukrw"),
-        Coin::new(345, "uusd"),
-        Coin::new(69420, "ibc/0471F1C4E7AFD3F07702BEF6DC365268D64570F7C1FDC98EA6098DD6DE59817B"),
+#[test]
+fn removing_validator() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
     ]);
 
-    execute(
+    let err = execute(
         deps.as_mut(),
-        mock_env_at_timestamp(35000),
-        mock_info("worker", &[]),
-        ExecuteMsg::Reconcile {},
+        mock_env(),
+        mock_info("jake", &[]),
+        ExecuteMsg::RemoveValidator {
+            validator: "charlie".to_string(),
+        },
     )
-    .unwrap();
+    .unwrap_err();
 
-    // Expected received: batch 2 + batch 3 = 1385 + 1506 = 2891
-    // Expected unlocked: 10000
-    // Expected: 12891
-    // Actual: 12345
-    // Shortfall: 12891 - 12345 = 456
-    //
-    // uluna per batch: 546 / 2 = 273
-    // remainder: 0
-    // batch 2: 1385 - 273 = 1112
-    // batch 3: 1506 - 273 = 1233
-    let batch = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap();
+    assert_eq!(err.to_string(), "Generic error: unauthorized: sender is not owner".to_string());
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::RemoveValidator {
+            validator: "dave".to_string(),
+        },
+    )
+    .unwrap_err();
+
+    assert_eq!(err.to_string(), "Generic error: validator is not already whitelisted".to_string());
+
+    // Target: (341667 + 341667 + 341666) / 2 = 512500
+    // Remainder: 0
+    // Alice:   512500 + 0 - 341667 = 170833
+    // Bob:     512500 + 0 - 341667 = 170833
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::RemoveValidator {
+            validator: "charlie".to_string(),
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 3);
     assert_eq!(
-        batch,
-        Batch {
-            id: 2,
-            reconciled: true,
-            total_shares: Uint128::new(1345),
-            uluna_unclaimed: Uint128::new(1112), // 1385 - 273
-            est_unbond_end_time: 20000,
-        }
+        res.messages[0],
+        SubMsg::new(Redelegation::new("charlie", "alice", 170833).to_cosmos_msg()),
     );
-
-    let batch = state.previous_batches.load(deps.as_ref().storage, 3u64).unwrap();
     assert_eq!(
-        batch,
-        Batch {
-            id: 3,
-            reconciled: true,
-            total_shares: Uint128::new(1456),
-            uluna_unclaimed: Uint128::new(1233), // 1506 - 273
-            est_unbond_end_time: 30000,
-        }
+        res.messages[1],
+        SubMsg::new(Redelegation::new("charlie", "bob", 170833).to_cosmos_msg()),
     );
+    assert_eq!(res.messages[2], check_received_coin(0));
 
-    // Batches 1 and 4 should not have changed
-    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
-    assert_eq!(batch, previous_batches[0]);
-
-    let batch = state.previous_batches.load(deps.as_ref().storage, 4u64).unwrap();
-    assert_eq!(batch, previous_batches[3]);
+    let validators = state.validators.load(deps.as_ref().storage).unwrap();
+    assert_eq!(validators, vec![String::from("alice"), String::from("bob")],);
 }
 
 #[test]
-fn reconciling_even_when_everything_ok() {
+fn replacing_validator() {
     let mut deps = setup_test();
     let state = State::default();
 
-    let previous_batches = vec![
-        Batch {
-            id: 1,
-            reconciled: true,
-            total_shares: Uint128::new(100000),
-            uluna_unclaimed: Uint128::new(100000),
-            est_unbond_end_time: 10000,
-        },
-        Batch {
-            id: 2,
-            reconciled: false,
-            total_shares: Uint128::new(1000),
-            uluna_unclaimed: Uint128::new(1000),
-            est_unbond_end_time: 20000,
-        },
-        Batch {
-            id: 3,
-            reconciled: false,
-            total_shares: Uint128::new(1500),
-            uluna_unclaimed: Uint128::new(1500),
-            est_unbond_end_time: 30000,
-        },
-        Batch {
-            id: 4,
-            reconciled: false,
-            total_shares: Uint128::new(1500),
-            uluna_unclaimed: Uint128::new(1500),
-            est_unbond_end_time: 40000, // not yet finished unbonding, ignored
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
+    ]);
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::SetValidatorWeights {
+            weights: vec![("bob".to_string(), 20000)],
         },
-    ];
+    )
+    .unwrap();
 
-    for previous_batch in &previous_batches {
-        state
-            .previous_batches
-            .save(deps.as_mut().storage, previous_batch.id, previous_batch)
-            .unwrap();
-    }
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("jake", &[]),
+        ExecuteMsg::ReplaceValidator {
+            old: "bob".to_string(),
+            new: "dave".to_string(),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("unauthorized: sender is not owner"));
 
-    state.unlocked_coins.save(deps.as_mut().storage, &vec![Coin::new(1000, "uluna")]).unwrap();
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::ReplaceValidator {
+            old: "bob".to_string(),
+            new: "alice".to_string(),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("validator is already whitelisted"));
 
-    deps.querier.set_bank_balances(&[Coin::new(3500, "uluna")]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::ReplaceValidator {
+            old: "dave".to_string(),
+            new: "eve".to_string(),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("validator is not already whitelisted"));
 
-    execute(
+    // Bob's whole stake is redelegated to Dave, who takes over his place and weight
+    let res = execute(
         deps.as_mut(),
-        mock_env_at_timestamp(35000),
-        mock_info("worker", &[]),
-        ExecuteMsg::Reconcile {},
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::ReplaceValidator {
+            old: "bob".to_string(),
+            new: "dave".to_string(),
+        },
     )
     .unwrap();
 
-    let batch = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap();
+    assert_eq!(res.messages.len(), 2);
     assert_eq!(
-        batch,
-        Batch {
-            id: 2,
-            reconciled: true,
-            total_shares: Uint128::new(1000),
-            uluna_unclaimed: Uint128::new(1000),
-            est_unbond_end_time: 20000,
-        }
+        res.messages[0],
+        SubMsg::new(Redelegation::new("bob", "dave", 341667).to_cosmos_msg()),
     );
-
-    let batch = state.previous_batches.load(deps.as_ref().storage, 3u64).unwrap();
+    assert_eq!(res.messages[1], check_received_coin(0));
     assert_eq!(
-        batch,
-        Batch {
-            id: 3,
-            reconciled: true,
-            total_shares: Uint128::new(1500),
-            uluna_unclaimed: Uint128::new(1500),
-            est_unbond_end_time: 30000,
-        }
+        res.events,
+        vec![Event::new("erishub/validator_replaced")
+            .add_attribute("old", "bob")
+            .add_attribute("new", "dave")
+            .add_attribute("uluna_redelegated", "341667")]
     );
 
-    // Batches 1 and 4 should not have changed
-    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
-    assert_eq!(batch, previous_batches[0]);
+    let validators = state.validators.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        validators,
+        vec![String::from("alice"), String::from("dave"), String::from("charlie")]
+    );
 
-    let batch = state.previous_batches.load(deps.as_ref().storage, 4u64).unwrap();
-    assert_eq!(batch, previous_batches[3]);
+    let weights = state.get_validator_weights(deps.as_ref().storage, &validators).unwrap();
+    assert_eq!(weights, vec![10000, 20000, 10000]);
+    assert_eq!(state.validator_weights.may_load(deps.as_ref().storage, "bob").unwrap(), None);
 }
 
 #[test]
-fn withdrawing_unbonded() {
+fn setting_validator_weights() {
     let mut deps = setup_test();
     let state = State::default();
 
-    // We simulate a most general case:
-    // - batches 1 and 2 have finished unbonding
-    // - batch 3 have been submitted for unbonding but have not finished
-    // - batch 4 is still pending
-    let unbond_requests = vec![
-        UnbondRequest {
-            id: 1,
-            user: Addr::unchecked("user_1"),
-            shares: Uint128::new(23456),
-        },
-        UnbondRequest {
-            id: 1,
-            user: Addr::unchecked("user_3"),
-            shares: Uint128::new(69420),
-        },
-        UnbondRequest {
-            id: 2,
-            user: Addr::unchecked("user_1"),
-            shares: Uint128::new(34567),
-        },
-        UnbondRequest {
-            id: 3,
-            user: Addr::unchecked("user_1"),
-            shares: Uint128::new(45678),
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("jake", &[]),
+        ExecuteMsg::SetValidatorWeights {
+            weights: vec![("alice".to_string(), 20000)],
         },
-        UnbondRequest {
-            id: 4,
-            user: Addr::unchecked("user_1"),
-            shares: Uint128::new(56789),
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("unauthorized: sender is not owner"));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::SetValidatorWeights {
+            weights: vec![("dave".to_string(), 20000)],
         },
-    ];
+    )
+    .unwrap_err();
 
-    for unbond_request in &unbond_requests {
-        state
-            .unbond_requests
-            .save(
-                deps.as_mut().storage,
-                (unbond_request.id, &Addr::unchecked(unbond_request.user.clone())),
-                unbond_request,
-            )
-            .unwrap();
-    }
+    assert_eq!(err, StdError::generic_err("validator dave is not whitelisted"));
 
-    let previous_batches = vec![
-        Batch {
-            id: 1,
-            reconciled: true,
-            total_shares: Uint128::new(92876),
-            uluna_unclaimed: Uint128::new(95197), // 1.025 Luna per Stake
-            est_unbond_end_time: 10000,
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::SetValidatorWeights {
+            weights: vec![("alice".to_string(), 0)],
         },
-        Batch {
-            id: 2,
-            reconciled: true,
-            total_shares: Uint128::new(34567),
-            uluna_unclaimed: Uint128::new(35604), // 1.030 Luna per Stake
-            est_unbond_end_time: 20000,
+    )
+    .unwrap_err();
+
+    assert_eq!(err, StdError::generic_err("validator weight must be greater than zero"));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::SetValidatorWeights {
+            weights: vec![("alice".to_string(), 20000)],
         },
-        Batch {
-            id: 3,
-            reconciled: false, // finished unbonding, but not reconciled; ignored
-            total_shares: Uint128::new(45678),
-            uluna_unclaimed: Uint128::new(47276), // 1.035 Luna per Stake
-            est_unbond_end_time: 20000,
+    )
+    .unwrap();
+
+    let res: ConfigResponse = query_helper(deps.as_ref(), QueryMsg::Config {});
+    assert_eq!(
+        res.validator_weights,
+        vec![
+            ("alice".to_string(), 20000),
+            ("bob".to_string(), 10000),
+            ("charlie".to_string(), 10000)
+        ]
+    );
+
+    // Alice has the largest delegation, but relative to her doubled weight it is the smallest one,
+    // so the full deposit goes to her
+    deps.querier.set_bank_balances(&[coin(12345, CONTRACT_DENOM)]);
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 600000),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[Coin::new(12345, CONTRACT_DENOM)]),
+        ExecuteMsg::Bond {
+            receiver: None,
+            referral: None,
+            min_ustake_out: None,
         },
-        Batch {
-            id: 4,
-            reconciled: true,
-            total_shares: Uint128::new(56789),
-            uluna_unclaimed: Uint128::new(59060), // 1.040 Luna per Stake
-            est_unbond_end_time: 30000, // reconciled, but not yet finished unbonding; ignored
+    )
+    .unwrap();
+
+    assert_eq!(res.messages[0], SubMsg::new(Delegation::new("alice", 12345).to_cosmos_msg()));
+
+    // The weight is dropped together with the validator
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::RemoveValidator {
+            validator: "alice".to_string(),
         },
-    ];
+    )
+    .unwrap();
 
-    for previous_batch in &previous_batches {
-        state
-            .previous_batches
-            .save(deps.as_mut().storage, previous_batch.id, previous_batch)
-            .unwrap();
-    }
+    let weight = state.validator_weights.may_load(deps.as_ref().storage, "alice").unwrap();
+    assert_eq!(weight, None);
+}
 
-    state
-        .pending_batch
-        .save(
-            deps.as_mut().storage,
-            &PendingBatch {
-                id: 4,
-                ustake_to_burn: Uint128::new(56789),
-                est_unbond_start_time: 100000,
-            },
-        )
-        .unwrap();
+#[test]
+fn evaluating_validators() {
+    let mut deps = setup_test();
+    let state = State::default();
 
-    // Attempt to withdraw before any batch has completed unbonding. Should error
-    let err = execute(
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 400000),
+        Delegation::new("bob", 400000),
+        Delegation::new("charlie", 200000),
+    ]);
+    deps.querier.set_staking_validators(&[
+        ("alice", Decimal::percent(5)),
+        ("bob", Decimal::percent(20)),
+        ("charlie", Decimal::percent(5)),
+    ]);
+
+    // Rewards accumulate since the harvest, except for Charlie, whose delegation changes later on
+    // and whose rewards are withdrawn by the staking module
+    execute(
         deps.as_mut(),
-        mock_env_at_timestamp(5000),
-        mock_info("user_1", &[]),
-        ExecuteMsg::WithdrawUnbonded {
+        mock_env_at_timestamp(10000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Harvest {},
+    )
+    .unwrap();
+
+    deps.querier.set_bank_balances(&[coin(1000, CONTRACT_DENOM)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(15000),
+        mock_info("user_1", &[Coin::new(1000, CONTRACT_DENOM)]),
+        ExecuteMsg::Bond {
             receiver: None,
+            referral: None,
+            min_ustake_out: None,
         },
     )
-    .unwrap_err();
+    .unwrap();
+    assert_eq!(res.messages[0], SubMsg::new(Delegation::new("charlie", 1000).to_cosmos_msg()));
+    deps.querier.set_bank_balances(&[]);
 
-    assert_eq!(err, StdError::generic_err("withdrawable amount is zero"));
+    deps.querier.set_staking_rewards(&[("alice", 4000), ("bob", 2000), ("charlie", 1000)]);
 
-    // Attempt to withdraw once batches 1 and 2 have finished unbonding, but 3 has not yet
-    //
-    // Withdrawable from batch 1: 95,197 * 23,456 / 92,876 = 24,042
-    // Withdrawable from batch 2: 35,604
-    // Total withdrawable: 24,042 + 35,604 = 59,646 - 100 (fee)
-    //
-    // Batch 1 should be updated:
-    // Total shares: 92,876 - 23,456 = 69,420
-    // Unclaimed uluna: 95,197 - 24,042 = 71,155
+    // Without a max commission, all active validators are kept and scored relative to the rewards
+    // per uluna delegated and second of the best validator
     //
-    // Batch 2 is completely withdrawn, should be purged from storage
+    // Alice:   4000 / 400000 / 10000 seconds = 0.000001
+    // Bob:     2000 / 400000 / 10000 seconds = 0.0000005
+    // Charlie: 1000 / 200000 / 5000 seconds = 0.000001
     let res = execute(
         deps.as_mut(),
-        mock_env_at_timestamp(25000),
-        mock_info("user_1", &[]),
-        ExecuteMsg::WithdrawUnbonded {
-            receiver: None,
-        },
+        mock_env_at_timestamp(20000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::EvaluateValidators {},
     )
     .unwrap();
 
-    assert_eq!(res.messages.len(), 1);
-    assert_eq!(
-        res.messages[0],
-        SubMsg {
-            id: 0,
-            msg: CosmosMsg::Bank(BankMsg::Send {
-                to_address: "user_1".to_string(),
-                amount: vec![Coin::new(59546, "uluna")]
-            }),
-            gas_limit: None,
-            reply_on: ReplyOn::Never
-        }
-    );
-
-    // Previous batches should have been updated
-    let batch = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap();
+    assert_eq!(res.messages.len(), 0);
     assert_eq!(
-        batch,
-        Batch {
-            id: 1,
-            reconciled: true,
-            total_shares: Uint128::new(69420),
-            uluna_unclaimed: Uint128::new(71155),
-            est_unbond_end_time: 10000,
-        }
+        res.events,
+        vec![Event::new("erishub/validators_evaluated")
+            .add_attribute("alice", "1")
+            .add_attribute("bob", "0.5")
+            .add_attribute("charlie", "1")]
     );
 
-    let err = state.previous_batches.load(deps.as_ref().storage, 2u64).unwrap_err();
+    let res: Vec<(String, ValidatorScore)> =
+        query_helper(deps.as_ref(), QueryMsg::ValidatorScores {});
     assert_eq!(
-        err,
-        StdError::NotFound {
-            kind: "eris::hub::Batch".to_string()
-        }
+        res[1],
+        (
+            "bob".to_string(),
+            ValidatorScore {
+                score: Decimal::percent(50),
+                commission: Decimal::percent(20),
+                active: true,
+                time: 20000,
+            }
+        )
     );
 
-    // User 1's unbond requests in batches 1 and 2 should have been deleted
-    let err1 = state
-        .unbond_requests
-        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
-        .unwrap_err();
-    let err2 = state
-        .unbond_requests
-        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_1")))
-        .unwrap_err();
+    // Bob charges more than the max commission and Charlie left the active set, so both are
+    // evicted and their stake is redelegated to Alice
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            max_commission: Some(Decimal::percent(10)),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
 
-    assert_eq!(
-        err1,
-        StdError::NotFound {
-            kind: "eris::hub::UnbondRequest".to_string()
-        }
-    );
-    assert_eq!(
-        err2,
-        StdError::NotFound {
-            kind: "eris::hub::UnbondRequest".to_string()
-        }
-    );
+    deps.querier
+        .set_staking_validators(&[("alice", Decimal::percent(5)), ("bob", Decimal::percent(20))]);
 
-    // User 3 attempt to withdraw; also specifying a receiver
     let res = execute(
         deps.as_mut(),
-        mock_env_at_timestamp(25000),
-        mock_info("user_3", &[]),
-        ExecuteMsg::WithdrawUnbonded {
-            receiver: Some("user_2".to_string()),
-        },
+        mock_env_at_timestamp(30000),
+        mock_info("keeper", &[]),
+        ExecuteMsg::EvaluateValidators {},
     )
     .unwrap();
 
-    assert_eq!(res.messages.len(), 1);
+    assert_eq!(res.messages.len(), 3);
     assert_eq!(
         res.messages[0],
-        SubMsg {
-            id: 0,
-            msg: CosmosMsg::Bank(BankMsg::Send {
-                to_address: "user_2".to_string(),
-                amount: vec![Coin::new(71055, "uluna")]
-            }),
-            gas_limit: None,
-            reply_on: ReplyOn::Never
-        }
+        SubMsg::new(Redelegation::new("bob", "alice", 400000).to_cosmos_msg()),
     );
-
-    // Batch 1 and user 2's unbonding request should have been purged from storage
-    let err = state.previous_batches.load(deps.as_ref().storage, 1u64).unwrap_err();
     assert_eq!(
-        err,
-        StdError::NotFound {
-            kind: "eris::hub::Batch".to_string()
-        }
+        res.messages[1],
+        SubMsg::new(Redelegation::new("charlie", "alice", 200000).to_cosmos_msg()),
+    );
+    assert_eq!(res.messages[2], check_received_coin(0));
+    assert_eq!(
+        res.events,
+        vec![
+            Event::new("erishub/validator_evicted")
+                .add_attribute("validator", "bob")
+                .add_attribute("reason", "commission")
+                .add_attribute("uluna_redelegated", "400000"),
+            Event::new("erishub/validator_evicted")
+                .add_attribute("validator", "charlie")
+                .add_attribute("reason", "inactive")
+                .add_attribute("uluna_redelegated", "200000"),
+            Event::new("erishub/validators_evaluated").add_attribute("alice", "1"),
+        ]
     );
 
-    let err = state
-        .unbond_requests
-        .load(deps.as_ref().storage, (1u64, &Addr::unchecked("user_3")))
-        .unwrap_err();
+    let validators = state.validators.load(deps.as_ref().storage).unwrap();
+    assert_eq!(validators, vec![String::from("alice")]);
 
+    let res: Vec<(String, ValidatorScore)> =
+        query_helper(deps.as_ref(), QueryMsg::ValidatorScores {});
     assert_eq!(
-        err,
-        StdError::NotFound {
-            kind: "eris::hub::UnbondRequest".to_string()
-        }
+        res,
+        vec![(
+            "alice".to_string(),
+            ValidatorScore {
+                score: Decimal::one(),
+                commission: Decimal::percent(5),
+                active: true,
+                time: 30000,
+            }
+        )]
     );
+
+    // The last validator can not be evicted
+    deps.querier.set_staking_validators(&[]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("keeper", &[]),
+        ExecuteMsg::EvaluateValidators {},
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("cannot evict all whitelisted validators"));
 }
 
 #[test]
-fn adding_validator() {
+fn enforcing_max_commission() {
     let mut deps = setup_test();
     let state = State::default();
 
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
+    ]);
+    deps.querier.set_staking_validators(&[
+        ("alice", Decimal::percent(5)),
+        ("bob", Decimal::percent(20)),
+        ("charlie", Decimal::percent(5)),
+        ("dave", Decimal::percent(30)),
+        ("eve", Decimal::percent(10)),
+    ]);
+
+    // Without a max commission, nobody can be evicted
     let err = execute(
         deps.as_mut(),
         mock_env(),
-        mock_info("jake", &[]),
-        ExecuteMsg::AddValidator {
-            validator: "dave".to_string(),
-        },
+        mock_info("keeper", &[]),
+        ExecuteMsg::EvictValidators {},
     )
     .unwrap_err();
+    assert_eq!(err, StdError::generic_err("no validator exceeds the max commission"));
 
-    assert_eq!(err, StdError::generic_err("unauthorized: sender is not owner"));
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            max_commission: Some(Decimal::percent(10)),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
 
+    // Validators exceeding the max commission can not be whitelisted
     let err = execute(
         deps.as_mut(),
         mock_env(),
         mock_info("owner", &[]),
         ExecuteMsg::AddValidator {
-            validator: "alice".to_string(),
+            validator: "dave".to_string(),
         },
     )
     .unwrap_err();
+    assert_eq!(
+        err,
+        StdError::generic_err(
+            "validator dave charges a commission of 0.3, more than the max of 0.1"
+        )
+    );
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::AddValidator {
+            validator: "eve".to_string(),
+        },
+    )
+    .unwrap();
+
+    // Rebalancing moves all stake away from Bob, who raised his commission after being whitelisted
+    // Target: 1025000 / 3 = 341666, remainder 2 for Alice and Charlie
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("keeper", &[]),
+        ExecuteMsg::Rebalance {
+            max_moves: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(res.messages.len(), 3);
+    assert_eq!(
+        res.messages[0],
+        SubMsg::new(Redelegation::new("bob", "charlie", 1).to_cosmos_msg()),
+    );
+    assert_eq!(
+        res.messages[1],
+        SubMsg::new(Redelegation::new("bob", "eve", 341666).to_cosmos_msg()),
+    );
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/rebalanced")
+            .add_attribute("uluna_moved", "341667")
+            .add_attribute("commission_exceeded", "bob")]
+    );
+
+    // Once Bob is drained, he has the smallest delegation, but deposits still skip him
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 0),
+        Delegation::new("charlie", 341667),
+        Delegation::new("eve", 341666),
+    ]);
+
+    let res: SimulateBondResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::SimulateBond {
+            uluna: Uint128::new(1000),
+        },
+    );
+    assert_eq!(res.validator, "eve");
+
+    deps.querier.set_bank_balances(&[coin(1000, CONTRACT_DENOM)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("user_1", &[Coin::new(1000, CONTRACT_DENOM)]),
+        ExecuteMsg::Bond {
+            receiver: None,
+            referral: None,
+            min_ustake_out: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(res.messages[0], SubMsg::new(Delegation::new("eve", 1000).to_cosmos_msg()));
 
-    assert_eq!(err, StdError::generic_err("validator is already whitelisted"));
+    deps.querier.set_bank_balances(&[]);
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
+    ]);
+    sync_last_delegations(&mut deps);
 
+    // Anyone can evict Bob from the whitelist
     let res = execute(
         deps.as_mut(),
         mock_env(),
-        mock_info("owner", &[]),
-        ExecuteMsg::AddValidator {
-            validator: "dave".to_string(),
-        },
+        mock_info("keeper", &[]),
+        ExecuteMsg::EvictValidators {},
     )
     .unwrap();
 
-    assert_eq!(res.messages.len(), 0);
+    assert_eq!(res.messages.len(), 3);
+    assert_eq!(
+        res.messages[0],
+        SubMsg::new(Redelegation::new("bob", "charlie", 1).to_cosmos_msg()),
+    );
+    assert_eq!(
+        res.messages[1],
+        SubMsg::new(Redelegation::new("bob", "eve", 341666).to_cosmos_msg()),
+    );
+    assert_eq!(res.messages[2], check_received_coin(0));
+    assert_eq!(
+        res.events,
+        vec![Event::new("erishub/validator_evicted")
+            .add_attribute("validator", "bob")
+            .add_attribute("reason", "commission")
+            .add_attribute("uluna_redelegated", "341667")]
+    );
 
     let validators = state.validators.load(deps.as_ref().storage).unwrap();
     assert_eq!(
         validators,
-        vec![
-            String::from("alice"),
-            String::from("bob"),
-            String::from("charlie"),
-            String::from("dave")
-        ],
+        vec![String::from("alice"), String::from("charlie"), String::from("eve")]
     );
-}
-
-#[test]
-fn removing_validator() {
-    let mut deps = setup_test();
-    let state = State::default();
-
-    deps.querier.set_staking_delegations(&[
-        Delegation::new("alice", 341667),
-        Delegation::new("bob", 341667),
-        Delegation::new("charlie", 341666),
-    ]);
 
     let err = execute(
         deps.as_mut(),
         mock_env(),
-        mock_info("jake", &[]),
-        ExecuteMsg::RemoveValidator {
-            validator: "charlie".to_string(),
-        },
+        mock_info("keeper", &[]),
+        ExecuteMsg::EvictValidators {},
     )
     .unwrap_err();
+    assert_eq!(err, StdError::generic_err("no validator exceeds the max commission"));
 
-    assert_eq!(err.to_string(), "Generic error: unauthorized: sender is not owner".to_string());
-
-    let err = execute(
+    // A max commission of 1 lifts the limit again
+    execute(
         deps.as_mut(),
         mock_env(),
         mock_info("owner", &[]),
-        ExecuteMsg::RemoveValidator {
-            validator: "dave".to_string(),
-        },
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            max_commission: Some(Decimal::one()),
+            ..Default::default()
+        })),
     )
-    .unwrap_err();
+    .unwrap();
 
-    assert_eq!(err.to_string(), "Generic error: validator is not already whitelisted".to_string());
+    let res: ConfigResponse = query_helper(deps.as_ref(), QueryMsg::Config {});
+    assert_eq!(res.max_commission, Decimal::one());
 
-    // Target: (341667 + 341667 + 341666) / 2 = 512500
-    // Remainder: 0
-    // Alice:   512500 + 0 - 341667 = 170833
-    // Bob:     512500 + 0 - 341667 = 170833
-    let res = execute(
+    execute(
         deps.as_mut(),
         mock_env(),
         mock_info("owner", &[]),
-        ExecuteMsg::RemoveValidator {
-            validator: "charlie".to_string(),
+        ExecuteMsg::AddValidator {
+            validator: "dave".to_string(),
         },
     )
     .unwrap();
-
-    assert_eq!(res.messages.len(), 3);
-    assert_eq!(
-        res.messages[0],
-        SubMsg::new(Redelegation::new("charlie", "alice", 170833).to_cosmos_msg()),
-    );
-    assert_eq!(
-        res.messages[1],
-        SubMsg::new(Redelegation::new("charlie", "bob", 170833).to_cosmos_msg()),
-    );
-    assert_eq!(res.messages[2], check_received_coin(0));
-
-    let validators = state.validators.load(deps.as_ref().storage).unwrap();
-    assert_eq!(validators, vec![String::from("alice"), String::from("bob")],);
 }
 
 #[test]
@@ -1451,7 +4962,11 @@ fn update_config() {
         config,
         FeeConfig {
             protocol_fee_contract: Addr::unchecked("fee"),
-            protocol_reward_fee: Decimal::from_ratio(1u128, 100u128)
+            protocol_reward_fee: Decimal::from_ratio(1u128, 100u128),
+            keeper_reward: None,
+            fee_recipients: vec![],
+            deposit_fee: Decimal::zero(),
+            withdraw_fee: Decimal::zero(),
         }
     );
 
@@ -1459,11 +4974,10 @@ fn update_config() {
         deps.as_mut(),
         mock_env(),
         mock_info("jake", &[]),
-        ExecuteMsg::UpdateConfig {
-            protocol_fee_contract: None,
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
             protocol_reward_fee: Some(Decimal::from_ratio(11u128, 100u128)),
-            swap_config: None,
-        },
+            ..Default::default()
+        })),
     )
     .unwrap_err();
     assert_eq!(err, StdError::generic_err("unauthorized: sender is not owner"));
@@ -1472,11 +4986,10 @@ fn update_config() {
         deps.as_mut(),
         mock_env(),
         mock_info("owner", &[]),
-        ExecuteMsg::UpdateConfig {
-            protocol_fee_contract: None,
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
             protocol_reward_fee: Some(Decimal::from_ratio(11u128, 100u128)),
-            swap_config: None,
-        },
+            ..Default::default()
+        })),
     )
     .unwrap_err();
     assert_eq!(err, StdError::generic_err("'protocol_reward_fee' greater than max"));
@@ -1485,14 +4998,20 @@ fn update_config() {
         deps.as_mut(),
         mock_env(),
         mock_info("owner", &[]),
-        ExecuteMsg::UpdateConfig {
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
             protocol_fee_contract: Some("fee-new".to_string()),
             protocol_reward_fee: Some(Decimal::from_ratio(10u128, 100u128)),
             swap_config: Some(vec![SwapConfig {
                 denom: "uusd".to_string(),
                 contract: Addr::unchecked("swap"),
+                max_spread: None,
+                hops: vec![],
+                min_return: None,
+                price_source: None,
+                kind: SwapKind::Pair,
             }]),
-        },
+            ..Default::default()
+        })),
     )
     .unwrap();
 
@@ -1504,6 +5023,10 @@ fn update_config() {
         FeeConfig {
             protocol_fee_contract: Addr::unchecked("fee-new"),
             protocol_reward_fee: Decimal::from_ratio(10u128, 100u128),
+            keeper_reward: None,
+            fee_recipients: vec![],
+            deposit_fee: Decimal::zero(),
+            withdraw_fee: Decimal::zero(),
         }
     );
 
@@ -1513,6 +5036,11 @@ fn update_config() {
         vec![SwapConfig {
             denom: "uusd".to_string(),
             contract: Addr::unchecked("swap"),
+            max_spread: None,
+            hops: vec![],
+            min_return: None,
+            price_source: None,
+            kind: SwapKind::Pair,
         }]
     );
 }
@@ -1521,6 +5049,329 @@ fn update_config() {
 // Queries
 //--------------------------------------------------------------------------------------------------
 
+#[test]
+fn querying_yield() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    // An old outlier, followed by the exchange rate growing 1% per day
+    for (day, rate) in [(50u64, "0.5"), (100, "0.99"), (101, "1.00"), (102, "1.01")] {
+        state
+            .exchange_history
+            .save(deps.as_mut().storage, day * DAY, &Decimal::from_str(rate).unwrap())
+            .unwrap();
+    }
+
+    // The default window of 30 days excludes the outlier
+    let res: YieldResponse = query_helper_env(
+        deps.as_ref(),
+        QueryMsg::Yield {
+            window_seconds: None,
+        },
+        102 * DAY,
+    );
+    assert_eq!(res.window_start, 72 * DAY);
+    assert_eq!(res.data_points, 3);
+    assert_eq!(
+        res.net,
+        Some(YieldFigures {
+            daily: Decimal::percent(1),
+            weekly: Decimal::percent(7),
+            apr: Decimal::percent(365),
+            apy: Decimal::percent(101).pow(365) - Decimal::one(),
+        })
+    );
+
+    // Gross yield before the 1% protocol reward fee: 0.01 / 0.99
+    let gross = res.gross.unwrap();
+    assert_eq!(gross.daily, Decimal::from_str("0.010101010101010101").unwrap());
+
+    // Over the last day only, the growth is relative to an average rate of 1.005
+    let res: YieldResponse = query_helper_env(
+        deps.as_ref(),
+        QueryMsg::Yield {
+            window_seconds: Some(DAY),
+        },
+        102 * DAY,
+    );
+    assert_eq!(res.data_points, 2);
+    assert_eq!(res.net.unwrap().daily, Decimal::from_str("0.009950248756218905").unwrap());
+
+    // A single exchange rate is not enough to estimate the yield
+    let res: YieldResponse = query_helper_env(
+        deps.as_ref(),
+        QueryMsg::Yield {
+            window_seconds: Some(0),
+        },
+        102 * DAY,
+    );
+    assert_eq!(res.data_points, 1);
+    assert_eq!(res.net, None);
+    assert_eq!(res.gross, None);
+}
+
+#[test]
+fn compacting_exchange_history() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    // An exchange rate every 12 hours over 20 days
+    for k in 0..=40u64 {
+        state
+            .exchange_history
+            .save(deps.as_mut().storage, k * DAY / 2, &Decimal::from_ratio(1000 + k, 1000u64))
+            .unwrap();
+    }
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20 * DAY),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            history_config: Some(HistoryConfig {
+                full_resolution_period: 2 * DAY,
+                daily_resolution_period: DAY,
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap_err();
+
+    assert_eq!(
+        err,
+        StdError::generic_err(
+            "'daily_resolution_period' must not be less than 'full_resolution_period'"
+        )
+    );
+
+    // Setting the retention policy compacts the existing history right away:
+    // - before day 10, the last exchange rate of each week is kept: 6.5 and 9.5
+    // - between day 10 and 18, the last exchange rate of each day is kept: 10.5 to 17.5
+    // - from day 18, all exchange rates are kept: 18 to 20
+    execute(
+        deps.as_mut(),
+        mock_env_at_timestamp(20 * DAY),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            history_config: Some(HistoryConfig {
+                full_resolution_period: 2 * DAY,
+                daily_resolution_period: 10 * DAY,
+            }),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    let res: ExchangeRatesResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::ExchangeRates {
+            start_after: None,
+            limit: Some(30),
+        },
+    );
+
+    let half_days = |resolution: HistoryResolution| {
+        res.exchange_rates
+            .iter()
+            .filter(|item| item.resolution == resolution)
+            .map(|item| item.time * 2 / DAY)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(half_days(HistoryResolution::Full), vec![40, 39, 38, 37, 36]);
+    assert_eq!(half_days(HistoryResolution::Daily), vec![35, 33, 31, 29, 27, 25, 23, 21]);
+    assert_eq!(half_days(HistoryResolution::Weekly), vec![19, 13]);
+    assert_eq!(
+        res.exchange_rates[0],
+        ExchangeRateItem {
+            time: 20 * DAY,
+            exchange_rate: Decimal::from_ratio(1040u128, 1000u128),
+            resolution: HistoryResolution::Full,
+            reason: ExchangeRateReason::Harvest,
+        }
+    );
+
+    // A day later, day 18 has become daily resolution and week 1 has been extended to day 11
+    state.compact_exchange_history(deps.as_mut().storage, 21 * DAY).unwrap();
+
+    let times = state
+        .exchange_history
+        .keys(deps.as_ref().storage, None, None, Order::Ascending)
+        .map(|time| time.unwrap() * 2 / DAY)
+        .collect::<Vec<_>>();
+    assert_eq!(times, vec![13, 21, 23, 25, 27, 29, 31, 33, 35, 37, 38, 39, 40]);
+}
+
+#[test]
+fn querying_exchange_rate_at() {
+    let mut deps = setup_test();
+    let state = State::default();
+
+    for (time, rate, reason) in [
+        (1000u64, "1.0", ExchangeRateReason::Harvest),
+        (2000, "1.1", ExchangeRateReason::Donate),
+        (3000, "1.05", ExchangeRateReason::Reconcile),
+    ] {
+        state
+            .record_exchange_rate(
+                deps.as_mut().storage,
+                time,
+                Decimal::from_str(rate).unwrap(),
+                reason,
+            )
+            .unwrap();
+    }
+
+    let item = |time: u64, rate: &str, reason: ExchangeRateReason| ExchangeRateItem {
+        time,
+        exchange_rate: Decimal::from_str(rate).unwrap(),
+        resolution: HistoryResolution::Full,
+        reason,
+    };
+
+    // Before the first record
+    let err = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::ExchangeRateAt {
+            time: 999,
+            interpolate: None,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, StdError::generic_err("no exchange rate recorded at or before 999"));
+
+    // Without interpolation, the prior exchange rate is returned
+    let res: ExchangeRateAtResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::ExchangeRateAt {
+            time: 1500,
+            interpolate: None,
+        },
+    );
+    assert_eq!(
+        res,
+        ExchangeRateAtResponse {
+            time: 1500,
+            exchange_rate: Decimal::from_str("1.0").unwrap(),
+            prior: item(1000, "1.0", ExchangeRateReason::Harvest),
+            next: Some(item(2000, "1.1", ExchangeRateReason::Donate)),
+        }
+    );
+
+    // Interpolating between an increasing and a decreasing exchange rate
+    let res: ExchangeRateAtResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::ExchangeRateAt {
+            time: 1500,
+            interpolate: Some(true),
+        },
+    );
+    assert_eq!(res.exchange_rate, Decimal::from_str("1.05").unwrap());
+
+    let res: ExchangeRateAtResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::ExchangeRateAt {
+            time: 2750,
+            interpolate: Some(true),
+        },
+    );
+    assert_eq!(res.exchange_rate, Decimal::from_str("1.0625").unwrap());
+
+    // At a recorded timestamp, and after the last record, there is nothing to interpolate
+    let res: ExchangeRateAtResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::ExchangeRateAt {
+            time: 2000,
+            interpolate: Some(true),
+        },
+    );
+    assert_eq!(res.exchange_rate, Decimal::from_str("1.1").unwrap());
+    assert_eq!(res.next, None);
+
+    let res: ExchangeRateAtResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::ExchangeRateAt {
+            time: 4000,
+            interpolate: Some(true),
+        },
+    );
+    assert_eq!(res.exchange_rate, Decimal::from_str("1.05").unwrap());
+    assert_eq!(res.prior, item(3000, "1.05", ExchangeRateReason::Reconcile));
+    assert_eq!(res.next, None);
+}
+
+#[test]
+fn simulating_bond_and_unbond() {
+    let mut deps = setup_test();
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+            deposit_fee: Some(Decimal::percent(1)),
+            withdraw_fee: Some(Decimal::percent(2)),
+            ..Default::default()
+        })),
+    )
+    .unwrap();
+
+    deps.querier.set_staking_delegations(&[
+        Delegation::new("alice", 341667),
+        Delegation::new("bob", 341667),
+        Delegation::new("charlie", 341666),
+    ]);
+    deps.querier.set_cw20_total_supply(STAKE_DENOM, 1000000);
+
+    // 12345 uluna at an exchange rate of 1.025 mint 12043 ustake, of which 1% is the deposit fee
+    let res: SimulateBondResponse = query_helper(
+        deps.as_ref(),
+        QueryMsg::SimulateBond {
+            uluna: Uint128::new(12345),
+        },
+    );
+    assert_eq!(
+        res,
+        SimulateBondResponse {
+            ustake_minted: Uint128::new(11923),
+            ustake_deposit_fee: Uint128::new(120),
+            uluna_reserved: Uint128::zero(),
+            validator: "charlie".to_string(),
+        }
+    );
+
+    // 10000 ustake return 10250 uluna, of which 2% is the withdraw fee. Before
+    // `est_unbond_start_time`, the batch is expected to be submitted then
+    let res: SimulateUnbondResponse = query_helper_env(
+        deps.as_ref(),
+        QueryMsg::SimulateUnbond {
+            ustake: Uint128::new(10000),
+        },
+        12345,
+    );
+    assert_eq!(
+        res,
+        SimulateUnbondResponse {
+            uluna_returned: Uint128::new(10045),
+            uluna_withdraw_fee: Uint128::new(205),
+            batch_id: 1,
+            est_unbond_start_time: 269200,
+            est_unbond_end_time: 269200 + 1814400,
+        }
+    );
+
+    // Afterwards, the batch is submitted immediately
+    let res: SimulateUnbondResponse = query_helper_env(
+        deps.as_ref(),
+        QueryMsg::SimulateUnbond {
+            ustake: Uint128::new(10000),
+        },
+        300000,
+    );
+    assert_eq!(res.est_unbond_start_time, 300000);
+    assert_eq!(res.est_unbond_end_time, 300000 + 1814400);
+}
+
 #[test]
 fn querying_previous_batches() {
     let mut deps = mock_dependencies();
@@ -1840,7 +5691,8 @@ fn computing_undelegations() {
     // Alice:   400 - (149 + 1) = 250
     // Bob:     300 - (149 + 1) = 150
     // Charlie: 200 - (149 + 0) = 51
-    let new_undelegations = compute_undelegations(Uint128::new(451), &current_delegations);
+    let new_undelegations =
+        compute_undelegations(Uint128::new(451), &current_delegations, &[10000; 3]);
     let expected = vec![
         Undelegation::new("alice", 250),
         Undelegation::new("bob", 150),
@@ -1871,7 +5723,11 @@ fn computing_redelegations_for_removal() {
     ];
 
     assert_eq!(
-        compute_redelegations_for_removal(&current_delegations[3], &current_delegations[..3]),
+        compute_redelegations_for_removal(
+            &current_delegations[3],
+            &current_delegations[..3],
+            &[10000; 3]
+        ),
         expected,
     );
 }
@@ -1916,7 +5772,60 @@ fn computing_redelegations_for_rebalancing() {
         Redelegation::new("charlie", "evan", 38126),
     ];
 
-    assert_eq!(compute_redelegations_for_rebalancing(&current_delegations), expected,);
+    assert_eq!(
+        compute_redelegations_for_rebalancing(
+            &current_delegations,
+            &[10000; 5],
+            Decimal::zero(),
+            |_, _| false
+        ),
+        (expected, 0)
+    );
+}
+
+#[test]
+fn computing_weighted_delegations() {
+    // Total weight: 20000 + 10000 + 10000 = 40000
+    // Alice:   1003 * 20000 / 40000 = 501 + 1 (remainder)
+    // Bob:     1003 * 10000 / 40000 = 250 + 1 (remainder)
+    // Charlie: 1003 * 10000 / 40000 = 250
+    let weights = [20000, 10000, 10000];
+    assert_eq!(compute_target_delegations(1003, &weights), vec![502, 251, 250]);
+
+    // Alice holds the smallest delegation relative to her weight
+    let current_delegations = vec![
+        Delegation::new("alice", 400),
+        Delegation::new("bob", 300),
+        Delegation::new("charlie", 200),
+    ];
+    assert_eq!(find_validator_to_delegate(&current_delegations, &weights), "alice");
+    assert_eq!(find_validator_to_delegate(&current_delegations, &[10000; 3]), "charlie");
+
+    // Target: 900 * [2, 1, 1] / 4 = [450, 225, 225]
+    // Alice:   +50 from Bob
+    // Charlie: +25 from Bob
+    let expected =
+        vec![Redelegation::new("bob", "alice", 50), Redelegation::new("bob", "charlie", 25)];
+    assert_eq!(
+        compute_redelegations_for_rebalancing(
+            &current_delegations,
+            &weights,
+            Decimal::zero(),
+            |_, _| false
+        ),
+        (expected, 0)
+    );
+
+    // Target after unbonding 300: 600 * [2, 1, 1] / 4 = [300, 150, 150]
+    // Alice:   400 - 300 = 100
+    // Bob:     300 - 150 = 150
+    // Charlie: 200 - 150 = 50
+    let expected = vec![
+        Undelegation::new("alice", 100),
+        Undelegation::new("bob", 150),
+        Undelegation::new("charlie", 50),
+    ];
+    assert_eq!(compute_undelegations(Uint128::new(300), &current_delegations, &weights), expected);
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -2020,10 +5929,20 @@ fn test_check_swap_config() {
         SwapConfig {
             denom: "dupe".to_string(),
             contract: Addr::unchecked("contract"),
+            max_spread: None,
+            hops: vec![],
+            min_return: None,
+            price_source: None,
+            kind: SwapKind::Pair,
         },
         SwapConfig {
             denom: "dupe".to_string(),
             contract: Addr::unchecked("contract"),
+            max_spread: None,
+            hops: vec![],
+            min_return: None,
+            price_source: None,
+            kind: SwapKind::Pair,
         },
     ];
 
@@ -2031,14 +5950,47 @@ fn test_check_swap_config() {
 
     assert_eq!(result, Err(StdError::generic_err("duplicate denom 'dupe' in swap config")));
 
+    let config_invalid = vec![SwapConfig {
+        denom: "ukrw".to_string(),
+        contract: Addr::unchecked("contract"),
+        max_spread: None,
+        hops: vec![SwapHop {
+            denom: "ukrw".to_string(),
+            contract: Addr::unchecked("contract"),
+            max_spread: None,
+        }],
+        min_return: None,
+        price_source: None,
+        kind: SwapKind::Pair,
+    }];
+
+    let result = check_swap_config(&config_invalid, &deps.api);
+
+    assert_eq!(
+        result,
+        Err(StdError::generic_err(
+            "hop for denom 'ukrw' in swap config offers the same denom as before"
+        ))
+    );
+
     let config_valid = vec![
         SwapConfig {
             denom: "uust".to_string(),
             contract: Addr::unchecked("terratest"),
+            max_spread: None,
+            hops: vec![],
+            min_return: None,
+            price_source: None,
+            kind: SwapKind::Pair,
         },
         SwapConfig {
             denom: "uluna".to_string(),
             contract: Addr::unchecked("terratest"),
+            max_spread: None,
+            hops: vec![],
+            min_return: None,
+            price_source: None,
+            kind: SwapKind::Pair,
         },
     ];
 
@@ -2049,7 +6001,7 @@ pub fn from_binary<T: DeserializeOwned>(value: &Binary) -> StdResult<T> {
     from_slice(value.as_slice())
 }
 
-pub fn check_received_coin(amount: u128) -> SubMsg {
+pub fn check_received_coin(amount: u128) -> SubMsg<TerraMsg> {
     SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
         contract_addr: MOCK_CONTRACT_ADDR.to_string(),
         msg: to_binary(&ExecuteMsg::Callback(CallbackMsg::CheckReceivedCoin {
@@ -15,7 +15,7 @@ impl Delegation {
         }
     }
 
-    pub fn to_cosmos_msg(&self) -> CosmosMsg {
+    pub fn to_cosmos_msg<T>(&self) -> CosmosMsg<T> {
         CosmosMsg::Staking(StakingMsg::Delegate {
             validator: self.validator.clone(),
             amount: Coin::new(self.amount, "uluna"),
@@ -37,7 +37,7 @@ impl Undelegation {
         }
     }
 
-    pub fn to_cosmos_msg(&self) -> CosmosMsg {
+    pub fn to_cosmos_msg<T>(&self) -> CosmosMsg<T> {
         CosmosMsg::Staking(StakingMsg::Undelegate {
             validator: self.validator.clone(),
             amount: Coin::new(self.amount, "uluna"),
@@ -61,7 +61,7 @@ impl Redelegation {
         }
     }
 
-    pub fn to_cosmos_msg(&self) -> CosmosMsg {
+    pub fn to_cosmos_msg<T>(&self) -> CosmosMsg<T> {
         CosmosMsg::Staking(StakingMsg::Redelegate {
             src_validator: self.src.clone(),
             dst_validator: self.dst.clone(),
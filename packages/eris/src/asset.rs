@@ -1,4 +1,4 @@
-use classic_bindings::{TerraQuerier, TerraQuery};
+use classic_bindings::{TerraMsg, TerraQuerier, TerraQuery};
 // Code is adjusted based on https://github.com/astroport-fi/astroport-core/blob/release/terra1/packages/astroport/src/asset.rs
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -22,6 +22,26 @@ pub enum PairExecuteMsg {
     },
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PairQueryMsg {
+    /// Simulate swapping an offer asset to the other
+    Simulation {
+        offer_asset: Asset,
+    },
+}
+
+/// This structure holds the parameters returned from a swap simulation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SimulationResponse {
+    /// The amount of ask assets returned by the swap
+    pub return_amount: Uint128,
+    /// The spread used in the swap operation
+    pub spread_amount: Uint128,
+    /// The amount of fees charged by the transaction
+    pub commission_amount: Uint128,
+}
+
 /// UST token denomination
 pub const UUSD_DENOM: &str = "uusd";
 /// LUNA token denomination
@@ -117,11 +137,11 @@ impl Asset {
     /// * **querier** is an object of type [`QuerierWrapper`]
     ///
     /// * **recipient** is the address where the funds will be sent.
-    pub fn into_msg(
+    pub fn into_msg<T>(
         self,
         querier: &QuerierWrapper<TerraQuery>,
         recipient: Addr,
-    ) -> StdResult<CosmosMsg> {
+    ) -> StdResult<CosmosMsg<T>> {
         let amount = self.amount;
 
         match &self.info {
@@ -144,13 +164,14 @@ impl Asset {
         }
     }
 
-    pub fn into_swap_msg(
+    pub fn into_swap_msg<T>(
         self,
         querier: &QuerierWrapper<TerraQuery>,
         pair_contract: String,
+        belief_price: Option<Decimal>,
         max_spread: Option<Decimal>,
         to: Option<String>,
-    ) -> StdResult<CosmosMsg> {
+    ) -> StdResult<CosmosMsg<T>> {
         match &self.info {
             AssetInfo::NativeToken {
                 denom,
@@ -168,7 +189,7 @@ impl Asset {
                             amount,
                             ..self
                         },
-                        belief_price: None,
+                        belief_price,
                         max_spread,
                         to,
                     })?,
@@ -184,7 +205,7 @@ impl Asset {
                     amount: self.amount,
                     msg: to_binary(&PairExecuteMsg::Swap {
                         offer_asset: self,
-                        belief_price: None,
+                        belief_price,
                         max_spread,
                         to,
                     })?,
@@ -193,6 +214,23 @@ impl Asset {
         }
     }
 
+    /// Returns a [`TerraMsg::Swap`] swapping a native token to `ask_denom` through the Terra market
+    /// module. The swapped coins are returned to the sender.
+    pub fn into_market_swap_msg(self, ask_denom: &str) -> StdResult<CosmosMsg<TerraMsg>> {
+        match self.info {
+            AssetInfo::NativeToken {
+                denom,
+            } => Ok(TerraMsg::create_swap_msg(
+                Coin::new(self.amount.u128(), denom),
+                ask_denom.to_string(),
+            )
+            .into()),
+            AssetInfo::Token {
+                ..
+            } => Err(StdError::generic_err("cannot swap token asset through the market module")),
+        }
+    }
+
     /// Validates an amount of native tokens being sent. Returns [`Ok`] if successful, otherwise returns [`Err`].
     /// ## Params
     /// * **self** is the type of the caller object.
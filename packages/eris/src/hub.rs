@@ -1,4 +1,4 @@
-use cosmwasm_std::{to_binary, Addr, Coin, CosmosMsg, Decimal, Empty, StdResult, Uint128, WasmMsg};
+use cosmwasm_std::{to_binary, Addr, Coin, CosmosMsg, Decimal, StdResult, Uint128, WasmMsg};
 use cw20::Cw20ReceiveMsg;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -36,15 +36,37 @@ pub struct InstantiateMsg {
 pub enum ExecuteMsg {
     /// Implements the Cw20 receiver interface
     Receive(Cw20ReceiveMsg),
-    /// Bond specified amount of Luna
+    /// Bond specified amount of Luna. Any other native coin with a route in the swap config is
+    /// swapped to Luna first
     Bond {
         receiver: Option<String>,
+        /// Address of the referrer, who is credited with the bonded amount
+        referral: Option<String>,
+        /// Minimum amount of ustake to be minted to the receiver, after the deposit fee
+        min_ustake_out: Option<Uint128>,
     },
     /// Donates specified amount of Luna to pool
     Donate {},
     /// Withdraw Luna that have finished unbonding in previous batches
     WithdrawUnbonded {
         receiver: Option<String>,
+        /// If provided, only withdraw from these batches; otherwise all finished batches are withdrawn
+        batch_ids: Option<Vec<u64>>,
+        /// Maximum number of batches to withdraw from in this call
+        limit: Option<u32>,
+    },
+    /// Cancel all or part of the sender's unbond request in the pending batch, returning the Stake
+    /// tokens
+    CancelUnbond {
+        /// Amount of Stake tokens to return; the whole request if not provided
+        amount: Option<Uint128>,
+    },
+    /// Transfer all or part of the sender's unbonding shares in a batch to another address
+    TransferUnbondRequest {
+        id: u64,
+        /// Amount of shares to transfer; all shares if not provided
+        shares: Option<Uint128>,
+        recipient: String,
     },
     /// Add a validator to the whitelist; callable by the owner
     AddValidator {
@@ -54,6 +76,17 @@ pub enum ExecuteMsg {
     RemoveValidator {
         validator: String,
     },
+    /// Replace a whitelisted validator by a new one, redelegating the whole stake of the old
+    /// validator to the new one; callable by the owner
+    ReplaceValidator {
+        old: String,
+        new: String,
+    },
+    /// Set the weights of whitelisted validators, in basis points relative to the default weight;
+    /// callable by the owner
+    SetValidatorWeights {
+        weights: Vec<(String, u64)>,
+    },
     /// Transfer ownership to another account; will not take effect unless the new owner accepts
     TransferOwnership {
         new_owner: String,
@@ -63,7 +96,16 @@ pub enum ExecuteMsg {
     /// Claim staking rewards, swap all for Luna, and restake
     Harvest {},
     /// Use redelegations to balance the amounts of Luna delegated to validators
-    Rebalance {},
+    Rebalance {
+        /// Only make up to this many redelegations, the largest ones first
+        max_moves: Option<u32>,
+    },
+    /// Score the whitelisted validators by the rewards earned per Luna delegated, and evict the
+    /// validators that left the active set or charge more than `max_commission`
+    EvaluateValidators {},
+    /// Evict the whitelisted validators charging more than `max_commission`, redelegating their
+    /// stake to the remaining validators
+    EvictValidators {},
     /// Update Luna amounts in unbonding batches to reflect any slashing or rounding errors
     Reconcile {},
     /// Submit the current pending batch of unbonding requests to be unbonded
@@ -72,15 +114,43 @@ pub enum ExecuteMsg {
     Callback(CallbackMsg),
 
     /// Updates the fee config,
-    UpdateConfig {
-        /// Contract address where fees are sent
-        protocol_fee_contract: Option<String>,
-        /// Fees that are being applied during reinvest of staking rewards
-        protocol_reward_fee: Option<Decimal>, // "1 is 100%, 0.05 is 5%"
-
-        /// Config about used swap routes
-        swap_config: Option<Vec<SwapConfig>>,
-    },
+    UpdateConfig(Box<UpdateConfigMsg>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct UpdateConfigMsg {
+    /// Contract address where fees are sent
+    pub protocol_fee_contract: Option<String>,
+    /// Fees that are being applied during reinvest of staking rewards
+    pub protocol_reward_fee: Option<Decimal>, // "1 is 100%, 0.05 is 5%"
+
+    /// Config about used swap routes
+    pub swap_config: Option<Vec<SwapConfig>>,
+
+    /// Config of the liquid reserve used for instant unbonds
+    pub reserve_config: Option<ReserveConfig>,
+
+    /// Rewards paid to keepers calling `Harvest`, `SubmitBatch` and `Reconcile`
+    pub keeper_reward: Option<KeeperReward>,
+
+    /// Split the protocol fee between these recipients by weight, "1 is 100%, 0.05 is 5%". An
+    /// empty list sends all fees to `protocol_fee_contract`
+    pub fee_recipients: Option<Vec<(String, Decimal)>>,
+
+    /// Fee on bonded Luna
+    pub deposit_fee: Option<Decimal>, // "1 is 100%, 0.05 is 5%"
+
+    /// Fee on withdrawn unbonded Luna
+    pub withdraw_fee: Option<Decimal>, // "1 is 100%, 0.05 is 5%"
+
+    /// Retention policy of the exchange rate history
+    pub history_config: Option<HistoryConfig>,
+
+    /// Maximum commission of whitelisted validators, 1 to accept any commission
+    pub max_commission: Option<Decimal>, // "1 is 100%, 0.05 is 5%"
+
+    /// Thresholds below which delegations are not rebalanced
+    pub rebalance_config: Option<RebalanceConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -90,6 +160,20 @@ pub enum ReceiveMsg {
     /// if `epoch_time` has elapsed since when the last unbonding queue was executed.
     QueueUnbond {
         receiver: Option<String>,
+        /// Minimum amount of uluna to be returned, estimated at the current exchange rate and after
+        /// the withdraw fee
+        min_uluna_out: Option<Uint128>,
+    },
+    /// Unbond immediately by receiving Luna from the liquid reserve, paying the instant unbond fee
+    InstantUnbond {
+        receiver: Option<String>,
+    },
+    /// Bond the received token by swapping it to Luna first. The token's contract address must be
+    /// configured as a denom in the swap config
+    Bond {
+        receiver: Option<String>,
+        referral: Option<String>,
+        min_ustake_out: Option<Uint128>,
     },
 }
 
@@ -98,16 +182,43 @@ pub enum ReceiveMsg {
 pub enum CallbackMsg {
     /// Swap Terra stablecoins held by the contract to Luna
     Swap {},
-    /// Following the swaps, stake the Luna acquired to the whitelisted validators
-    Reinvest {},
+    /// Swap the balance of a denom along its configured multi-hop route. Sent as a submessage, so
+    /// that a failing route is reverted on its own and its denom is skipped until the next harvest
+    SwapRoute {
+        denom: String,
+    },
+    /// Swap the whole balance of the denom offered in a hop of a route
+    SwapHop {
+        hop: SwapHop,
+    },
+    /// Following a swap route, assert that at least `min_received` uluna were received since the
+    /// snapshot was taken
+    AssertMinReturn {
+        snapshot: Coin,
+        min_received: Uint128,
+    },
+    /// Following the swaps, stake the Luna acquired to the whitelisted validators. If a keeper is
+    /// provided, it receives its reward out of the harvested Luna
+    Reinvest {
+        keeper: Option<Addr>,
+    },
 
     CheckReceivedCoin {
         snapshot: Coin,
     },
+    /// Following the swap of a non-Luna deposit, assert that at least `min_received` uluna were
+    /// received since the snapshot was taken, and bond them for the receiver
+    BondSwapped {
+        receiver: Addr,
+        snapshot: Coin,
+        min_received: Uint128,
+        referral: Option<Addr>,
+        min_ustake_out: Option<Uint128>,
+    },
 }
 
 impl CallbackMsg {
-    pub fn into_cosmos_msg(&self, contract_addr: &Addr) -> StdResult<CosmosMsg> {
+    pub fn into_cosmos_msg<T>(&self, contract_addr: &Addr) -> StdResult<CosmosMsg<T>> {
         Ok(CosmosMsg::Wasm(WasmMsg::Execute {
             contract_addr: contract_addr.to_string(),
             msg: to_binary(&ExecuteMsg::Callback(self.clone()))?,
@@ -159,6 +270,40 @@ pub enum QueryMsg {
         start_after: Option<u64>,
         limit: Option<u32>,
     },
+    /// The exchange rate at the given timestamp, based on the exchange rate history. Response:
+    /// `ExchangeRateAtResponse`
+    ExchangeRateAt {
+        /// Timestamp in seconds
+        time: u64,
+        /// Whether to interpolate linearly between the exchange rates recorded before and after `time`
+        interpolate: Option<bool>,
+    },
+    /// Enumerate the referrers and the total amount of Luna bonded through them. Response:
+    /// `Vec<ReferralResponseItem>`
+    Referrals {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// The maintenance actions that are currently due. Response: `KeeperStatusResponse`
+    KeeperStatus {},
+    /// Yield of the Stake token, estimated from the exchange rate history. Response: `YieldResponse`
+    Yield {
+        /// Only use exchange rates from this many seconds ago onwards; defaults to 30 days
+        window_seconds: Option<u64>,
+    },
+    /// Simulate bonding the given amount of uluna at the current exchange rate. Response:
+    /// `SimulateBondResponse`
+    SimulateBond {
+        uluna: Uint128,
+    },
+    /// Simulate queuing an unbond of the given amount of ustake at the current exchange rate.
+    /// Response: `SimulateUnbondResponse`
+    SimulateUnbond {
+        ustake: Uint128,
+    },
+    /// The scores of the whitelisted validators from their last evaluation. Response:
+    /// `Vec<(String, ValidatorScore)>`
+    ValidatorScores {},
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -175,17 +320,27 @@ pub struct ConfigResponse {
     pub unbond_period: u64,
     /// Initial set of validators who will receive the delegations
     pub validators: Vec<String>,
+    /// Weight of each whitelisted validator, in basis points. Delegations are split proportionally
+    pub validator_weights: Vec<(String, u64)>,
     /// Information about applied fees
     pub fee_config: FeeConfig,
     /// Information about applied swaps
     pub swap_config: Vec<SwapConfig>,
+    /// Information about the liquid reserve
+    pub reserve_config: ReserveConfig,
+    /// Thresholds below which delegations are not rebalanced
+    pub rebalance_config: RebalanceConfig,
+    /// Retention policy of the exchange rate history; if not set, the full history is kept
+    pub history_config: Option<HistoryConfig>,
+    /// Maximum commission of whitelisted validators; 1 accepts any commission
+    pub max_commission: Decimal,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct StateResponse {
     /// Total supply to the Stake token
     pub total_ustake: Uint128,
-    /// Total amount of uluna staked
+    /// Total amount of uluna backing the Stake token: staked, plus held in the liquid reserve
     pub total_uluna: Uint128,
     /// The exchange rate between ustake and uluna, in terms of uluna per ustake
     pub exchange_rate: Decimal,
@@ -193,10 +348,15 @@ pub struct StateResponse {
     pub unlocked_coins: Vec<Coin>,
     // Amount of uluna currently unbonding
     pub unbonding: Uint128,
-    // Amount of uluna currently available as balance of the contract
+    // Amount of uluna currently available as balance of the contract, except for the share of the
+    // liquid reserve set aside for batches that are still counted as unbonding
     pub available: Uint128,
-    // Total amount of uluna within the contract (bonded + unbonding + available)
+    // Total amount of uluna within the contract (staked + unbonding + available)
     pub tvl_uluna: Uint128,
+    /// Amount of uluna held in the liquid reserve, available for instant unbonds
+    pub reserve_uluna: Uint128,
+    /// Fee charged on instant unbonds
+    pub instant_unbond_fee: Decimal,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -215,6 +375,68 @@ pub struct FeeConfig {
     pub protocol_fee_contract: Addr,
     /// Fees that are being applied during reinvest of staking rewards
     pub protocol_reward_fee: Decimal, // "1 is 100%, 0.05 is 5%"
+    /// Rewards paid to keepers calling the permissionless maintenance functions
+    #[serde(default)]
+    pub keeper_reward: Option<KeeperReward>,
+    /// Recipients of the protocol fee and their shares, summing up to 1. If empty, all fees are sent
+    /// to `protocol_fee_contract`
+    #[serde(default)]
+    pub fee_recipients: Vec<(Addr, Decimal)>,
+    /// Fee on bonded Luna, paid by minting part of the Stake tokens to the fee recipients
+    #[serde(default)]
+    pub deposit_fee: Decimal, // "1 is 100%, 0.05 is 5%"
+    /// Fee on withdrawn unbonded Luna, sent to the fee recipients
+    #[serde(default)]
+    pub withdraw_fee: Decimal, // "1 is 100%, 0.05 is 5%"
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct KeeperReward {
+    /// Share of the harvested rewards paid to the keeper calling `Harvest`
+    pub reward_share: Decimal, // "1 is 100%, 0.05 is 5%"
+    /// Maximum amount of uluna paid to the keeper calling `Harvest`
+    pub max_reward: Uint128,
+    /// Flat amount of uluna paid to the keeper calling `SubmitBatch` or `Reconcile`, taken from the
+    /// unlocked rewards
+    pub tip: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct ReserveConfig {
+    /// Share of bonded and reinvested Luna that is kept undelegated in the liquid reserve
+    pub reserve_share: Decimal, // "1 is 100%, 0.05 is 5%"
+    /// Maximum amount of uluna held in the liquid reserve
+    pub max_reserve: Uint128,
+    /// Fee charged on instant unbonds; it stays in the reserve and accrues to the remaining stakers
+    pub instant_unbond_fee: Decimal, // "1 is 100%, 0.05 is 5%"
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct RebalanceConfig {
+    /// Redelegations smaller than this amount of uluna are not made
+    pub min_redelegation: Uint128,
+    /// Delegations deviating less than this share from their target are not rebalanced
+    pub tolerance: Decimal, // "1 is 100%, 0.05 is 5%"
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct HistoryConfig {
+    /// Exchange rates younger than this many seconds are kept at full resolution
+    pub full_resolution_period: u64,
+    /// Older exchange rates younger than this many seconds are kept at one per day; anything older
+    /// is kept at one per week
+    pub daily_resolution_period: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryResolution {
+    /// Every exchange rate recorded during harvest
+    Full,
+    /// The last exchange rate of each day
+    Daily,
+    /// The last exchange rate of each week
+    Weekly,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -224,6 +446,66 @@ pub struct SwapConfig {
 
     // denom used for swap
     pub denom: String,
+
+    /// Maximum spread accepted when swapping through `contract`
+    #[serde(default)]
+    pub max_spread: Option<Decimal>,
+
+    /// Further hops swapping the output of the previous one, until Luna is received. Empty if
+    /// `contract` swaps directly to Luna
+    #[serde(default)]
+    pub hops: Vec<SwapHop>,
+
+    /// Minimum amount of uluna to be received over the whole route, per unit of `denom` swapped
+    #[serde(default)]
+    pub min_return: Option<Decimal>,
+
+    /// Source of the reference price of `denom` in Luna. If set, the price is used as belief price
+    /// and the denom is skipped when the simulated return deviates more than `max_spread` from
+    /// it. For routes with hops, `max_spread` is the tolerance of the whole route: the route is
+    /// swapped and reverted if it returns less, skipping the denom until the next harvest
+    #[serde(default)]
+    pub price_source: Option<PriceSource>,
+
+    /// How the first hop is swapped. Further hops are always swapped through pair contracts
+    #[serde(default)]
+    pub kind: SwapKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SwapKind {
+    /// Swap through the pair contract `contract`
+    #[default]
+    Pair,
+    /// Swap through the Terra market module, `contract` is not used
+    Market,
+    /// Swap through whichever of the pair contract and the market module returns more
+    BestRate,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSource {
+    /// Exchange rate of the Terra oracle module
+    Oracle {},
+    /// Swap simulation of the Terra market module
+    Market {},
+    /// Swap simulation of the pair contract. Only supported for routes without hops
+    Simulation {},
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SwapHop {
+    /// Contract address of the pair that is used for this hop
+    pub contract: Addr,
+
+    /// Denom offered in this hop, received from the previous one
+    pub denom: String,
+
+    /// Maximum spread accepted for this hop
+    #[serde(default)]
+    pub max_spread: Option<Decimal>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -303,8 +585,133 @@ pub struct UnbondRequestsByUserResponseItemDetails {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct ExchangeRatesResponse {
-    pub exchange_rates: Vec<(u64, Decimal)>,
+    pub exchange_rates: Vec<ExchangeRateItem>,
     // APR normalized per DAY
     pub apr: Option<Decimal>,
 }
-pub type MigrateMsg = Empty;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ExchangeRateItem {
+    /// Timestamp of the exchange rate, in seconds
+    pub time: u64,
+    pub exchange_rate: Decimal,
+    /// Resolution of the history this exchange rate is part of
+    pub resolution: HistoryResolution,
+    /// Action that changed the exchange rate
+    pub reason: ExchangeRateReason,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ExchangeRateAtResponse {
+    /// The requested timestamp
+    pub time: u64,
+    /// The exchange rate at the requested timestamp; either the exchange rate recorded last before
+    /// it, or interpolated between `prior` and `next`
+    pub exchange_rate: Decimal,
+    /// The exchange rate recorded last at or before the requested timestamp
+    pub prior: ExchangeRateItem,
+    /// The exchange rate recorded first after the requested timestamp, if any
+    pub next: Option<ExchangeRateItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExchangeRateReason {
+    /// Staking rewards were reinvested
+    #[default]
+    Harvest,
+    /// Luna was donated to the pool
+    Donate,
+    /// A batch was submitted for unbonding, e.g. rounding when burning Stake tokens
+    SubmitBatch,
+    /// Batches were reconciled, e.g. after delegations were slashed
+    Reconcile,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct YieldResponse {
+    /// Start of the window the yield was estimated over
+    pub window_start: u64,
+    /// Number of exchange rates in the window
+    pub data_points: u32,
+    /// Yield after the protocol reward fee, i.e. as reflected by the exchange rate. `None` if there
+    /// are not enough exchange rates in the window
+    pub net: Option<YieldFigures>,
+    /// Yield before the protocol reward fee
+    pub gross: Option<YieldFigures>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct YieldFigures {
+    /// Growth of the exchange rate per day, "1 is 100%, 0.05 is 5%"
+    pub daily: Decimal,
+    /// Simple growth per week
+    pub weekly: Decimal,
+    /// Simple growth per year
+    pub apr: Decimal,
+    /// Growth per year, compounding daily
+    pub apy: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ReferralResponseItem {
+    /// Address of the referrer
+    pub referrer: String,
+    /// Total amount of uluna bonded through the referrer
+    pub uluna_bonded: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct KeeperStatusResponse {
+    /// Whether there are staking rewards to be harvested
+    pub harvest: bool,
+    /// Whether the pending batch can be submitted
+    pub submit_batch: bool,
+    /// Whether there are batches that finished unbonding and need to be reconciled
+    pub reconcile: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ValidatorScore {
+    /// Rewards earned per uluna delegated and second, relative to the best performing validator
+    pub score: Decimal,
+    /// Commission charged by the validator
+    pub commission: Decimal,
+    /// Whether the validator is in the active set, i.e. neither jailed nor unbonded
+    pub active: bool,
+    /// Time of the evaluation
+    pub time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct SimulateBondResponse {
+    /// Amount of ustake minted to the receiver, after the deposit fee
+    pub ustake_minted: Uint128,
+    /// Amount of ustake minted to the fee recipients as deposit fee
+    pub ustake_deposit_fee: Uint128,
+    /// Amount of uluna kept in the liquid reserve instead of being delegated
+    pub uluna_reserved: Uint128,
+    /// Validator the deposit is delegated to
+    pub validator: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct SimulateUnbondResponse {
+    /// Estimated amount of uluna returned, after the withdraw fee
+    pub uluna_returned: Uint128,
+    /// Estimated amount of uluna sent to the fee recipients as withdraw fee
+    pub uluna_withdraw_fee: Uint128,
+    /// ID of the batch the unbond request is queued in
+    pub batch_id: u64,
+    /// Estimated time when the batch is submitted
+    pub est_unbond_start_time: u64,
+    /// Estimated time when the unbonded uluna can be withdrawn
+    pub est_unbond_end_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {
+    /// If provided, sets the retention policy of the exchange rate history and compacts the
+    /// existing history accordingly
+    pub history_config: Option<HistoryConfig>,
+}
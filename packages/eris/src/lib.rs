@@ -3,7 +3,7 @@ pub mod hub;
 
 mod extensions {
     use cosmwasm_std::{
-        CosmosMsg, Decimal, Decimal256, Empty, Env, Fraction, OverflowError, Response, StdError,
+        CosmosMsg, Decimal, Decimal256, Env, Fraction, OverflowError, Response, StdError,
         StdResult, Uint128, Uint256,
     };
     use std::{convert::TryInto, str::FromStr};
@@ -15,8 +15,8 @@ mod extensions {
         fn add_callback_message(self, env: &Env, msg: CallbackMsg) -> StdResult<Self>;
     }
 
-    impl CustomResponse<Empty> for Response {
-        fn add_optional_message(self, msg: Option<CosmosMsg>) -> Self {
+    impl<T> CustomResponse<T> for Response<T> {
+        fn add_optional_message(self, msg: Option<CosmosMsg<T>>) -> Self {
             match msg {
                 Some(msg) => self.add_message(msg),
                 None => self,